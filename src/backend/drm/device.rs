@@ -1,20 +1,127 @@
 use smithay::{
     backend::{
-        allocator::gbm::GbmDevice,
-        drm::{DrmDevice, DrmDeviceFd, DrmError, DrmNode, NodeType},
+        allocator::{
+            gbm::{GbmAllocator, GbmBufferFlags, GbmDevice},
+            Format as DrmFormat, Fourcc,
+        },
+        drm::{
+            compositor::{DrmCompositor, PrimaryPlaneElement},
+            DrmDevice, DrmDeviceFd, DrmError, DrmEvent, DrmNode, NodeType,
+        },
         egl::{display::EGLDisplay, EGLDevice, Error as EglErr},
+        renderer::{
+            element::{RenderElement, RenderElementStates},
+            gles::GlesRenderer,
+            multigpu::{gbm::GbmGlesBackend, GpuManager},
+        },
         session::{libseat::Error as LibseatErr, Session},
         udev::{all_gpus, primary_gpu},
     },
-    reexports::nix::fcntl::OFlag,
-    utils::DeviceFd,
+    desktop::utils::OutputPresentationFeedback,
+    output::{Mode as OutputMode, Output, PhysicalProperties, Scale, Subpixel},
+    reexports::{
+        calloop::{
+            self,
+            timer::{TimeoutAction, Timer},
+            LoopHandle, RegistrationToken,
+        },
+        drm::control::{
+            atomic::AtomicModeReq, connector, crtc, framebuffer, plane, property, property::Value,
+            AtomicCommitFlags, Device as ControlDevice, Mode as DrmMode, ModeTypeFlags,
+            ResourceHandle,
+        },
+        gbm::BufferObject,
+        nix::fcntl::OFlag,
+        wayland_protocols::wp::linux_dmabuf::zv1::server::zwp_linux_dmabuf_feedback_v1::TrancheFlags,
+        wayland_server::{backend::GlobalId, DisplayHandle},
+    },
+    utils::{DeviceFd, Logical, Point, Transform},
+    wayland::dmabuf::DmabufFeedbackBuilder,
 };
 
-use crate::backend::drm::DrmBackend;
-use crate::state::Waysight;
+use crate::{
+    backend::drm::{DrmBackend, UdevRenderer},
+    config::{AdaptiveSync, OutputConfig},
+    ipc::OutputStats,
+    protocols::output_management::{
+        Head, HeadConfiguration, ModeConfiguration, OutputConfigurationError,
+    },
+    render::SurfaceDmabufFeedback,
+    state::{CalloopData, Waysight, CONFIG},
+    utils::output::{configured_state, layout_right_edge, refresh_interval},
+};
 use smithay_drm_extras::drm_scanner::DrmScanEvent;
 use smithay_drm_extras::drm_scanner::{DrmScanResult, DrmScanner};
-use std::{os::fd::FromRawFd, path::Path};
+use smithay_drm_extras::edid::EdidInfo;
+use std::{
+    collections::{HashMap, HashSet},
+    io, iter,
+    os::fd::FromRawFd,
+    path::Path,
+};
+
+// Formats tried in order when allocating the buffers we render into
+const SUPPORTED_FORMATS: &[Fourcc] = &[
+    Fourcc::Abgr2101010,
+    Fourcc::Argb2101010,
+    Fourcc::Abgr8888,
+    Fourcc::Argb8888,
+];
+
+pub type GbmDrmCompositor = DrmCompositor<
+    GbmAllocator<DrmDeviceFd>,
+    GbmDevice<DrmDeviceFd>,
+    Option<OutputPresentationFeedback>,
+    DrmDeviceFd,
+>;
+
+// An output driven by one of the device's crtcs. Disabled outputs keep their `Output` so they can
+// still be listed and enabled through output management, but have no compositor or global
+pub struct Surface {
+    pub output: Output,
+    connector: connector::Info,
+    global: Option<GlobalId>,
+    compositor: Option<GbmDrmCompositor>,
+    redraw_state: RedrawState,
+    // Feedback of an undamaged frame, presented at the emulated vblank
+    pending_feedback: Option<OutputPresentationFeedback>,
+    // Built on first use, depends on the planes of the crtc and the render formats
+    dmabuf_feedback: Option<SurfaceDmabufFeedback>,
+    stats: OutputStats,
+    // Whether the driver and the monitor support variable refresh rates
+    vrr_capable: bool,
+    adaptive_sync: AdaptiveSync,
+    // Whether vrr is currently turned on for the crtc
+    vrr_enabled: bool,
+    // Turned off through output power management. The crtc is disabled, but the output stays in
+    // the layout
+    powered: bool,
+}
+
+// Where a surface is in its repaint cycle
+#[derive(Clone, Copy, Default)]
+enum RedrawState {
+    #[default]
+    Idle,
+    // A render is scheduled for the next loop iteration
+    Queued,
+    // A frame was queued and we wait for its vblank. If there was nothing to draw the vblank is
+    // emulated with a timer, so clients still get their frame callbacks at the refresh rate
+    WaitingForVBlank {
+        redraw_needed: bool,
+    },
+}
+
+// Everything that can be configured on a surface
+#[derive(Clone, Copy)]
+pub struct SurfaceState {
+    pub enabled: bool,
+    pub mode: DrmMode,
+    pub position: Point<i32, Logical>,
+    pub scale: Scale,
+    pub transform: Transform,
+    pub adaptive_sync: AdaptiveSync,
+}
 
 #[allow(dead_code)]
 pub struct Device {
@@ -22,7 +129,12 @@ pub struct Device {
     gbm: GbmDevice<DrmDeviceFd>,
     device_node: DrmNode,
     render_node: DrmNode,
+    // Formats this device's own gpu can render to, the buffers we scan out are allocated with them
+    render_formats: HashSet<DrmFormat>,
     scanner: DrmScanner,
+    surfaces: HashMap<crtc::Handle, Surface>,
+    event_token: RegistrationToken,
+    loop_handle: LoopHandle<'static, CalloopData<DrmBackend>>,
 }
 
 #[allow(dead_code)]
@@ -36,11 +148,25 @@ pub enum DeviceError {
     GbmDevice(std::io::Error),
     #[error("Failure to create egl display: {0}")]
     Egl(EglErr),
+    #[error("Failure to create renderer: {0}")]
+    Renderer(Box<dyn std::error::Error>),
+    #[error("Unable to listen to drm events: {0}")]
+    EventSource(calloop::Error),
+    #[error("Unable to create drm surface: {0}")]
+    Surface(DrmError),
+    #[error("Unable to create drm compositor: {0}")]
+    Compositor(Box<dyn std::error::Error>),
 }
 
 #[allow(unused)]
 impl Device {
-    pub fn new<S: Session>(node: DrmNode, path: &Path, session: &mut S) -> Result<Self, DeviceError>
+    pub fn new<S: Session>(
+        node: DrmNode,
+        path: &Path,
+        session: &mut S,
+        gpus: &mut GpuManager<GbmGlesBackend<GlesRenderer>>,
+        handle: &LoopHandle<'static, CalloopData<DrmBackend>>,
+    ) -> Result<Self, DeviceError>
     where
         S::Error: Into<LibseatErr>,
     {
@@ -64,38 +190,1195 @@ impl Device {
             node.dev_path().unwrap().to_str().unwrap()
         );
 
-        let render_node =
-            EGLDevice::device_for_display(&EGLDisplay::new(gbm.clone()).map_err(DeviceError::Egl)?)
-                .map_err(DeviceError::Egl)
-                .ok()
-                .and_then(|egl| egl.try_get_render_node().map_err(DeviceError::Egl).ok()?)
-                .unwrap_or(node);
+        let egl_display = EGLDisplay::new(gbm.clone()).map_err(DeviceError::Egl)?;
+        let render_node = EGLDevice::device_for_display(&egl_display)
+            .map_err(DeviceError::Egl)
+            .ok()
+            .and_then(|egl| egl.try_get_render_node().map_err(DeviceError::Egl).ok()?)
+            .unwrap_or(node);
+
+        // Every gpu is registered with the gpu manager so it can render for, or copy from, the
+        // others
+        gpus.as_mut()
+            .add_node(render_node, gbm.clone())
+            .map_err(DeviceError::Egl)?;
+        let render_formats = gpus
+            .single_renderer(&render_node)
+            .map_err(|err| DeviceError::Renderer(Box::new(err)))?
+            .as_mut()
+            .egl_context()
+            .dmabuf_render_formats()
+            .clone();
+
+        let event_token = handle
+            .insert_source(notifier, move |event, metadata, data| match event {
+                DrmEvent::VBlank(crtc) => data.state.on_vblank(node, crtc, metadata),
+                DrmEvent::Error(err) => tracing::error!("Drm error on {}: {}", node, err),
+            })
+            .map_err(|err| DeviceError::EventSource(err.error))?;
 
         Ok(Device {
             drm,
             gbm,
             device_node: node,
             render_node,
+            render_formats,
             scanner: DrmScanner::new(),
+            surfaces: HashMap::new(),
+            event_token,
+            loop_handle: handle.clone(),
         })
     }
 
-    pub fn on_device_changed(&mut self, state: &mut Waysight<DrmBackend>) {
+    // Outputs without a configured position are placed at `next_x`, which is moved past them
+    pub fn on_device_changed(&mut self, display_handle: &DisplayHandle, next_x: &mut i32) {
         for event in self.scanner.scan_connectors(&self.drm) {
             match event {
                 DrmScanEvent::Connected {
                     connector,
                     crtc: Some(crtc),
                 } => {
-                    tracing::info!("Connector found");
+                    tracing::info!("Connector {} connected", connector_name(&connector));
+                    self.connector_connected(display_handle, connector, crtc, next_x);
                 }
-                DrmScanEvent::Disconnected { connector, crtc } => {
-                    tracing::info!("Connector disconnected");
+                DrmScanEvent::Disconnected {
+                    connector,
+                    crtc: Some(crtc),
+                } => {
+                    tracing::info!("Connector {} disconnected", connector_name(&connector));
+                    self.connector_disconnected(display_handle, crtc);
                 }
                 _ => {}
             }
         }
     }
+
+    // Re-evaluates the config of every connected connector, enabling, updating or disabling outputs
+    pub fn apply_output_config(&mut self, display_handle: &DisplayHandle, next_x: &mut i32) {
+        let connectors: Vec<(connector::Info, crtc::Handle)> = self
+            .scanner
+            .crtcs()
+            .map(|(connector, crtc)| (connector.clone(), crtc))
+            .collect();
+        for (connector, crtc) in connectors {
+            self.connector_connected(display_handle, connector, crtc, next_x);
+        }
+    }
+
+    pub fn heads(&self) -> impl Iterator<Item = Head> + '_ {
+        self.surfaces.values().map(|surface| Head {
+            output: surface.output.clone(),
+            enabled: surface.compositor.is_some(),
+            adaptive_sync: surface.vrr_capable && surface.adaptive_sync != AdaptiveSync::Off,
+        })
+    }
+
+    // Returns the presentation feedback of the frame that just hit the screen
+    pub fn frame_submitted(&mut self, crtc: crtc::Handle) -> Option<OutputPresentationFeedback> {
+        let compositor = self
+            .surfaces
+            .get_mut(&crtc)
+            .and_then(|surface| surface.compositor.as_mut());
+        let feedback = match compositor.map(|compositor| compositor.frame_submitted()) {
+            Some(Ok(feedback)) => feedback.flatten(),
+            Some(Err(err)) => {
+                tracing::warn!("Error marking frame as submitted: {}", err);
+                None
+            }
+            None => None,
+        };
+        self.finish_frame(crtc);
+        feedback
+    }
+
+    // Returns the presentation feedback of the frame that had nothing to flip
+    pub fn estimated_vblank(&mut self, crtc: crtc::Handle) -> Option<OutputPresentationFeedback> {
+        let feedback = self
+            .surfaces
+            .get_mut(&crtc)
+            .and_then(|surface| surface.pending_feedback.take());
+        self.finish_frame(crtc);
+        feedback
+    }
+
+    // The (real or emulated) vblank of the last frame happened or the frame was dropped. Repaints
+    // requested in the meantime happen now
+    pub fn finish_frame(&mut self, crtc: crtc::Handle) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        let redraw_needed = matches!(
+            surface.redraw_state,
+            RedrawState::WaitingForVBlank {
+                redraw_needed: true
+            }
+        );
+        surface.redraw_state = RedrawState::Idle;
+        if !surface.powered {
+            self.disable_crtc(crtc);
+        } else if redraw_needed {
+            self.schedule_render(crtc);
+        }
+    }
+
+    pub fn is_powered(&self, crtc: crtc::Handle) -> bool {
+        self.surfaces
+            .get(&crtc)
+            .map_or(false, |surface| surface.powered)
+    }
+
+    // Turns the crtc off or back on. While off nothing is rendered, a frame in flight is finished
+    // first
+    pub fn set_power(&mut self, crtc: crtc::Handle, on: bool) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        if surface.powered == on {
+            return;
+        }
+        surface.powered = on;
+        tracing::info!(
+            "Turning {} {}",
+            surface.output.name(),
+            if on { "on" } else { "off" }
+        );
+        if on {
+            // The surface still has its mode and connectors pending, so the next frame commits
+            // them and turns the crtc back on
+            if let Some(compositor) = surface.compositor.as_mut() {
+                compositor.reset_buffers();
+            }
+            self.schedule_render(crtc);
+        } else if matches!(surface.redraw_state, RedrawState::Idle) {
+            self.disable_crtc(crtc);
+        }
+    }
+
+    // Turns the crtc and its planes off through its drm surface, so the compositor knows the crtc
+    // is inactive
+    fn disable_crtc(&mut self, crtc: crtc::Handle) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        if let Some(compositor) = surface.compositor.as_mut() {
+            if let Err(err) = compositor.surface().clear_state() {
+                tracing::warn!("Failed to turn off {}: {}", surface.output.name(), err);
+            }
+        }
+    }
+
+    pub fn dmabuf_feedback(
+        &mut self,
+        crtc: crtc::Handle,
+        render_node: DrmNode,
+        render_formats: &HashSet<DrmFormat>,
+    ) -> Option<&SurfaceDmabufFeedback> {
+        // Client buffers live on the render gpu, only its own outputs can scan them out
+        let scanout_node = (self.render_node == render_node).then_some(self.device_node);
+        let surface = self.surfaces.get_mut(&crtc)?;
+        if surface.dmabuf_feedback.is_none() {
+            surface.dmabuf_feedback = surface_dmabuf_feedback(
+                surface.compositor.as_ref()?,
+                render_node,
+                render_formats,
+                scanout_node,
+            );
+        }
+        surface.dmabuf_feedback.as_ref()
+    }
+
+    pub fn reset_dmabuf_feedback(&mut self) {
+        for surface in self.surfaces.values_mut() {
+            surface.dmabuf_feedback = None;
+        }
+    }
+
+    // Turns vrr on or off according to the output's adaptive sync setting. `fullscreen` tells
+    // whether a fullscreen window is shown, for `AdaptiveSync::FullscreenOnly`
+    pub fn update_adaptive_sync(&mut self, crtc: crtc::Handle, fullscreen: bool) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        let compositor = match surface.compositor.as_mut() {
+            Some(compositor) => compositor,
+            None => return,
+        };
+        let wanted = surface.vrr_capable
+            && match surface.adaptive_sync {
+                AdaptiveSync::Off => false,
+                AdaptiveSync::On => true,
+                AdaptiveSync::FullscreenOnly => fullscreen,
+            };
+        if wanted == surface.vrr_enabled {
+            return;
+        }
+        match compositor.use_vrr(wanted) {
+            Ok(()) => {
+                tracing::info!(
+                    "{} adaptive sync on {}",
+                    if wanted { "Enabled" } else { "Disabled" },
+                    surface.output.name()
+                );
+                surface.vrr_enabled = wanted;
+                surface.stats.adaptive_sync = wanted;
+            }
+            Err(err) => {
+                // Not retried on every frame, the output keeps its fixed refresh rate
+                tracing::warn!(
+                    "Failed to change adaptive sync on {}, disabling it: {}",
+                    surface.output.name(),
+                    err
+                );
+                surface.vrr_capable = false;
+            }
+        }
+    }
+
+    // Records whether the fullscreen window asked for async presentation. The drm compositor only
+    // does vsynced page flips, so frames still wait for the vblank. With adaptive sync on, the
+    // vblank follows the client anyway
+    pub fn set_tearing(&mut self, crtc: crtc::Handle, tearing: bool) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        if surface.stats.tearing_requested != tearing {
+            tracing::debug!(
+                "Fullscreen window on {} {} async presentation",
+                surface.output.name(),
+                if tearing {
+                    "requests"
+                } else {
+                    "no longer requests"
+                }
+            );
+            surface.stats.tearing_requested = tearing;
+        }
+    }
+
+    pub fn output_stats(&self) -> impl Iterator<Item = OutputStats> + '_ {
+        self.surfaces
+            .values()
+            .filter(|surface| surface.compositor.is_some())
+            .map(|surface| surface.stats.clone())
+    }
+
+    pub fn crtc_for_output(&self, output: &Output) -> Option<crtc::Handle> {
+        self.surfaces
+            .iter()
+            .find(|(_, surface)| &surface.output == output)
+            .map(|(crtc, _)| *crtc)
+    }
+
+    pub fn render_node(&self) -> DrmNode {
+        self.render_node
+    }
+
+    // The format of the buffers the crtc scans out, which is what a secondary gpu has to copy into
+    pub fn surface_format(&self, crtc: crtc::Handle) -> Option<Fourcc> {
+        self.surfaces
+            .get(&crtc)?
+            .compositor
+            .as_ref()
+            .map(|compositor| compositor.format())
+    }
+
+    pub fn surface_output(&self, crtc: crtc::Handle) -> Option<Output> {
+        self.surfaces
+            .get(&crtc)
+            .map(|surface| surface.output.clone())
+    }
+
+    // Renders the crtc on the next loop iteration, or after the pending vblank if a frame is
+    // already in flight
+    pub fn schedule_render(&mut self, crtc: crtc::Handle) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) if surface.compositor.is_some() && surface.powered => surface,
+            _ => return,
+        };
+        match surface.redraw_state {
+            RedrawState::Idle => {
+                surface.redraw_state = RedrawState::Queued;
+                let node = self.device_node;
+                self.loop_handle
+                    .insert_idle(move |data| data.state.render_surface(node, crtc));
+            }
+            RedrawState::Queued => {}
+            RedrawState::WaitingForVBlank { .. } => {
+                surface.redraw_state = RedrawState::WaitingForVBlank {
+                    redraw_needed: true,
+                };
+            }
+        }
+    }
+
+    // Called when our session is switched away from, the device must not be touched until
+    // `activate`
+    pub fn pause(&mut self) {
+        self.drm.pause();
+    }
+
+    // Takes drm master back after a vt switch. Whoever had the device in the meantime may have
+    // changed the crtcs, so their state is reset and the buffers dropped to force a full repaint
+    pub fn activate(&mut self) {
+        self.drm.activate();
+        for surface in self.surfaces.values_mut() {
+            if let Some(compositor) = surface.compositor.as_mut() {
+                if let Err(err) = compositor.surface().reset_state() {
+                    tracing::warn!(
+                        "Failed to reset the drm state of {}: {}",
+                        surface.output.name(),
+                        err
+                    );
+                }
+                compositor.reset_buffers();
+            }
+            // Vblanks of frames queued before the switch never arrive
+            surface.redraw_state = RedrawState::Idle;
+        }
+        // Whoever had the device may have turned them on
+        let off: Vec<crtc::Handle> = self
+            .surfaces
+            .iter()
+            .filter(|(_, surface)| !surface.powered)
+            .map(|(crtc, _)| *crtc)
+            .collect();
+        for crtc in off {
+            self.disable_crtc(crtc);
+        }
+        self.schedule_render_all();
+    }
+
+    pub fn schedule_render_all(&mut self) {
+        let crtcs: Vec<crtc::Handle> = self.surfaces.keys().copied().collect();
+        for crtc in crtcs {
+            self.schedule_render(crtc);
+        }
+    }
+
+    // Renders a frame, which is then submitted with `queue_frame`. Returns whether anything was
+    // damaged and which elements ended up visible, or `None` if rendering failed
+    pub fn render<'a, 'b, E>(
+        &mut self,
+        crtc: crtc::Handle,
+        renderer: &mut UdevRenderer<'a, 'b>,
+        elements: &[E],
+        clear_color: [f32; 4],
+    ) -> Option<(bool, RenderElementStates)>
+    where
+        E: RenderElement<UdevRenderer<'a, 'b>>,
+    {
+        let surface = self.surfaces.get_mut(&crtc)?;
+        let compositor = match surface.compositor.as_mut() {
+            Some(compositor) => compositor,
+            None => {
+                surface.redraw_state = RedrawState::Idle;
+                return None;
+            }
+        };
+        match compositor.render_frame(renderer, elements, clear_color) {
+            Ok(result) => {
+                if !result.is_empty {
+                    let direct_scanout =
+                        matches!(result.primary_element, PrimaryPlaneElement::Element(_));
+                    let overlay_planes = result.overlay_elements.len();
+                    let cursor_plane = result.cursor_element.is_some();
+                    let stats = &mut surface.stats;
+                    if (direct_scanout, overlay_planes, cursor_plane)
+                        != (
+                            stats.direct_scanout,
+                            stats.overlay_planes,
+                            stats.cursor_plane,
+                        )
+                    {
+                        tracing::debug!(
+                            "Plane assignment on {} changed: direct scanout: {}, overlay planes: {}, cursor plane: {}",
+                            stats.name,
+                            direct_scanout,
+                            overlay_planes,
+                            cursor_plane
+                        );
+                    }
+                    tracing::trace!(
+                        "Frame on {}: direct scanout: {}, overlay planes: {}, cursor plane: {}",
+                        stats.name,
+                        direct_scanout,
+                        overlay_planes,
+                        cursor_plane
+                    );
+                    stats.frames += 1;
+                    if direct_scanout {
+                        stats.direct_scanout_frames += 1;
+                    }
+                    stats.direct_scanout = direct_scanout;
+                    stats.overlay_planes = overlay_planes;
+                    stats.cursor_plane = cursor_plane;
+                }
+                Some((!result.is_empty, result.states))
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Failed to render frame on {}: {}",
+                    surface.output.name(),
+                    err
+                );
+                surface.redraw_state = RedrawState::Idle;
+                None
+            }
+        }
+    }
+
+    // Submits the frame rendered last. Without damage there is nothing to flip, the vblank is then
+    // emulated and `feedback` presented at the estimated time
+    pub fn queue_frame(
+        &mut self,
+        crtc: crtc::Handle,
+        damaged: bool,
+        feedback: OutputPresentationFeedback,
+    ) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        let compositor = match surface.compositor.as_mut() {
+            Some(compositor) => compositor,
+            None => return,
+        };
+
+        if !damaged {
+            let node = self.device_node;
+            let timer = Timer::from_duration(refresh_interval(&surface.output));
+            let inserted = self.loop_handle.insert_source(timer, move |_, _, data| {
+                data.state.on_estimated_vblank(node, crtc);
+                TimeoutAction::Drop
+            });
+            if inserted.is_err() {
+                surface.redraw_state = RedrawState::Idle;
+                return;
+            }
+            surface.pending_feedback = Some(feedback);
+        } else if let Err(err) = compositor.queue_frame(Some(feedback)) {
+            tracing::error!(
+                "Failed to queue frame on {}: {}",
+                surface.output.name(),
+                err
+            );
+            surface.redraw_state = RedrawState::Idle;
+            return;
+        }
+        surface.redraw_state = RedrawState::WaitingForVBlank {
+            redraw_needed: false,
+        };
+    }
+
+    // Resolves an output management request to the full state of one of our surfaces. Returns
+    // `None` if the output doesn't belong to this device
+    pub fn resolve_head(
+        &self,
+        head: &HeadConfiguration,
+    ) -> Option<Result<(crtc::Handle, SurfaceState), OutputConfigurationError>> {
+        let (crtc, surface) = self
+            .surfaces
+            .iter()
+            .find(|(_, surface)| surface.output == head.output)?;
+        let current = self.surface_state(*crtc)?;
+
+        let mode = match head.mode {
+            Some(ModeConfiguration::Mode(mode)) => surface
+                .connector
+                .modes()
+                .iter()
+                .find(|drm_mode| OutputMode::from(**drm_mode) == mode)
+                .copied(),
+            Some(ModeConfiguration::Custom { size, refresh }) => {
+                // Only modes advertised by the connector can be driven, a custom mode is accepted
+                // when it matches one of them
+                surface
+                    .connector
+                    .modes()
+                    .iter()
+                    .filter(|drm_mode| OutputMode::from(**drm_mode).size == size)
+                    .min_by_key(|drm_mode| {
+                        let mode_refresh = OutputMode::from(**drm_mode).refresh;
+                        refresh.map_or(-mode_refresh, |refresh| (mode_refresh - refresh).abs())
+                    })
+                    .filter(|drm_mode| {
+                        refresh.map_or(true, |refresh| {
+                            (OutputMode::from(**drm_mode).refresh - refresh).abs() < 1000
+                        })
+                    })
+                    .copied()
+            }
+            None => Some(current.mode),
+        };
+        let adaptive_sync = match head.adaptive_sync {
+            None => current.adaptive_sync,
+            Some(false) => AdaptiveSync::Off,
+            Some(true) if !surface.vrr_capable => {
+                return Some(Err(OutputConfigurationError::AdaptiveSyncUnsupported(
+                    surface.output.name(),
+                )))
+            }
+            // Keeps `fullscreen-only` from the config, output management only knows on and off
+            Some(true) if current.adaptive_sync != AdaptiveSync::Off => current.adaptive_sync,
+            Some(true) => AdaptiveSync::On,
+        };
+        let mode = match mode {
+            Some(mode) => mode,
+            None => {
+                return Some(Err(OutputConfigurationError::UnsupportedMode(
+                    surface.output.name(),
+                )))
+            }
+        };
+
+        Some(Ok((
+            *crtc,
+            SurfaceState {
+                enabled: head.enabled,
+                mode,
+                position: head.position.unwrap_or(current.position),
+                scale: head.scale.map(Scale::Fractional).unwrap_or(current.scale),
+                transform: head.transform.unwrap_or(current.transform),
+                adaptive_sync,
+            },
+        )))
+    }
+
+    pub fn surface_state(&self, crtc: crtc::Handle) -> Option<SurfaceState> {
+        let surface = self.surfaces.get(&crtc)?;
+        let current_mode = surface.output.current_mode();
+        let mode = surface
+            .connector
+            .modes()
+            .iter()
+            .find(|drm_mode| Some(OutputMode::from(**drm_mode)) == current_mode)
+            .copied()
+            .or_else(|| preferred_mode(surface.connector.modes()))?;
+        Some(SurfaceState {
+            enabled: surface.compositor.is_some(),
+            mode,
+            position: surface.output.current_location(),
+            scale: surface.output.current_scale(),
+            transform: surface.output.current_transform(),
+            adaptive_sync: surface.adaptive_sync,
+        })
+    }
+
+    // Checks that `states` can be applied together, with one atomic test commit covering all of
+    // their crtcs. Nothing changes, the mode blobs and framebuffers only exist for the test. Devices
+    // without atomic modesetting can't test, their configurations are accepted as is
+    pub fn test_surfaces(
+        &self,
+        states: &[(crtc::Handle, SurfaceState)],
+    ) -> Result<(), OutputConfigurationError> {
+        let names = || {
+            states
+                .iter()
+                .filter_map(|(crtc, _)| self.surfaces.get(crtc))
+                .map(|surface| surface.output.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if !self.drm.is_atomic() {
+            tracing::debug!(
+                "Not testing {}, the device has no atomic modesetting",
+                names()
+            );
+            return Ok(());
+        }
+        let mut resources = TestResources {
+            drm: &self.drm,
+            blobs: Vec::new(),
+            framebuffers: Vec::new(),
+            buffers: Vec::new(),
+        };
+        self.test_request(&mut resources, states)
+            .and_then(|request| {
+                self.drm.atomic_commit(
+                    AtomicCommitFlags::TEST_ONLY | AtomicCommitFlags::ALLOW_MODESET,
+                    request,
+                )
+            })
+            .map_err(|err| OutputConfigurationError::Backend(names(), err.to_string()))
+    }
+
+    fn test_request(
+        &self,
+        resources: &mut TestResources<'_>,
+        states: &[(crtc::Handle, SurfaceState)],
+    ) -> io::Result<AtomicModeReq> {
+        let drm = &self.drm;
+        let planes = drm.plane_handles()?;
+        let crtcs = drm.resource_handles()?;
+        let mut request = AtomicModeReq::new();
+        let mut taken_planes = Vec::new();
+        for (crtc, state) in states {
+            let surface = match self.surfaces.get(crtc) {
+                Some(surface) => surface,
+                None => continue,
+            };
+            let connector = surface.connector.handle();
+
+            let primary = if state.enabled {
+                // The plane already showing the crtc, else any free primary plane that can
+                let mut candidates = Vec::new();
+                for plane in planes.iter().copied() {
+                    let info = drm.get_plane(plane)?;
+                    if taken_planes.contains(&plane)
+                        || !crtcs.filter_crtcs(info.possible_crtcs()).contains(crtc)
+                        || plane_type(drm, plane)? != PlaneType::Primary
+                    {
+                        continue;
+                    }
+                    candidates.push((plane, info.crtc() == Some(*crtc)));
+                }
+                let plane = candidates
+                    .iter()
+                    .find(|(_, current)| *current)
+                    .or_else(|| candidates.first())
+                    .map(|(plane, _)| *plane)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no primary plane"))?;
+                taken_planes.push(plane);
+                Some(plane)
+            } else {
+                None
+            };
+            // Planes of the old configuration, the compositor assigns them again after a change
+            for plane in planes.iter().copied() {
+                if Some(plane) != primary && drm.get_plane(plane)?.crtc() == Some(*crtc) {
+                    add_property(drm, &mut request, plane, "FB_ID", Value::Framebuffer(None))?;
+                    add_property(drm, &mut request, plane, "CRTC_ID", Value::CRTC(None))?;
+                }
+            }
+
+            let primary = match primary {
+                Some(primary) => primary,
+                None => {
+                    add_property(drm, &mut request, *crtc, "ACTIVE", Value::Boolean(false))?;
+                    add_property(drm, &mut request, *crtc, "MODE_ID", Value::Blob(0))?;
+                    add_property(drm, &mut request, connector, "CRTC_ID", Value::CRTC(None))?;
+                    continue;
+                }
+            };
+            let mode_blob = drm.create_property_blob(&state.mode)?;
+            if let Value::Blob(blob) = mode_blob {
+                resources.blobs.push(blob);
+            }
+            add_property(drm, &mut request, *crtc, "MODE_ID", mode_blob)?;
+            add_property(drm, &mut request, *crtc, "ACTIVE", Value::Boolean(true))?;
+            add_property(
+                drm,
+                &mut request,
+                connector,
+                "CRTC_ID",
+                Value::CRTC(Some(*crtc)),
+            )?;
+
+            let (width, height) = state.mode.size();
+            let (width, height) = (width as u32, height as u32);
+            let buffer = self.gbm.create_buffer_object::<()>(
+                width,
+                height,
+                Fourcc::Xrgb8888,
+                GbmBufferFlags::SCANOUT,
+            )?;
+            let framebuffer = drm.add_framebuffer(&buffer, 24, 32)?;
+            resources.buffers.push(buffer);
+            resources.framebuffers.push(framebuffer);
+            for (name, value) in [
+                ("FB_ID", Value::Framebuffer(Some(framebuffer))),
+                ("CRTC_ID", Value::CRTC(Some(*crtc))),
+                ("SRC_X", Value::UnsignedRange(0)),
+                ("SRC_Y", Value::UnsignedRange(0)),
+                ("SRC_W", Value::UnsignedRange((width as u64) << 16)),
+                ("SRC_H", Value::UnsignedRange((height as u64) << 16)),
+                ("CRTC_X", Value::SignedRange(0)),
+                ("CRTC_Y", Value::SignedRange(0)),
+                ("CRTC_W", Value::UnsignedRange(width as u64)),
+                ("CRTC_H", Value::UnsignedRange(height as u64)),
+            ] {
+                add_property(drm, &mut request, primary, name, value)?;
+            }
+        }
+        Ok(request)
+    }
+
+    // Turns the output on `crtc` off and removes its global
+    pub fn disable_surface(&mut self, display_handle: &DisplayHandle, crtc: crtc::Handle) {
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        // Dropping the compositor releases the crtc
+        surface.compositor = None;
+        surface.redraw_state = RedrawState::Idle;
+        surface.pending_feedback = None;
+        surface.dmabuf_feedback = None;
+        surface.vrr_enabled = false;
+        surface.stats.adaptive_sync = false;
+        if let Some(global) = surface.global.take() {
+            display_handle.remove_global::<Waysight<DrmBackend>>(global);
+        }
+    }
+
+    pub fn configure_surface(
+        &mut self,
+        display_handle: &DisplayHandle,
+        crtc: crtc::Handle,
+        state: SurfaceState,
+    ) -> Result<(), DeviceError> {
+        if !state.enabled {
+            self.disable_surface(display_handle, crtc);
+            return Ok(());
+        }
+        let surface = match self.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return Ok(()),
+        };
+
+        match surface.compositor.as_mut() {
+            Some(compositor) => {
+                if surface.output.current_mode() != Some(OutputMode::from(state.mode)) {
+                    compositor
+                        .use_mode(state.mode)
+                        .map_err(|err| DeviceError::Compositor(Box::new(err)))?;
+                }
+            }
+            None => {
+                surface.compositor = Some(create_compositor(
+                    &self.drm,
+                    &self.gbm,
+                    &self.render_formats,
+                    &surface.output,
+                    crtc,
+                    state.mode,
+                    surface.connector.handle(),
+                )?);
+                surface.vrr_enabled = false;
+                surface.stats.adaptive_sync = false;
+            }
+        }
+        // Takes effect with the next frame, see `update_adaptive_sync`
+        surface.adaptive_sync = state.adaptive_sync;
+        surface.output.change_current_state(
+            Some(OutputMode::from(state.mode)),
+            Some(state.transform),
+            Some(state.scale),
+            Some(state.position),
+        );
+        if surface.global.is_none() {
+            surface.global = Some(
+                surface
+                    .output
+                    .create_global::<Waysight<DrmBackend>>(display_handle),
+            );
+        }
+
+        self.schedule_render(crtc);
+        Ok(())
+    }
+
+    fn connector_connected(
+        &mut self,
+        display_handle: &DisplayHandle,
+        connector: connector::Info,
+        crtc: crtc::Handle,
+        next_x: &mut i32,
+    ) {
+        let output_name = connector_name(&connector);
+        let (make, model) = EdidInfo::for_connector(&self.drm, connector.handle())
+            .map(|info| (info.manufacturer, info.model))
+            .unwrap_or_else(|| ("Unknown".to_owned(), "Unknown".to_owned()));
+        let serial = edid_serial(&self.drm, connector.handle()).unwrap_or_default();
+        let config = CONFIG
+            .read()
+            .unwrap()
+            .output_config(&output_name, &make, &model, &serial)
+            .cloned();
+
+        let mode = match select_mode(&output_name, connector.modes(), config.as_ref()) {
+            Some(mode) => mode,
+            None => {
+                tracing::warn!("Connector {} has no modes", output_name);
+                return;
+            }
+        };
+        // Enabled outputs stay where they are, new ones go right of the others
+        let fallback = match self.surfaces.get(&crtc) {
+            Some(surface) if surface.compositor.is_some() => surface.output.current_location(),
+            _ => (*next_x, 0).into(),
+        };
+        let (position, scale, transform) = configured_state(config.as_ref(), fallback);
+        let enabled = config.as_ref().map_or(true, |config| config.enabled);
+        if !enabled {
+            tracing::info!("Output {} is disabled in the config", output_name);
+        }
+        let adaptive_sync = config
+            .as_ref()
+            .and_then(|config| config.adaptive_sync)
+            .unwrap_or_default();
+
+        if !self.surfaces.contains_key(&crtc) {
+            let (width, height) = connector.size().unwrap_or((0, 0));
+            let output = Output::new(
+                output_name.clone(),
+                PhysicalProperties {
+                    size: (width as i32, height as i32).into(),
+                    subpixel: subpixel(connector.subpixel()),
+                    make,
+                    model,
+                },
+            );
+            for drm_mode in connector.modes() {
+                output.add_mode(OutputMode::from(*drm_mode));
+            }
+            if let Some(preferred) = preferred_mode(connector.modes()) {
+                output.set_preferred(OutputMode::from(preferred));
+            }
+            let vrr_capable = vrr_capable(&self.drm, connector.handle());
+            if adaptive_sync != AdaptiveSync::Off && !vrr_capable {
+                tracing::info!(
+                    "Output {} doesn't support adaptive sync, ignoring the config",
+                    output_name
+                );
+            }
+            self.surfaces.insert(
+                crtc,
+                Surface {
+                    output,
+                    connector,
+                    global: None,
+                    compositor: None,
+                    redraw_state: RedrawState::Idle,
+                    pending_feedback: None,
+                    dmabuf_feedback: None,
+                    stats: OutputStats {
+                        name: output_name.clone(),
+                        ..OutputStats::default()
+                    },
+                    vrr_capable,
+                    adaptive_sync,
+                    vrr_enabled: false,
+                    powered: true,
+                },
+            );
+        }
+
+        let state = SurfaceState {
+            enabled,
+            mode,
+            position,
+            scale,
+            transform,
+            adaptive_sync,
+        };
+        match self.configure_surface(display_handle, crtc, state) {
+            Ok(()) if enabled => {
+                let output = &self.surfaces[&crtc].output;
+                *next_x = (*next_x).max(layout_right_edge(iter::once(output)));
+            }
+            Ok(()) => {}
+            Err(err) => tracing::error!("Failed to set up output {}: {}", output_name, err),
+        }
+    }
+
+    fn connector_disconnected(&mut self, display_handle: &DisplayHandle, crtc: crtc::Handle) {
+        if let Some(surface) = self.surfaces.remove(&crtc) {
+            if let Some(global) = surface.global {
+                display_handle.remove_global::<Waysight<DrmBackend>>(global);
+            }
+        }
+    }
+
+    // Tears down the outputs of a device that went away (e.g. an unplugged egpu) and stops
+    // listening to its events. The drm fd is closed once the device is dropped
+    pub fn remove(mut self, display_handle: &DisplayHandle) {
+        for (_, surface) in self.surfaces.drain() {
+            tracing::info!("Removing output {}", surface.output.name());
+            if let Some(global) = surface.global {
+                display_handle.remove_global::<Waysight<DrmBackend>>(global);
+            }
+        }
+        self.loop_handle.remove(self.event_token);
+    }
+}
+
+fn create_compositor(
+    drm: &DrmDevice,
+    gbm: &GbmDevice<DrmDeviceFd>,
+    render_formats: &HashSet<DrmFormat>,
+    output: &Output,
+    crtc: crtc::Handle,
+    mode: DrmMode,
+    connector: connector::Handle,
+) -> Result<GbmDrmCompositor, DeviceError> {
+    let surface = drm
+        .create_surface(crtc, mode, &[connector])
+        .map_err(DeviceError::Surface)?;
+    let allocator = GbmAllocator::new(
+        gbm.clone(),
+        GbmBufferFlags::RENDERING | GbmBufferFlags::SCANOUT,
+    );
+    // With a gbm device the compositor copies `Kind::Cursor` elements into a gbm buffer on the
+    // cursor plane. Cursors larger than the plane, or crtcs without one, are composited instead
+    let cursor_gbm = if !CONFIG.read().unwrap().drm.hardware_cursor {
+        tracing::debug!(
+            "Hardware cursor disabled, compositing the cursor on {}",
+            output.name()
+        );
+        None
+    } else if surface.planes().cursor.is_none() {
+        tracing::debug!(
+            "No cursor plane on {}, compositing the cursor",
+            output.name()
+        );
+        None
+    } else {
+        Some(gbm.clone())
+    };
+    DrmCompositor::new(
+        output,
+        surface,
+        None,
+        allocator,
+        gbm.clone(),
+        SUPPORTED_FORMATS,
+        render_formats.clone(),
+        drm.cursor_size(),
+        cursor_gbm,
+    )
+    .map_err(|err| DeviceError::Compositor(Box::new(err)))
+}
+
+fn surface_dmabuf_feedback(
+    compositor: &GbmDrmCompositor,
+    render_node: DrmNode,
+    render_formats: &HashSet<DrmFormat>,
+    scanout_node: Option<DrmNode>,
+) -> Option<SurfaceDmabufFeedback> {
+    let builder = DmabufFeedbackBuilder::new(render_node.dev_id(), render_formats.iter().copied());
+    let render = match builder.clone().build() {
+        Ok(feedback) => feedback,
+        Err(err) => {
+            tracing::warn!("Failed to build dmabuf feedback: {}", err);
+            return None;
+        }
+    };
+    let scanout_node = match scanout_node {
+        Some(node) => node,
+        None => {
+            return Some(SurfaceDmabufFeedback {
+                scanout: render.clone(),
+                render,
+            })
+        }
+    };
+
+    let drm_surface = compositor.surface();
+    let planes = drm_surface.planes();
+    let plane_formats: HashSet<DrmFormat> = iter::once(&planes.primary)
+        .chain(planes.overlay.iter())
+        .flat_map(|plane| {
+            drm_surface
+                .supported_formats(plane.handle)
+                .unwrap_or_default()
+        })
+        .filter(|format| render_formats.contains(format))
+        .collect();
+    let scanout = builder
+        .add_preference_tranche(
+            scanout_node.dev_id(),
+            Some(TrancheFlags::Scanout),
+            plane_formats,
+        )
+        .build()
+        .map_err(|err| tracing::warn!("Failed to build scanout dmabuf feedback: {}", err))
+        .ok()?;
+    Some(SurfaceDmabufFeedback { render, scanout })
+}
+
+fn connector_name(connector: &connector::Info) -> String {
+    format!(
+        "{}-{}",
+        connector.interface().as_str(),
+        connector.interface_id()
+    )
+}
+
+fn preferred_mode(modes: &[DrmMode]) -> Option<DrmMode> {
+    modes
+        .iter()
+        .find(|mode| mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+        .or_else(|| modes.first())
+        .copied()
+}
+
+// Picks the configured mode if the connector `name` supports it, falling back to the preferred
+// mode
+fn select_mode(name: &str, modes: &[DrmMode], config: Option<&OutputConfig>) -> Option<DrmMode> {
+    let wanted = match config.and_then(|config| config.mode) {
+        Some(wanted) => wanted,
+        None => return preferred_mode(modes),
+    };
+    let refresh = config.and_then(|config| config.refresh_mhz());
+
+    let configured = modes
+        .iter()
+        .filter(|mode| {
+            let (width, height) = mode.size();
+            width as i32 == wanted.width && height as i32 == wanted.height
+        })
+        // Without a refresh rate the fastest mode for the resolution wins
+        .min_by_key(|mode| {
+            let mode_refresh = OutputMode::from(**mode).refresh;
+            match refresh {
+                Some(refresh) => (mode_refresh - refresh).abs(),
+                None => -mode_refresh,
+            }
+        })
+        .copied();
+
+    if configured.is_none() {
+        tracing::warn!(
+            "Connector {} doesn't support the configured mode {}x{}, using its preferred mode",
+            name,
+            wanted.width,
+            wanted.height
+        );
+    }
+    configured.or_else(|| preferred_mode(modes))
+}
+
+// Mode blobs and framebuffers created for a test commit, released once it's done
+struct TestResources<'a> {
+    drm: &'a DrmDevice,
+    blobs: Vec<u64>,
+    framebuffers: Vec<framebuffer::Handle>,
+    buffers: Vec<BufferObject<()>>,
+}
+
+impl Drop for TestResources<'_> {
+    fn drop(&mut self) {
+        for framebuffer in self.framebuffers.drain(..) {
+            let _ = self.drm.destroy_framebuffer(framebuffer);
+        }
+        for blob in self.blobs.drain(..) {
+            let _ = self.drm.destroy_property_blob(blob);
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum PlaneType {
+    Overlay,
+    Primary,
+    Cursor,
+}
+
+fn property_handle<H: ResourceHandle>(
+    drm: &DrmDevice,
+    object: H,
+    name: &str,
+) -> io::Result<property::Handle> {
+    let properties = drm.get_properties(object)?;
+    let (handles, _) = properties.as_props_and_values();
+    for handle in handles {
+        if drm.get_property(*handle)?.name().to_str() == Ok(name) {
+            return Ok(*handle);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no {} property", name),
+    ))
+}
+
+fn add_property<H: ResourceHandle>(
+    drm: &DrmDevice,
+    request: &mut AtomicModeReq,
+    object: H,
+    name: &str,
+    value: Value<'static>,
+) -> io::Result<()> {
+    let property = property_handle(drm, object, name)?;
+    request.add_property(object, property, value);
+    Ok(())
+}
+
+fn plane_type(drm: &DrmDevice, plane: plane::Handle) -> io::Result<PlaneType> {
+    let property = property_handle(drm, plane, "type")?;
+    let properties = drm.get_properties(plane)?;
+    let (handles, values) = properties.as_props_and_values();
+    let value = handles
+        .iter()
+        .zip(values)
+        .find(|(handle, _)| **handle == property)
+        .map(|(_, value)| *value);
+    Ok(match value {
+        Some(1) => PlaneType::Primary,
+        Some(2) => PlaneType::Cursor,
+        _ => PlaneType::Overlay,
+    })
+}
+
+fn subpixel(subpixel: connector::SubPixel) -> Subpixel {
+    match subpixel {
+        connector::SubPixel::HorizontalRgb => Subpixel::HorizontalRgb,
+        connector::SubPixel::HorizontalBgr => Subpixel::HorizontalBgr,
+        connector::SubPixel::VerticalRgb => Subpixel::VerticalRgb,
+        connector::SubPixel::VerticalBgr => Subpixel::VerticalBgr,
+        connector::SubPixel::None => Subpixel::None,
+        _ => Subpixel::Unknown,
+    }
+}
+
+// `EdidInfo` doesn't expose the serial, so it is read from the raw EDID blob. It is either stored
+// as a string in a display descriptor or as a number in the EDID header
+fn edid_serial(drm: &DrmDevice, connector: connector::Handle) -> Option<String> {
+    let properties = drm.get_properties(connector).ok()?;
+    let (handles, values) = properties.as_props_and_values();
+    let blob = handles.iter().zip(values).find_map(|(handle, value)| {
+        let info = drm.get_property(*handle).ok()?;
+        (info.name().to_str().ok()? == "EDID").then_some(*value)
+    })?;
+    let edid = drm.get_property_blob(blob).ok()?;
+    if edid.len() < 128 {
+        return None;
+    }
+
+    // The four 18 byte descriptors start at offset 54, tag 0xff marks the serial string
+    for offset in [54, 72, 90, 108] {
+        let descriptor = &edid[offset..offset + 18];
+        if descriptor[..3] == [0, 0, 0] && descriptor[3] == 0xff {
+            let serial = String::from_utf8_lossy(&descriptor[5..]);
+            return Some(serial.trim_end_matches(['\n', ' ']).to_owned());
+        }
+    }
+    let serial = u32::from_le_bytes(edid[12..16].try_into().ok()?);
+    (serial != 0).then(|| serial.to_string())
+}
+
+// Set by the kernel when both the driver and the monitor, according to its EDID, support variable
+// refresh rates
+fn vrr_capable(drm: &DrmDevice, connector: connector::Handle) -> bool {
+    let properties = match drm.get_properties(connector) {
+        Ok(properties) => properties,
+        Err(_) => return false,
+    };
+    let (handles, values) = properties.as_props_and_values();
+    handles.iter().zip(values).any(|(handle, value)| {
+        let is_vrr_capable = drm
+            .get_property(*handle)
+            .ok()
+            .and_then(|info| info.name().to_str().ok().map(|name| name == "vrr_capable"))
+            .unwrap_or(false);
+        is_vrr_capable && *value != 0
+    })
 }
 
 pub fn find_primary_gpu<T: AsRef<str> + Clone>(seat: T) -> DrmNode {
@@ -134,3 +1417,104 @@ pub fn find_primary_gpu<T: AsRef<str> + Clone>(seat: T) -> DrmNode {
     };
     node
 }
+
+// The gpu every output is rendered with. Outputs connected to other gpus get the result copied
+// over. Defaults to the render node of the primary gpu
+pub fn find_render_gpu(primary_gpu: DrmNode) -> DrmNode {
+    let configured = CONFIG.read().unwrap().drm.render_device.clone();
+    if let Some(path) = configured {
+        match DrmNode::from_path(&path).map(|node| node.node_with_type(NodeType::Render)) {
+            Ok(Some(Ok(node))) => {
+                tracing::info!("Using configured render node: {}", path.display());
+                return node;
+            }
+            _ => tracing::error!(
+                "Configured render device {} is not a usable drm node, falling back to the primary gpu",
+                path.display()
+            ),
+        }
+    }
+    primary_gpu
+        .node_with_type(NodeType::Render)
+        .and_then(Result::ok)
+        .unwrap_or(primary_gpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timings are picked so the refresh rate comes out exact, 1 Hz per 2000 kHz of pixel clock
+    fn mode(width: u16, height: u16, refresh: u32, preferred: bool) -> DrmMode {
+        let mut info: drm_ffi::drm_mode_modeinfo = unsafe { std::mem::zeroed() };
+        info.hdisplay = width;
+        info.htotal = 2000;
+        info.vdisplay = height;
+        info.vtotal = 1000;
+        info.clock = refresh * 2000;
+        info.vrefresh = refresh;
+        if preferred {
+            info.type_ = ModeTypeFlags::PREFERRED.bits();
+        }
+        DrmMode::from(info)
+    }
+
+    fn config(entry: &str) -> OutputConfig {
+        toml::from_str(entry).unwrap()
+    }
+
+    fn refresh(mode: DrmMode) -> i32 {
+        OutputMode::from(mode).refresh
+    }
+
+    #[test]
+    fn modes_are_picked_by_refresh_rate() {
+        let modes = [
+            mode(2560, 1440, 60, true),
+            mode(2560, 1440, 144, false),
+            mode(2560, 1440, 120, false),
+            mode(1920, 1080, 60, false),
+        ];
+        let exact = config("mode = { width = 2560, height = 1440, refresh = 120.0 }");
+        let selected = select_mode("DP-1", &modes, Some(&exact)).unwrap();
+        assert_eq!(refresh(selected), 120_000);
+
+        let closest = config("mode = { width = 2560, height = 1440, refresh = 100.0 }");
+        let selected = select_mode("DP-1", &modes, Some(&closest)).unwrap();
+        assert_eq!(refresh(selected), 120_000);
+
+        // The fastest mode for the resolution without a refresh rate
+        let fastest = config("mode = { width = 2560, height = 1440 }");
+        let selected = select_mode("DP-1", &modes, Some(&fastest)).unwrap();
+        assert_eq!(refresh(selected), 144_000);
+
+        let other = config("mode = { width = 1920, height = 1080, refresh = 144.0 }");
+        let selected = select_mode("DP-1", &modes, Some(&other)).unwrap();
+        assert_eq!(selected.size(), (1920, 1080));
+    }
+
+    #[test]
+    fn modes_fall_back_to_the_preferred_one() {
+        let modes = [
+            mode(1920, 1080, 60, false),
+            mode(2560, 1440, 60, true),
+            mode(2560, 1440, 144, false),
+        ];
+        let preferred = Some((2560, 1440));
+        assert_eq!(
+            select_mode("DP-1", &modes, None).map(|mode| mode.size()),
+            preferred
+        );
+        let unsupported = config("mode = { width = 3840, height = 2160 }");
+        let selected = select_mode("DP-1", &modes, Some(&unsupported)).unwrap();
+        assert_eq!(selected.size(), (2560, 1440));
+        assert_eq!(refresh(selected), 60_000);
+
+        // Connectors without a preferred mode use their first one
+        assert_eq!(
+            select_mode("DP-1", &modes[..1], Some(&unsupported)).map(|mode| mode.size()),
+            Some((1920, 1080))
+        );
+        assert!(select_mode("DP-1", &[], None).is_none());
+    }
+}
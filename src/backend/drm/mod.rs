@@ -1,36 +1,516 @@
 mod device;
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
-use self::device::{find_primary_gpu, Device};
-use crate::state::{Backend, CalloopData, Waysight};
+use self::device::{find_primary_gpu, find_render_gpu, Device, SurfaceState};
+use crate::{
+    ipc::OutputStats,
+    protocols::{
+        output_management::{Head, HeadConfiguration, OutputConfigurationError},
+        tearing_control::{self, PresentationHint},
+    },
+    render,
+    state::{Backend, CalloopData, Waysight, CONFIG},
+    utils::output::{layout_right_edge, refresh_interval},
+};
 use smithay::{
     backend::{
-        drm::DrmNode,
-        session::{libseat::LibSeatSession, Session},
-        udev::UdevBackend,
+        allocator::{dmabuf::Dmabuf, Format as DrmFormat},
+        drm::{DrmEventMetadata, DrmEventTime, DrmNode},
+        libinput::{LibinputInputBackend, LibinputSessionInterface},
+        renderer::{
+            gles::GlesRenderer,
+            multigpu::{gbm::GbmGlesBackend, GpuManager, MultiRenderer},
+            ImportDma,
+        },
+        session::{libseat::LibSeatSession, Event as SessionEvent, Session},
+        udev::{UdevBackend, UdevEvent},
+    },
+    output::Output,
+    reexports::{
+        calloop::EventLoop,
+        drm::control::crtc,
+        input::Libinput,
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        wayland_server::{Display, DisplayHandle},
     },
-    reexports::{calloop::EventLoop, wayland_server::Display},
+    wayland::dmabuf::{DmabufFeedbackBuilder, DmabufGlobal},
 };
 
+// Renders on one gpu and, if needed, copies the result to the gpu driving the output
+pub type UdevRenderer<'a, 'b> =
+    MultiRenderer<'a, 'b, GbmGlesBackend<GlesRenderer>, GbmGlesBackend<GlesRenderer>>;
+
 pub struct DrmBackend {
     primary_gpu: DrmNode,
+    // Render node of the gpu doing all the rendering
+    render_node: DrmNode,
+    gpus: GpuManager<GbmGlesBackend<GlesRenderer>>,
     devices: HashMap<DrmNode, Device>,
     session: LibSeatSession,
+    libinput: Libinput,
+    dmabuf_global: Option<DmabufGlobal>,
+    // Formats clients can allocate their buffers with, i.e. what the render gpu can import
+    render_formats: HashSet<DrmFormat>,
+}
+
+impl DrmBackend {
+    pub fn change_vt(&mut self, vt: i32) {
+        if let Err(err) = self.session.change_vt(vt) {
+            tracing::error!("Failed to switch to vt {}: {}", vt, err);
+        }
+    }
 }
 
 impl Backend for DrmBackend {
     fn seat_name(&self) -> String {
         self.session.seat()
     }
+
+    fn schedule_render(&mut self, output: &Output) {
+        for device in self.devices.values_mut() {
+            if let Some(crtc) = device.crtc_for_output(output) {
+                device.schedule_render(crtc);
+                return;
+            }
+        }
+    }
+
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> bool {
+        let mut renderer = match self.gpus.single_renderer(&self.render_node) {
+            Ok(renderer) => renderer,
+            Err(err) => {
+                tracing::error!("Failed to get the renderer to import a dmabuf: {}", err);
+                return false;
+            }
+        };
+        match renderer.import_dmabuf(dmabuf, None) {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::debug!("Failed to import client dmabuf: {}", err);
+                false
+            }
+        }
+    }
+
+    fn render_node(&self) -> Option<DrmNode> {
+        Some(self.render_node)
+    }
+
+    fn set_output_power(&mut self, output: &Output, on: bool) -> bool {
+        for device in self.devices.values_mut() {
+            if let Some(crtc) = device.crtc_for_output(output) {
+                device.set_power(crtc, on);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn output_stats(&self) -> Vec<OutputStats> {
+        self.devices
+            .values()
+            .flat_map(Device::output_stats)
+            .collect()
+    }
+
+    fn apply_output_config(&mut self, display_handle: &DisplayHandle) {
+        let enabled: Vec<Output> = self
+            .heads()
+            .into_iter()
+            .filter(|head| head.enabled)
+            .map(|head| head.output)
+            .collect();
+        let mut next_x = layout_right_edge(enabled.iter());
+        for device in self.devices.values_mut() {
+            device.apply_output_config(display_handle, &mut next_x);
+        }
+    }
+
+    fn heads(&self) -> Vec<Head> {
+        self.devices.values().flat_map(Device::heads).collect()
+    }
+
+    fn apply_output_configuration(
+        &mut self,
+        display_handle: &DisplayHandle,
+        heads: &[HeadConfiguration],
+        test_only: bool,
+    ) -> Result<(), OutputConfigurationError> {
+        // Everything is resolved and tested before the first output is touched
+        let mut targets = Vec::new();
+        for head in heads {
+            let target = self.devices.iter().find_map(|(node, device)| {
+                device
+                    .resolve_head(head)
+                    .map(|result| result.map(|(crtc, state)| (*node, crtc, state)))
+            });
+            match target {
+                Some(target) => targets.push((target?, head.output.name())),
+                None => return Err(OutputConfigurationError::UnknownOutput(head.output.name())),
+            }
+        }
+        // One test commit per device with all of its heads, a configuration can fail only when
+        // applied together, e.g. because of bandwidth limits
+        for (node, device) in self.devices.iter() {
+            let states: Vec<(crtc::Handle, SurfaceState)> = targets
+                .iter()
+                .filter(|((target, _, _), _)| target == node)
+                .map(|((_, crtc, state), _)| (*crtc, *state))
+                .collect();
+            if !states.is_empty() {
+                device.test_surfaces(&states)?;
+            }
+        }
+        if test_only {
+            return Ok(());
+        }
+
+        let mut applied = Vec::new();
+        for ((node, crtc, state), name) in targets {
+            let device = self.devices.get_mut(&node).unwrap();
+            let previous = device.surface_state(crtc);
+            if let Err(err) = device.configure_surface(display_handle, crtc, state) {
+                // Roll back what was already applied so the layout isn't left half changed
+                for (node, crtc, previous) in applied.into_iter().rev() {
+                    let device = match self.devices.get_mut(&node) {
+                        Some(device) => device,
+                        None => continue,
+                    };
+                    // Heads without a previous state had no usable mode, so they were off
+                    match previous {
+                        Some(previous) => {
+                            let _ = device.configure_surface(display_handle, crtc, previous);
+                        }
+                        None => device.disable_surface(display_handle, crtc),
+                    }
+                }
+                return Err(OutputConfigurationError::Backend(name, err.to_string()));
+            }
+            applied.push((node, crtc, previous));
+        }
+        Ok(())
+    }
+}
+
+impl Waysight<DrmBackend> {
+    pub fn on_vblank(
+        &mut self,
+        node: DrmNode,
+        crtc: crtc::Handle,
+        metadata: &mut Option<DrmEventMetadata>,
+    ) {
+        let device = match self.backend_data.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        let (feedback, output) = match (device.frame_submitted(crtc), device.surface_output(crtc)) {
+            (Some(feedback), Some(output)) => (feedback, output),
+            _ => return,
+        };
+
+        // The page flip timestamp comes from the kernel, unless it's on the wrong clock
+        let timestamp = metadata.as_ref().and_then(|metadata| match metadata.time {
+            DrmEventTime::Monotonic(time) => Some(time),
+            DrmEventTime::Realtime(_) => None,
+        });
+        let (time, flags) = match timestamp {
+            Some(time) => (
+                time.into(),
+                wp_presentation_feedback::Kind::Vsync
+                    | wp_presentation_feedback::Kind::HwClock
+                    | wp_presentation_feedback::Kind::HwCompletion,
+            ),
+            None => (self.clock.now(), wp_presentation_feedback::Kind::Vsync),
+        };
+        let sequence = metadata
+            .as_ref()
+            .map_or(0, |metadata| metadata.sequence as u64);
+        feedback.presented(
+            time,
+            refresh_interval(&output).as_nanos() as u32,
+            sequence,
+            flags,
+        );
+    }
+
+    pub fn on_estimated_vblank(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        let device = match self.backend_data.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        let (feedback, output) = match (device.estimated_vblank(crtc), device.surface_output(crtc))
+        {
+            (Some(feedback), Some(output)) => (feedback, output),
+            _ => return,
+        };
+        // Nothing was flipped, so nothing is tied to a real retrace
+        feedback.presented(
+            self.clock.now(),
+            refresh_interval(&output).as_nanos() as u32,
+            0,
+            wp_presentation_feedback::Kind::empty(),
+        );
+    }
+
+    pub fn render_surface(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        self.space.refresh();
+        self.popups.cleanup();
+        let pointer_location = self.pointer_location();
+        let cursor_status = self.cursor_image_status.lock().unwrap().clone();
+
+        let backend = &mut self.backend_data;
+        // Another session owns the gpus, we repaint everything once we get them back
+        if !backend.session.is_active() {
+            return;
+        }
+        let device = match backend.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        let (format, output) = match (device.surface_format(crtc), device.surface_output(crtc)) {
+            (Some(format), Some(output)) => (format, output),
+            _ => return,
+        };
+        // Turned off after this render was queued
+        if !device.is_powered(crtc) {
+            device.finish_frame(crtc);
+            return;
+        }
+
+        let renderer = if device.render_node() == backend.render_node {
+            backend.gpus.single_renderer(&backend.render_node)
+        } else {
+            backend
+                .gpus
+                .renderer(&backend.render_node, &device.render_node(), format)
+        };
+        let mut renderer = match renderer {
+            Ok(renderer) => renderer,
+            Err(err) => {
+                tracing::error!("Failed to get a renderer for {}: {}", node, err);
+                device.finish_frame(crtc);
+                return;
+            }
+        };
+
+        // The lock screen hides fullscreen windows
+        let locked = self.session_lock.is_locked();
+        let fullscreen = self
+            .space
+            .elements_for_output(&output)
+            .find(|window| render::is_fullscreen(window))
+            .filter(|_| !locked);
+        let tearing = fullscreen.map_or(false, |window| {
+            tearing_control::presentation_hint(window.toplevel().wl_surface())
+                == PresentationHint::Async
+        });
+        device.update_adaptive_sync(crtc, fullscreen.is_some());
+        device.set_tearing(crtc, tearing);
+
+        let elements = render::output_elements(
+            &mut renderer,
+            &output,
+            &self.space,
+            &self.session_lock,
+            pointer_location,
+            &cursor_status,
+            &self.cursor_buffer,
+        );
+        let clear_color = CONFIG.read().unwrap().clear_color;
+        let (damaged, states) = match device.render(crtc, &mut renderer, &elements, clear_color) {
+            Some(result) => result,
+            None => return,
+        };
+        render::post_repaint(
+            &output,
+            &self.space,
+            &cursor_status,
+            Some(&states),
+            self.clock.now().into(),
+        );
+        self.session_lock
+            .post_repaint(&output, self.clock.now().into());
+        if let Some(feedback) =
+            device.dmabuf_feedback(crtc, backend.render_node, &backend.render_formats)
+        {
+            render::send_dmabuf_feedback(&output, &self.space, &states, feedback);
+        }
+        let feedback = render::take_presentation_feedback(&output, &self.space, Some(&states));
+        device.queue_frame(crtc, damaged, feedback);
+
+        let space = &self.space;
+        let session_lock = &self.session_lock;
+        let cursor_buffer = &self.cursor_buffer;
+        self.capture_state.process(
+            &mut renderer,
+            &output,
+            damaged,
+            locked,
+            self.clock.now().into(),
+            |renderer, overlay_cursor| {
+                render::output_elements(
+                    renderer,
+                    &output,
+                    space,
+                    session_lock,
+                    pointer_location.filter(|_| overlay_cursor),
+                    &cursor_status,
+                    cursor_buffer,
+                )
+            },
+        );
+    }
+
+    pub fn on_device_changed(&mut self, node: DrmNode) {
+        let mut next_x = layout_right_edge(self.space.outputs());
+        if let Some(device) = self.backend_data.devices.get_mut(&node) {
+            device.on_device_changed(&self.display_handle, &mut next_x);
+        }
+        self.outputs_changed();
+    }
+
+    fn pause_session(&mut self) {
+        tracing::info!("Pausing session");
+        self.backend_data.libinput.suspend();
+        for device in self.backend_data.devices.values_mut() {
+            device.pause();
+        }
+    }
+
+    fn activate_session(&mut self) {
+        tracing::info!("Resuming session");
+        if let Err(err) = self.backend_data.libinput.resume() {
+            tracing::error!("Failed to resume libinput: {:?}", err);
+        }
+        let nodes: Vec<DrmNode> = self.backend_data.devices.keys().copied().collect();
+        for node in nodes {
+            self.backend_data.devices.get_mut(&node).unwrap().activate();
+            // Monitors may have been plugged or unplugged while we were away
+            self.on_device_changed(node);
+        }
+    }
+
+    // A gpu showed up, either at startup or hotplugged. Devices that can't be opened are skipped so
+    // one broken gpu doesn't take the whole session down
+    pub fn on_device_added(&mut self, node: DrmNode, path: &Path) {
+        let backend = &mut self.backend_data;
+        // Udev reports devices that are already there again, e.g. after a session resume. Opening
+        // them a second time would leak the old device and its event sources
+        if backend.devices.contains_key(&node) {
+            tracing::debug!("Drm device {} is already added", path.display());
+            return;
+        }
+        let device = match Device::new(
+            node,
+            path,
+            &mut backend.session,
+            &mut backend.gpus,
+            &self.loop_handle,
+        ) {
+            Ok(device) => device,
+            Err(err) => {
+                tracing::warn!("Skipping drm device {}: {}", path.display(), err);
+                return;
+            }
+        };
+        tracing::info!("Added drm device {}", path.display());
+        backend.devices.insert(node, device);
+        self.update_render_node();
+        self.on_device_changed(node);
+    }
+
+    pub fn on_device_removed(&mut self, node: DrmNode) {
+        let device = match self.backend_data.devices.remove(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        tracing::info!("Removed drm device {}", node);
+        let render_node = device.render_node();
+        device.remove(&self.display_handle);
+        self.backend_data.gpus.as_mut().remove_node(&render_node);
+        self.update_render_node();
+        self.outputs_changed();
+    }
+
+    // Picks the gpu to render with among the devices that are currently present, so rendering moves
+    // back to the primary gpu when the configured render gpu is unplugged and over to it when it
+    // comes back
+    fn update_render_node(&mut self) {
+        let backend = &mut self.backend_data;
+        let present = |node: DrmNode| {
+            backend
+                .devices
+                .values()
+                .any(|device| device.render_node() == node)
+        };
+        let preferred = find_render_gpu(backend.primary_gpu);
+        let render_node = if present(preferred) {
+            preferred
+        } else {
+            match backend.devices.values().next() {
+                Some(device) => device.render_node(),
+                None => return,
+            }
+        };
+        if render_node == backend.render_node {
+            return;
+        }
+        tracing::info!("Rendering with {}", render_node);
+        backend.render_node = render_node;
+        for device in backend.devices.values_mut() {
+            device.schedule_render_all();
+        }
+        self.update_dmabuf_global();
+    }
+
+    // (Re)creates the dmabuf global for the render gpu, which is where client buffers get imported
+    fn update_dmabuf_global(&mut self) {
+        let backend = &mut self.backend_data;
+        let formats: HashSet<DrmFormat> = match backend.gpus.single_renderer(&backend.render_node) {
+            Ok(renderer) => renderer.dmabuf_formats().collect(),
+            Err(err) => {
+                tracing::error!(
+                    "Failed to get the dmabuf formats of the render gpu: {}",
+                    err
+                );
+                return;
+            }
+        };
+        if let Some(global) = backend.dmabuf_global.take() {
+            self.dmabuf_state
+                .destroy_global::<Self>(&self.display_handle, global);
+        }
+        match DmabufFeedbackBuilder::new(backend.render_node.dev_id(), formats.iter().copied())
+            .build()
+        {
+            Ok(feedback) => {
+                backend.dmabuf_global = Some(
+                    self.dmabuf_state
+                        .create_global_with_default_feedback::<Self>(
+                            &self.display_handle,
+                            &feedback,
+                        ),
+                );
+            }
+            Err(err) => tracing::error!("Failed to build the default dmabuf feedback: {}", err),
+        }
+        backend.render_formats = formats;
+        // The per-output feedback is built from the render formats
+        for device in backend.devices.values_mut() {
+            device.reset_dmabuf_feedback();
+        }
+    }
 }
 
 pub fn initialize() {
     let mut display: Display<Waysight<DrmBackend>> = Display::new().unwrap();
-    let event_loop: EventLoop<CalloopData<DrmBackend>> = EventLoop::try_new().unwrap();
+    let mut event_loop: EventLoop<CalloopData<DrmBackend>> = EventLoop::try_new().unwrap();
 
-    let (mut session, notifier) = match LibSeatSession::new() {
+    let (session, notifier) = match LibSeatSession::new() {
         Ok((ses, not)) => {
             tracing::info!(
                 "Successfully created libseat session with name: {}",
@@ -44,33 +524,85 @@ pub fn initialize() {
         }
     };
 
+    let mut libinput_context =
+        Libinput::new_with_udev::<LibinputSessionInterface<LibSeatSession>>(session.clone().into());
+    if libinput_context.udev_assign_seat(&session.seat()).is_err() {
+        tracing::error!("Failed to assign seat {} to libinput", session.seat());
+        return;
+    }
+    let libinput_backend = LibinputInputBackend::new(libinput_context.clone());
+
     let backend = UdevBackend::new(session.seat().as_str()).unwrap();
     let primary_gpu = find_primary_gpu(session.seat().as_str());
+    let render_node = find_render_gpu(primary_gpu);
+    let gpus = match GpuManager::new(GbmGlesBackend::default()) {
+        Ok(gpus) => gpus,
+        Err(err) => {
+            tracing::error!("Error creating the gpu manager: {}", err);
+            return;
+        }
+    };
     let data = DrmBackend {
         primary_gpu,
+        render_node,
+        gpus,
         devices: HashMap::new(),
         session,
+        libinput: libinput_context,
+        dmabuf_global: None,
+        render_formats: HashSet::new(),
     };
 
-    let mut state = RefCell::new(Waysight::new(&event_loop, &mut display, data));
+    let mut state = Waysight::new(&event_loop, &mut display, data);
     for (dev_id, node_path) in backend.device_list() {
-        let node = DrmNode::from_path(node_path).unwrap();
-        state.borrow_mut().backend_data.devices.insert(
-            node,
-            Device::new(
-                node,
-                node_path,
-                &mut state.borrow_mut().backend_data.session,
-            )
-            .expect("Error creating device struct"),
-        );
-        // Created a let binding because `state.borrow_mut()` creates a temporary value that lives shorter than `device`
-        let mut borrowed_state = state.borrow_mut();
-        let device = if let Some(device) = borrowed_state.backend_data.devices.get_mut(&node) {
-            device
-        } else {
-            return;
-        };
-        device.on_device_changed(&mut state.borrow_mut());
+        match DrmNode::from_dev_id(dev_id) {
+            Ok(node) => state.on_device_added(node, node_path),
+            Err(err) => tracing::warn!("Skipping drm device {}: {}", node_path.display(), err),
+        }
     }
+    if state.backend_data.devices.is_empty() {
+        tracing::error!("No usable drm device found");
+        return;
+    }
+    state.update_dmabuf_global();
+
+    event_loop
+        .handle()
+        .insert_source(libinput_backend, |event, _, data| {
+            data.state.parse_input_event(event)
+        })
+        .unwrap();
+    event_loop
+        .handle()
+        .insert_source(notifier, |event, _, data| match event {
+            SessionEvent::PauseSession => data.state.pause_session(),
+            SessionEvent::ActivateSession => data.state.activate_session(),
+        })
+        .unwrap();
+    event_loop
+        .handle()
+        .insert_source(backend, |event, _, data| match event {
+            UdevEvent::Added { device_id, path } => match DrmNode::from_dev_id(device_id) {
+                Ok(node) => data.state.on_device_added(node, &path),
+                Err(err) => tracing::warn!("Skipping drm device {}: {}", path.display(), err),
+            },
+            UdevEvent::Changed { device_id } => {
+                if let Ok(node) = DrmNode::from_dev_id(device_id) {
+                    data.state.on_device_changed(node);
+                }
+            }
+            UdevEvent::Removed { device_id } => {
+                if let Ok(node) = DrmNode::from_dev_id(device_id) {
+                    data.state.on_device_removed(node);
+                }
+            }
+        })
+        .unwrap();
+
+    let mut data = CalloopData { display, state };
+    event_loop
+        .run(None, &mut data, move |data| {
+            data.display.flush_clients().unwrap();
+        })
+        .expect("Failure to run event loop");
 }
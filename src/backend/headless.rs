@@ -0,0 +1,394 @@
+use std::{env, time::Duration};
+
+use crate::{
+    ipc::OutputStats,
+    protocols::output_management::{
+        Head, HeadConfiguration, ModeConfiguration, OutputConfigurationError,
+    },
+    render,
+    state::{Backend, CalloopData, Waysight},
+    utils::output::{configured_state, find_output_config, refresh_interval},
+};
+use smithay::{
+    backend::{
+        allocator::dmabuf::Dmabuf,
+        drm::DrmNode,
+        egl::{EGLContext, EGLDevice, EGLDisplay},
+        renderer::gles::GlesRenderer,
+    },
+    output::{Mode, Output, PhysicalProperties, Scale, Subpixel},
+    reexports::{
+        calloop::{
+            timer::{TimeoutAction, Timer},
+            EventLoop,
+        },
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        wayland_server::{backend::GlobalId, Display, DisplayHandle},
+    },
+    utils::{Logical, Point, Transform},
+};
+
+fn default_mode() -> Mode {
+    Mode {
+        size: (1920, 1080).into(),
+        refresh: 60_000,
+    }
+}
+
+// An output that only exists in the compositor, nothing is ever displayed
+struct VirtualOutput {
+    output: Output,
+    global: Option<GlobalId>,
+    render_pending: bool,
+    // Number of emulated refresh cycles, reported as the presentation sequence
+    sequence: u64,
+    // Turned off outputs skip their repaints, like a disabled crtc
+    powered: bool,
+}
+
+impl VirtualOutput {
+    fn set_state(
+        &mut self,
+        display_handle: &DisplayHandle,
+        enabled: bool,
+        mode: Mode,
+        position: Point<i32, Logical>,
+        scale: Scale,
+        transform: Transform,
+    ) {
+        if !enabled {
+            if let Some(global) = self.global.take() {
+                display_handle.remove_global::<Waysight<HeadlessBackend>>(global);
+            }
+            return;
+        }
+        // Any mode can be simulated, so requested modes are added to the advertised list
+        if !self.output.modes().contains(&mode) {
+            self.output.add_mode(mode);
+        }
+        self.output
+            .change_current_state(Some(mode), Some(transform), Some(scale), Some(position));
+        if self.global.is_none() {
+            self.global = Some(
+                self.output
+                    .create_global::<Waysight<HeadlessBackend>>(display_handle),
+            );
+        }
+    }
+}
+
+pub struct HeadlessBackend {
+    outputs: Vec<VirtualOutput>,
+    // Only used for screen capture. Software rendering is good enough, so this works in CI too
+    renderer: Option<GlesRenderer>,
+}
+
+fn init_renderer() -> Option<GlesRenderer> {
+    let result = EGLDevice::enumerate()
+        .map_err(|err| err.to_string())
+        .and_then(|mut devices| devices.next().ok_or_else(|| "no egl device".to_owned()))
+        .and_then(|device| EGLDisplay::new(device).map_err(|err| err.to_string()))
+        .and_then(|display| EGLContext::new(&display).map_err(|err| err.to_string()))
+        .and_then(|context| unsafe { GlesRenderer::new(context) }.map_err(|err| err.to_string()));
+    match result {
+        Ok(renderer) => Some(renderer),
+        Err(err) => {
+            tracing::warn!("No renderer, screen capture is unavailable: {}", err);
+            None
+        }
+    }
+}
+
+impl Backend for HeadlessBackend {
+    fn seat_name(&self) -> String {
+        "waysight-seat".to_owned()
+    }
+
+    fn apply_output_config(&mut self, display_handle: &DisplayHandle) {
+        // Outputs without a configured position are laid out left to right
+        let mut next_x = 0;
+        for virtual_output in self.outputs.iter_mut() {
+            let config = find_output_config(&virtual_output.output, "");
+            let (position, scale, transform) =
+                configured_state(config.as_ref(), (next_x, 0).into());
+            let enabled = config.as_ref().map_or(true, |config| config.enabled);
+            let mode = config
+                .as_ref()
+                .and_then(|config| {
+                    config.mode.map(|mode| Mode {
+                        size: (mode.width, mode.height).into(),
+                        refresh: config.refresh_mhz().unwrap_or(default_mode().refresh),
+                    })
+                })
+                .unwrap_or_else(default_mode);
+            if enabled {
+                next_x = position.x + mode.size.w;
+            }
+            virtual_output.set_state(display_handle, enabled, mode, position, scale, transform);
+        }
+    }
+
+    fn schedule_render(&mut self, output: &Output) {
+        if let Some(virtual_output) = self
+            .outputs
+            .iter_mut()
+            .find(|virtual_output| &virtual_output.output == output)
+        {
+            virtual_output.render_pending = true;
+        }
+    }
+
+    // The renderer only draws captures, no dmabuf global is advertised
+    fn import_dmabuf(&mut self, _dmabuf: &Dmabuf) -> bool {
+        false
+    }
+
+    // Without a dmabuf global clients can only hand us shm buffers
+    fn render_node(&self) -> Option<DrmNode> {
+        None
+    }
+
+    fn set_output_power(&mut self, output: &Output, on: bool) -> bool {
+        match self
+            .outputs
+            .iter_mut()
+            .find(|virtual_output| &virtual_output.output == output)
+        {
+            Some(virtual_output) => {
+                virtual_output.powered = on;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Every emulated refresh cycle counts as a frame
+    fn output_stats(&self) -> Vec<OutputStats> {
+        self.outputs
+            .iter()
+            .map(|virtual_output| OutputStats {
+                name: virtual_output.output.name(),
+                frames: virtual_output.sequence,
+                ..OutputStats::default()
+            })
+            .collect()
+    }
+
+    fn heads(&self) -> Vec<Head> {
+        self.outputs
+            .iter()
+            .map(|virtual_output| Head {
+                output: virtual_output.output.clone(),
+                enabled: virtual_output.global.is_some(),
+                adaptive_sync: false,
+            })
+            .collect()
+    }
+
+    // Simulated: every configuration is valid, applying it only updates the `Output`s
+    fn apply_output_configuration(
+        &mut self,
+        display_handle: &DisplayHandle,
+        heads: &[HeadConfiguration],
+        test_only: bool,
+    ) -> Result<(), OutputConfigurationError> {
+        for head in heads {
+            if !self
+                .outputs
+                .iter()
+                .any(|virtual_output| virtual_output.output == head.output)
+            {
+                return Err(OutputConfigurationError::UnknownOutput(head.output.name()));
+            }
+            // There is no refresh cycle to stretch
+            if head.adaptive_sync == Some(true) {
+                return Err(OutputConfigurationError::AdaptiveSyncUnsupported(
+                    head.output.name(),
+                ));
+            }
+        }
+        if test_only {
+            return Ok(());
+        }
+
+        for head in heads {
+            let virtual_output = self
+                .outputs
+                .iter_mut()
+                .find(|virtual_output| virtual_output.output == head.output)
+                .unwrap();
+            let output = &virtual_output.output;
+            let current_mode = output.current_mode().unwrap_or_else(default_mode);
+            let mode = match head.mode {
+                Some(ModeConfiguration::Mode(mode)) => mode,
+                Some(ModeConfiguration::Custom { size, refresh }) => Mode {
+                    size,
+                    refresh: refresh.unwrap_or(default_mode().refresh),
+                },
+                None => current_mode,
+            };
+            let position = head.position.unwrap_or(output.current_location());
+            let scale = head
+                .scale
+                .map(Scale::Fractional)
+                .unwrap_or(output.current_scale());
+            let transform = head.transform.unwrap_or(output.current_transform());
+            virtual_output.set_state(
+                display_handle,
+                head.enabled,
+                mode,
+                position,
+                scale,
+                transform,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Waysight<HeadlessBackend> {
+    // Nothing is displayed, but clients still get their frame callbacks at the output's refresh
+    // rate. The output is only drawn when a screen capture asks for it
+    fn on_refresh(&mut self, output: &Output) {
+        let virtual_output = match self
+            .backend_data
+            .outputs
+            .iter_mut()
+            .find(|virtual_output| &virtual_output.output == output)
+        {
+            Some(virtual_output) => virtual_output,
+            None => return,
+        };
+        if virtual_output.global.is_none() {
+            return;
+        }
+        virtual_output.sequence += 1;
+        if !virtual_output.render_pending || !virtual_output.powered {
+            return;
+        }
+        virtual_output.render_pending = false;
+        let sequence = virtual_output.sequence;
+        self.space.refresh();
+        self.popups.cleanup();
+        let cursor_status = self.cursor_image_status.lock().unwrap().clone();
+        render::post_repaint(
+            output,
+            &self.space,
+            &cursor_status,
+            None,
+            self.clock.now().into(),
+        );
+        self.session_lock
+            .post_repaint(output, self.clock.now().into());
+        // The emulated refresh cycles stand in for vblanks
+        render::take_presentation_feedback(output, &self.space, None).presented(
+            self.clock.now(),
+            refresh_interval(output).as_nanos() as u32,
+            sequence,
+            wp_presentation_feedback::Kind::Vsync,
+        );
+
+        // Every repaint was asked for by something that changed, so it counts as damage
+        let pointer_location = self.pointer_location();
+        let renderer = match self.backend_data.renderer.as_mut() {
+            Some(renderer) => renderer,
+            None => {
+                self.capture_state.fail_output(output);
+                return;
+            }
+        };
+        let space = &self.space;
+        let session_lock = &self.session_lock;
+        let cursor_buffer = &self.cursor_buffer;
+        self.capture_state.process(
+            renderer,
+            output,
+            true,
+            session_lock.is_locked(),
+            self.clock.now().into(),
+            |renderer, overlay_cursor| {
+                render::output_elements(
+                    renderer,
+                    output,
+                    space,
+                    session_lock,
+                    pointer_location.filter(|_| overlay_cursor),
+                    &cursor_status,
+                    cursor_buffer,
+                )
+            },
+        );
+    }
+}
+
+pub fn initialize() {
+    let mut display = Display::<Waysight<HeadlessBackend>>::new().unwrap();
+    let mut event_loop = EventLoop::<'static, CalloopData<HeadlessBackend>>::try_new().unwrap();
+
+    // Tests can ask for more than one output to exercise multi-output code paths
+    let output_count = env::var("WAYSIGHT_HEADLESS_OUTPUTS")
+        .ok()
+        .and_then(|count| count.parse::<usize>().ok())
+        .unwrap_or(1);
+    let outputs = (1..=output_count)
+        .map(|index| {
+            let output = Output::new(
+                format!("HEADLESS-{}", index),
+                PhysicalProperties {
+                    size: (0, 0).into(),
+                    subpixel: Subpixel::Unknown,
+                    make: "Waysight".to_owned(),
+                    model: "Headless".to_owned(),
+                },
+            );
+            output.add_mode(default_mode());
+            output.set_preferred(default_mode());
+            VirtualOutput {
+                output,
+                global: None,
+                render_pending: true,
+                sequence: 0,
+                powered: true,
+            }
+        })
+        .collect();
+
+    let backend_data = HeadlessBackend {
+        outputs,
+        renderer: init_renderer(),
+    };
+    let mut state = Waysight::new(&event_loop, &mut display, backend_data);
+    state
+        .backend_data
+        .apply_output_config(&state.display_handle);
+    state.outputs_changed();
+    tracing::info!(
+        "Started headless backend with {} outputs on {}",
+        output_count,
+        state.socket_name
+    );
+
+    let outputs: Vec<Output> = state
+        .backend_data
+        .outputs
+        .iter()
+        .map(|virtual_output| virtual_output.output.clone())
+        .collect();
+    for output in outputs {
+        state
+            .loop_handle
+            .insert_source(Timer::immediate(), move |_, _, data| {
+                data.state.on_refresh(&output);
+                TimeoutAction::ToDuration(refresh_interval(&output))
+            })
+            .unwrap();
+    }
+
+    let mut data = CalloopData { display, state };
+    env::set_var("WAYLAND_DISPLAY", data.state.socket_name.clone());
+    event_loop
+        .run(Duration::from_millis(8), &mut data, move |data| {
+            data.display.flush_clients().unwrap();
+        })
+        .expect("Failure to run event loop");
+}
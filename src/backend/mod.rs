@@ -1,12 +1,16 @@
 use std::env;
 
+use crate::USER_DATA;
+
 pub mod drm;
+pub mod headless;
 pub mod winit;
 
 pub fn backend_init_from_name(name: &str) {
     match name {
         "drm" => drm::initialize(),
         "winit" => winit::initialize(),
+        "headless" => headless::initialize(),
         _ => {
             tracing::error!("Unknown backend");
         }
@@ -14,7 +18,10 @@ pub fn backend_init_from_name(name: &str) {
 }
 
 pub fn backend_autoinit() {
-    if env::var("WAYLAND_DISPLAY").is_ok() || env::var("DISPLAY").is_ok() {
+    let user_backend = USER_DATA.lock().unwrap().backend.clone();
+    if let Some(backend) = user_backend {
+        backend_init_from_name(&backend);
+    } else if env::var("WAYLAND_DISPLAY").is_ok() || env::var("DISPLAY").is_ok() {
         backend_init_from_name("winit");
     } else {
         backend_init_from_name("drm");
@@ -1,37 +1,226 @@
 use std::{borrow::Borrow, env, time::Duration};
 
-use crate::state::{Backend, CalloopData, Waysight, CONFIG};
+use crate::{
+    ipc::OutputStats,
+    protocols::output_management::{
+        Head, HeadConfiguration, ModeConfiguration, OutputConfigurationError,
+    },
+    render,
+    state::{Backend, CalloopData, Waysight, CONFIG},
+    utils::output::{configured_state, find_output_config, refresh_interval},
+};
 use smithay::{
     backend::{
-        renderer::{gles::GlesRenderer, glow::GlowRenderer, Frame, Renderer},
+        allocator::{dmabuf::Dmabuf, Format as DmabufFormat},
+        drm::DrmNode,
+        egl::EGLDevice,
+        renderer::{damage::OutputDamageTracker, glow::GlowRenderer, ImportDma},
         winit::{self, WinitError, WinitEvent, WinitEventLoop, WinitGraphicsBackend},
     },
-    output::{Mode, Output, PhysicalProperties, Subpixel},
+    output::{Mode, Output, PhysicalProperties, Scale, Subpixel},
     reexports::{
         calloop::{
             timer::{TimeoutAction, Timer},
             EventLoop,
         },
-        wayland_server::Display,
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        wayland_server::{Display, DisplayHandle},
     },
-    utils::{Rectangle, Size, Transform},
+    utils::Transform,
+    wayland::dmabuf::DmabufFeedbackBuilder,
 };
 
-pub struct WinitBackend {}
+// Refresh rate used when neither the config nor the monitor the window is on gives one
+const DEFAULT_REFRESH: i32 = 60_000;
+
+pub struct WinitBackend {
+    backend: WinitGraphicsBackend<GlowRenderer>,
+    output: Output,
+    damage_tracker: OutputDamageTracker,
+    // Refresh rate of the monitor the window was opened on
+    monitor_refresh: i32,
+    render_pending: bool,
+    // Frames actually submitted, for ipc stats
+    frames: u64,
+    // Found when creating the dmabuf global, `None` with software rendering
+    render_node: Option<DrmNode>,
+}
+
+impl WinitBackend {
+    // The damage tracker has to be recreated whenever the size, scale or transform changes, the
+    // next frame is then a full repaint
+    fn reset_damage_tracker(&mut self) {
+        self.damage_tracker = damage_tracker(&self.output);
+        self.render_pending = true;
+    }
+}
 
 impl Backend for WinitBackend {
     fn seat_name(&self) -> String {
         "waysight-seat".to_owned()
     }
+
+    fn apply_output_config(&mut self, _display_handle: &DisplayHandle) {
+        let config = find_output_config(&self.output, "");
+        if config.as_ref().map_or(false, |config| !config.enabled) {
+            tracing::warn!(
+                "Ignoring `enabled = false` for the winit output, it is the only output"
+            );
+        }
+        if config
+            .as_ref()
+            .map_or(false, |config| config.mode.is_some())
+        {
+            tracing::debug!("The winit output resolution follows the window size");
+        }
+
+        let (position, scale, transform) = configured_state(config.as_ref(), (0, 0).into());
+        let refresh = config
+            .as_ref()
+            .and_then(|config| config.refresh_mhz())
+            .unwrap_or(self.monitor_refresh);
+        let mode = self
+            .output
+            .current_mode()
+            .map(|mode| Mode { refresh, ..mode });
+        self.output
+            .change_current_state(mode, Some(transform), Some(scale), Some(position));
+        self.reset_damage_tracker();
+    }
+
+    fn schedule_render(&mut self, output: &Output) {
+        if output == &self.output {
+            self.render_pending = true;
+        }
+    }
+
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> bool {
+        match self.backend.renderer().import_dmabuf(dmabuf, None) {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::debug!("Failed to import client dmabuf: {}", err);
+                false
+            }
+        }
+    }
+
+    fn render_node(&self) -> Option<DrmNode> {
+        self.render_node
+    }
+
+    // The window can't be turned off
+    fn set_output_power(&mut self, _output: &Output, _on: bool) -> bool {
+        false
+    }
+
+    // Everything is composited into the window
+    fn output_stats(&self) -> Vec<OutputStats> {
+        vec![OutputStats {
+            name: self.output.name(),
+            frames: self.frames,
+            ..OutputStats::default()
+        }]
+    }
+
+    fn heads(&self) -> Vec<Head> {
+        vec![Head {
+            output: self.output.clone(),
+            enabled: true,
+            adaptive_sync: false,
+        }]
+    }
+
+    // Simulated: the output is a window, so it can't be disabled and its resolution follows the
+    // window size. Everything else is only stored on the `Output`
+    fn apply_output_configuration(
+        &mut self,
+        _display_handle: &DisplayHandle,
+        heads: &[HeadConfiguration],
+        test_only: bool,
+    ) -> Result<(), OutputConfigurationError> {
+        let current_mode = self.output.current_mode();
+        let mut new_state = None;
+        for head in heads {
+            if head.output != self.output {
+                return Err(OutputConfigurationError::UnknownOutput(head.output.name()));
+            }
+            if !head.enabled {
+                return Err(OutputConfigurationError::CannotDisable(head.output.name()));
+            }
+            if head.adaptive_sync == Some(true) {
+                return Err(OutputConfigurationError::AdaptiveSyncUnsupported(
+                    head.output.name(),
+                ));
+            }
+            let mode = match (head.mode, current_mode) {
+                (None, current_mode) => current_mode,
+                (Some(ModeConfiguration::Mode(mode)), Some(current))
+                    if mode.size == current.size =>
+                {
+                    Some(mode)
+                }
+                (Some(ModeConfiguration::Custom { size, refresh }), Some(current))
+                    if size == current.size =>
+                {
+                    Some(Mode {
+                        size,
+                        refresh: refresh.unwrap_or(current.refresh),
+                    })
+                }
+                _ => {
+                    return Err(OutputConfigurationError::UnsupportedMode(
+                        head.output.name(),
+                    ))
+                }
+            };
+            new_state = Some((mode, head.transform, head.scale, head.position));
+        }
+
+        if let (false, Some((mode, transform, scale, position))) = (test_only, new_state) {
+            self.output.change_current_state(
+                mode,
+                transform,
+                scale.map(Scale::Fractional),
+                position,
+            );
+            self.reset_damage_tracker();
+        }
+        Ok(())
+    }
+}
+
+// The winit framebuffer is upside down compared to what clients expect, so rendering always
+// applies a 180 degree flip on top of the output transform
+fn winit_transform(transform: Transform) -> Transform {
+    match transform {
+        Transform::Normal => Transform::Flipped180,
+        Transform::_90 => Transform::Flipped270,
+        Transform::_180 => Transform::Flipped,
+        Transform::_270 => Transform::Flipped90,
+        Transform::Flipped => Transform::_180,
+        Transform::Flipped90 => Transform::_270,
+        Transform::Flipped180 => Transform::Normal,
+        Transform::Flipped270 => Transform::_90,
+    }
 }
+
+fn damage_tracker(output: &Output) -> OutputDamageTracker {
+    let size = output
+        .current_mode()
+        .map(|mode| mode.size)
+        .unwrap_or_default();
+    OutputDamageTracker::new(
+        size,
+        output.current_scale().fractional_scale(),
+        winit_transform(output.current_transform()),
+    )
+}
+
 pub fn initialize() {
     let mut display = Display::<Waysight<WinitBackend>>::new().unwrap();
     let mut event_loop = EventLoop::<'static, CalloopData<WinitBackend>>::try_new().unwrap();
 
-    let backend_data = WinitBackend {};
-
-    let state = Waysight::new(&event_loop, &mut display, backend_data);
-    let (mut backend, mut winit_event_loop) = match winit::init::<GlowRenderer>() {
+    let (backend, mut winit_event_loop) = match winit::init::<GlowRenderer>() {
         Ok((backend, winit_event_loop)) => (backend, winit_event_loop),
         Err(err) => {
             tracing::error!("Failure initializing winit backend: {}", err);
@@ -39,11 +228,11 @@ pub fn initialize() {
         }
     };
 
-    let mode = Mode {
-        size: backend.window_size().physical_size,
-        refresh: 60_000,
-    };
-
+    let monitor_refresh = backend
+        .window()
+        .current_monitor()
+        .and_then(|monitor| monitor.refresh_rate_millihertz())
+        .map_or(DEFAULT_REFRESH, |refresh| refresh as i32);
     let output = Output::new(
         "waysight".to_owned(),
         PhysicalProperties {
@@ -53,20 +242,39 @@ pub fn initialize() {
             model: "Waysight".to_owned(),
         },
     );
+    let mode = Mode {
+        size: backend.window_size().physical_size,
+        refresh: monitor_refresh,
+    };
+    output.change_current_state(Some(mode), None, None, None);
+    output.set_preferred(mode);
+
+    let backend_data = WinitBackend {
+        backend,
+        output: output.clone(),
+        damage_tracker: damage_tracker(&output),
+        monitor_refresh,
+        render_pending: true,
+        frames: 0,
+        render_node: None,
+    };
+    let mut state = Waysight::new(&event_loop, &mut display, backend_data);
     let _global = output.create_global::<Waysight<WinitBackend>>(&state.display_handle);
-    output.change_current_state(
-        Some(mode),
-        Some(Transform::Flipped180),
-        None,
-        Some((0, 0).into()),
-    );
+    state
+        .backend_data
+        .apply_output_config(&state.display_handle);
+    state.outputs_changed();
+    state.init_dmabuf_global();
+
     let mut data = CalloopData { display, state };
+    // Winit events still have to be polled, but a frame is only drawn when something asked for
+    // one, at most once per refresh cycle
     let timer = Timer::immediate();
     data.state
         .loop_handle
         .insert_source(timer, move |_, _, data: &mut _| {
-            dispatch_winit_events(data, &mut winit_event_loop, &output, &mut backend);
-            TimeoutAction::ToDuration(Duration::from_millis(16))
+            dispatch_winit_events(data, &mut winit_event_loop, &output);
+            TimeoutAction::ToDuration(refresh_interval(&output))
         })
         .unwrap();
     env::set_var("WAYLAND_DISPLAY", data.state.socket_name.clone());
@@ -77,11 +285,38 @@ pub fn initialize() {
         .expect("Failure to run event loop");
 }
 
+impl Waysight<WinitBackend> {
+    // The whole window is rendered by one gpu, so the default feedback is all clients get
+    fn init_dmabuf_global(&mut self) {
+        let renderer = self.backend_data.backend.renderer();
+        let formats: Vec<DmabufFormat> = renderer.dmabuf_formats().collect();
+        let render_node = EGLDevice::device_for_display(renderer.egl_context().display())
+            .ok()
+            .and_then(|device| device.try_get_render_node().ok().flatten());
+        self.backend_data.render_node = render_node;
+        let feedback = render_node.and_then(|node| {
+            DmabufFeedbackBuilder::new(node.dev_id(), formats.clone())
+                .build()
+                .ok()
+        });
+        let _global = match feedback {
+            Some(feedback) => self
+                .dmabuf_state
+                .create_global_with_default_feedback::<Self>(&self.display_handle, &feedback),
+            // Without a known main device (e.g. software rendering) only v3 can be offered
+            None => {
+                tracing::warn!("Could not find the render node, advertising linux-dmabuf v3");
+                self.dmabuf_state
+                    .create_global::<Self>(&self.display_handle, formats)
+            }
+        };
+    }
+}
+
 fn dispatch_winit_events(
     data: &mut CalloopData<WinitBackend>,
     winit_event_loop: &mut WinitEventLoop,
     output: &Output,
-    backend: &mut WinitGraphicsBackend<GlowRenderer>,
 ) {
     let ret = winit_event_loop.dispatch_new_events(|event| match event {
         WinitEvent::Input(event) => data.state.parse_input_event_winit(event),
@@ -89,39 +324,113 @@ fn dispatch_winit_events(
             size,
             scale_factor: _,
         } => {
-            output.change_current_state(
-                Some(Mode {
-                    refresh: 60_000,
-                    size,
-                }),
-                None,
-                None,
-                None,
-            );
+            let refresh = output
+                .current_mode()
+                .map_or(DEFAULT_REFRESH, |mode| mode.refresh);
+            output.change_current_state(Some(Mode { refresh, size }), None, None, None);
+            data.state.backend_data.reset_damage_tracker();
+            data.state.outputs_changed();
         }
         _ => {}
     });
 
-    backend.window().borrow().set_title("Waysight");
+    data.state
+        .backend_data
+        .backend
+        .window()
+        .borrow()
+        .set_title("Waysight");
 
     if let Err(WinitError::WindowClosed) = ret {
         tracing::info!("Closed winit window, stopping the loop");
-        data.state.loop_signal.stop();
+        data.state.stop();
     }
 
-    let size = backend.window_size().physical_size;
-    let damage = Rectangle::from_loc_and_size((0, 0), size);
-
-    backend.bind().unwrap();
-    backend
-        .renderer()
-        .render(size, output.current_transform())
-        .unwrap()
-        .clear(
-            CONFIG.clear_color,
-            &[Rectangle::from_loc_and_size((0, 0), size)],
-        )
-        .unwrap();
+    let state = &mut data.state;
+    if !state.backend_data.render_pending {
+        return;
+    }
+    state.backend_data.render_pending = false;
+    state.space.refresh();
+    state.popups.cleanup();
 
-    backend.submit(Some(&[damage])).unwrap();
+    let pointer_location = state.pointer_location();
+    let cursor_status = state.cursor_image_status.lock().unwrap().clone();
+    let backend = &mut state.backend_data.backend;
+    if let Err(err) = backend.bind() {
+        tracing::error!("Failed to bind the winit surface: {}", err);
+        return;
+    }
+    let age = backend.buffer_age().unwrap_or(0);
+    let elements = render::output_elements(
+        backend.renderer(),
+        output,
+        &state.space,
+        &state.session_lock,
+        pointer_location,
+        &cursor_status,
+        &state.cursor_buffer,
+    );
+    let clear_color = CONFIG.read().unwrap().clear_color;
+    let result = state.backend_data.damage_tracker.render_output(
+        backend.renderer(),
+        age,
+        &elements,
+        clear_color,
+    );
+    let (damaged, states) = match result {
+        Ok((Some(damage), states)) => {
+            if let Err(err) = backend.submit(Some(&damage)) {
+                tracing::error!("Failed to submit the winit frame: {}", err);
+            }
+            state.backend_data.frames += 1;
+            (true, states)
+        }
+        // Nothing changed since the last frame, don't swap buffers at all
+        Ok((None, states)) => (false, states),
+        Err(err) => {
+            tracing::error!("Failed to render the winit output: {:?}", err);
+            return;
+        }
+    };
+    render::post_repaint(
+        output,
+        &state.space,
+        &cursor_status,
+        Some(&states),
+        state.clock.now().into(),
+    );
+    state
+        .session_lock
+        .post_repaint(output, state.clock.now().into());
+    // Winit doesn't tell us when the frame hits the screen, so it counts as presented right away.
+    // There is no retrace counter and no flag can be guaranteed
+    render::take_presentation_feedback(output, &state.space, Some(&states)).presented(
+        state.clock.now(),
+        refresh_interval(output).as_nanos() as u32,
+        0,
+        wp_presentation_feedback::Kind::empty(),
+    );
+
+    let space = &state.space;
+    let session_lock = &state.session_lock;
+    let cursor_buffer = &state.cursor_buffer;
+    state.capture_state.process(
+        state.backend_data.backend.renderer(),
+        output,
+        damaged,
+        session_lock.is_locked(),
+        state.clock.now().into(),
+        |renderer, overlay_cursor| {
+            render::output_elements(
+                renderer,
+                output,
+                space,
+                session_lock,
+                pointer_location.filter(|_| overlay_cursor),
+                &cursor_status,
+                cursor_buffer,
+            )
+        },
+    );
 }
@@ -0,0 +1,706 @@
+// Screen capture shared by wlr-screencopy, ext-image-copy-capture and the screenshot and recording
+// actions. Requests are queued here along with a repaint, the backends fulfil them right after
+// repainting the output
+use std::{
+    env, fs, io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use smithay::{
+    backend::{
+        allocator::{dmabuf::Dmabuf, Fourcc},
+        renderer::{
+            buffer_type,
+            damage::OutputDamageTracker,
+            element::utils::{Relocate, RelocateRenderElement},
+            gles::GlesRenderbuffer,
+            Bind, BufferType, ExportMem, ImportAll, ImportMem, Offscreen, Renderer,
+        },
+    },
+    desktop::{Space, Window},
+    output::Output,
+    reexports::wayland_server::protocol::{wl_buffer::WlBuffer, wl_shm},
+    utils::{Buffer, IsAlive, Logical, Physical, Point, Rectangle, Size, Transform},
+    wayland::{
+        dmabuf::get_dmabuf,
+        shm::{with_buffer_contents, with_buffer_contents_mut},
+    },
+};
+
+use crate::{
+    protocols::{image_copy_capture, screencopy},
+    recording::Recording,
+    render::{self, OutputRenderElements},
+    screenshot,
+    state::CONFIG,
+};
+
+// Formats clients can hand us to copy into
+pub const SHM_FORMATS: [wl_shm::Format; 2] = [wl_shm::Format::Xrgb8888, wl_shm::Format::Argb8888];
+pub const DMABUF_FORMATS: [Fourcc; 2] = [Fourcc::Xrgb8888, Fourcc::Argb8888];
+
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    #[error("The captured output or window is gone")]
+    SourceGone,
+    #[error("Buffer has the wrong size")]
+    BufferSize,
+    #[error("Buffer has an unsupported format")]
+    BufferFormat,
+    #[error("Buffer is neither shm nor dmabuf")]
+    BufferType,
+    #[error("Failed to access the buffer")]
+    BufferAccess,
+    #[error("Failed to render: {0}")]
+    Render(String),
+    #[error("The output size changed")]
+    SizeChanged,
+    #[error("Failed to write the file: {0}")]
+    Save(String),
+    #[error("The session is locked")]
+    Locked,
+}
+
+impl CaptureError {
+    // Errors the client can fix by allocating a buffer that matches the advertised constraints
+    pub fn is_buffer_constraint(&self) -> bool {
+        matches!(
+            self,
+            CaptureError::BufferSize | CaptureError::BufferFormat | CaptureError::BufferType
+        )
+    }
+}
+
+// The protocol object waiting for the result
+pub enum CaptureFrame {
+    Wlr(screencopy::Frame),
+    Ext(image_copy_capture::Frame),
+}
+
+pub enum CaptureTarget {
+    // A client buffer from one of the capture protocols
+    Buffer {
+        buffer: WlBuffer,
+        frame: CaptureFrame,
+    },
+    // A png saved by the compositor. Unlike client buffers it is upright, the output transform is
+    // already applied
+    Screenshot(PathBuf),
+}
+
+pub enum CaptureSource {
+    // Relative to the output, `None` captures all of it
+    Output(Option<Rectangle<i32, Logical>>),
+    // A window and its popups on their own, whatever is stacked above them and wherever they are
+    Toplevel(Window),
+}
+
+pub struct CaptureRequest {
+    // The output whose next repaint performs the capture. Toplevels are rendered at its scale
+    pub output: Output,
+    pub source: CaptureSource,
+    pub overlay_cursor: bool,
+    // Copy-with-damage: nothing is sent until the output changed
+    pub wait_for_damage: bool,
+    pub target: CaptureTarget,
+}
+
+impl CaptureRequest {
+    fn fail(self, err: &CaptureError) {
+        match self.target {
+            CaptureTarget::Buffer { frame, .. } => frame.failed(err),
+            CaptureTarget::Screenshot(path) => {
+                tracing::error!("Failed to save screenshot {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CaptureState {
+    pending: Vec<CaptureRequest>,
+    // Captured windows that committed since their capture was queued
+    damaged_windows: Vec<Window>,
+    recording: Option<Recording>,
+}
+
+impl CaptureState {
+    // The caller schedules a repaint of the output, the capture happens along with it
+    pub fn queue(&mut self, request: CaptureRequest) {
+        self.pending.push(request);
+    }
+
+    // Marks the captures of `window` as damaged. Returns the outputs that have to be repainted for
+    // them, the window itself may not be visible on any
+    pub fn window_committed(&mut self, window: &Window) -> Vec<Output> {
+        let outputs: Vec<Output> = self
+            .pending
+            .iter()
+            .filter(|request| {
+                matches!(&request.source, CaptureSource::Toplevel(captured) if captured == window)
+            })
+            .map(|request| request.output.clone())
+            .collect();
+        if !outputs.is_empty() && !self.damaged_windows.contains(window) {
+            self.damaged_windows.push(window.clone());
+        }
+        outputs
+    }
+
+    // Fails the captures of outputs that were disabled or unplugged
+    pub fn retain_outputs(&mut self, outputs: &[Output]) {
+        let (kept, gone): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|request| outputs.contains(&request.output));
+        self.pending = kept;
+        for request in gone {
+            request.fail(&CaptureError::SourceGone);
+        }
+        if let Some(recording) = &self.recording {
+            if !outputs.contains(&recording.output) {
+                tracing::warn!(
+                    "Stopping the recording of {}, the output is gone",
+                    recording.output.name()
+                );
+                self.stop_recording();
+            }
+        }
+    }
+
+    // Fails the captures of `output`, for backends that have no renderer to fulfil them
+    pub fn fail_output(&mut self, output: &Output) {
+        let (gone, kept): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|request| &request.output == output);
+        self.pending = kept;
+        for request in gone {
+            request.fail(&CaptureError::Render("no renderer available".to_owned()));
+        }
+        if self
+            .recording
+            .as_ref()
+            .map_or(false, |recording| &recording.output == output)
+        {
+            tracing::error!(
+                "Stopping the recording of {}, there is no renderer",
+                output.name()
+            );
+            self.stop_recording();
+        }
+    }
+
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    // Replaces a running recording. The caller schedules a repaint of the output, which writes
+    // the first frame
+    pub fn start_recording(&mut self, recording: Recording) {
+        self.stop_recording();
+        tracing::info!(
+            "Recording {} to {}",
+            recording.output.name(),
+            recording.path.display()
+        );
+        self.recording = Some(recording);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let mut recording = self.recording.take()?;
+        if let Err(err) = recording.finish() {
+            tracing::error!(
+                "Failed to finish the recording {}: {}",
+                recording.path.display(),
+                err
+            );
+        }
+        tracing::info!(
+            "Stopped recording {} after {} frames, {} dropped",
+            recording.path.display(),
+            recording.frames,
+            recording.dropped
+        );
+        Some(recording)
+    }
+
+    // Called by the backends once `output` was repainted. `damaged` tells whether the frame changed
+    // anything, captures waiting for damage stay queued otherwise. `elements` returns what is on
+    // the output, with or without the cursor. Windows can't be captured while the session is
+    // locked
+    pub fn process<R, F>(
+        &mut self,
+        renderer: &mut R,
+        output: &Output,
+        damaged: bool,
+        locked: bool,
+        time: Duration,
+        mut elements: F,
+    ) where
+        R: Renderer
+            + ImportAll
+            + ImportMem
+            + Bind<Dmabuf>
+            + Offscreen<GlesRenderbuffer>
+            + ExportMem,
+        <R as Renderer>::TextureId: Clone + 'static,
+        F: FnMut(&mut R, bool) -> Vec<OutputRenderElements<R>>,
+    {
+        let damaged_windows = &self.damaged_windows;
+        let (due, waiting): (Vec<_>, Vec<_>) = self.pending.drain(..).partition(|request| {
+            let source_damaged = match &request.source {
+                CaptureSource::Output(_) => damaged,
+                CaptureSource::Toplevel(window) => damaged_windows.contains(window),
+            };
+            &request.output == output && (source_damaged || !request.wait_for_damage)
+        });
+        self.pending = waiting;
+        self.damaged_windows.retain(|window| {
+            !due.iter()
+                .any(|request| matches!(&request.source, CaptureSource::Toplevel(captured) if captured == window))
+        });
+
+        let scale = output.current_scale().fractional_scale();
+        for request in due {
+            if locked && matches!(request.source, CaptureSource::Toplevel(_)) {
+                request.fail(&CaptureError::Locked);
+                continue;
+            }
+            let CaptureRequest {
+                source,
+                overlay_cursor,
+                target,
+                ..
+            } = request;
+            // Client buffers of outputs are in the output's buffer orientation, everything else is
+            // upright
+            let transform = match (&source, &target) {
+                (CaptureSource::Output(_), CaptureTarget::Buffer { .. }) => {
+                    output.current_transform()
+                }
+                _ => Transform::Normal,
+            };
+            let size = source_size(output, &source).map(|size| transform.transform_size(size));
+            let region = match &source {
+                CaptureSource::Output(region) => *region,
+                CaptureSource::Toplevel(_) => None,
+            };
+            let mut source_elements = |renderer: &mut R| match &source {
+                CaptureSource::Output(_) => elements(renderer, overlay_cursor),
+                CaptureSource::Toplevel(window) => {
+                    render::toplevel_elements(renderer, window, scale)
+                }
+            };
+
+            match target {
+                CaptureTarget::Buffer { buffer, frame } => {
+                    let result = size.ok_or(CaptureError::SourceGone).and_then(|size| {
+                        check_buffer(&buffer, size)?;
+                        let elements = source_elements(renderer);
+                        render_to_buffer(
+                            renderer, output, region, &buffer, size, transform, elements,
+                        )?;
+                        Ok(size)
+                    });
+                    match result {
+                        Ok(size) => frame.ready(size, transform, time),
+                        Err(err) => {
+                            tracing::debug!("Capture on {} failed: {}", output.name(), err);
+                            frame.failed(&err);
+                        }
+                    }
+                }
+                CaptureTarget::Screenshot(path) => {
+                    let result = size.ok_or(CaptureError::SourceGone).and_then(|size| {
+                        let elements = source_elements(renderer);
+                        let pixels =
+                            render_to_memory(renderer, output, region, size, transform, elements)?;
+                        screenshot::write_png(&path, size, &pixels)
+                            .map_err(|err| CaptureError::Save(err.to_string()))
+                    });
+                    match result {
+                        Ok(()) => tracing::info!("Saved screenshot {}", path.display()),
+                        Err(err) => {
+                            tracing::error!("Failed to save screenshot {}: {}", path.display(), err)
+                        }
+                    }
+                }
+            }
+            // Windows that aren't visible anywhere only get throttled frame callbacks, a captured
+            // one has to keep drawing at the rate it is captured
+            if let CaptureSource::Toplevel(window) = &source {
+                window.send_frame(output, time, None, |_, _| Some(output.clone()));
+            }
+        }
+
+        // Only frames that changed something are recorded, the first one always is
+        let recording = match self.recording.as_mut() {
+            Some(recording) if &recording.output == output => recording,
+            _ => return,
+        };
+        if !damaged && recording.frames > 0 {
+            return;
+        }
+        let result = upright_size(output, None)
+            .filter(|size| *size == recording.size)
+            .ok_or(CaptureError::SizeChanged)
+            .and_then(|size| {
+                let elements = elements(renderer, true);
+                let pixels =
+                    render_to_memory(renderer, output, None, size, Transform::Normal, elements)?;
+                recording
+                    .write_frame(pixels, time)
+                    .map_err(|err| CaptureError::Save(err.to_string()))
+            });
+        if let Err(err) = result {
+            tracing::error!("Stopping the recording of {}: {}", output.name(), err);
+            self.stop_recording();
+        }
+    }
+}
+
+impl CaptureFrame {
+    fn ready(&self, size: Size<i32, Physical>, transform: Transform, time: Duration) {
+        match self {
+            CaptureFrame::Wlr(frame) => frame.ready(size, time),
+            CaptureFrame::Ext(frame) => frame.ready(size, transform, time),
+        }
+    }
+
+    fn failed(&self, err: &CaptureError) {
+        match self {
+            CaptureFrame::Wlr(frame) => frame.failed(),
+            CaptureFrame::Ext(frame) => frame.failed(err),
+        }
+    }
+}
+
+// Size of the buffer a capture of `region`, or all, of `output` needs. Images are in the output's
+// buffer orientation, clients apply the output transform themselves
+pub fn buffer_size(
+    output: &Output,
+    region: Option<Rectangle<i32, Logical>>,
+) -> Option<Size<i32, Physical>> {
+    let size = upright_size(output, region)?;
+    Some(output.current_transform().transform_size(size))
+}
+
+// Upright size of a capture of `source` in physical pixels
+pub fn source_size(output: &Output, source: &CaptureSource) -> Option<Size<i32, Physical>> {
+    match source {
+        CaptureSource::Output(region) => upright_size(output, *region),
+        CaptureSource::Toplevel(window) => {
+            if !window.alive() {
+                return None;
+            }
+            let scale = output.current_scale().fractional_scale();
+            let size = window.geometry().size.to_physical_precise_round(scale);
+            (size.w > 0 && size.h > 0).then_some(size)
+        }
+    }
+}
+
+// The output a capture of `window` is done with: the first one it is shown on, else any
+pub fn toplevel_output(space: &Space<Window>, window: &Window) -> Option<Output> {
+    space
+        .outputs_for_element(window)
+        .into_iter()
+        .next()
+        .or_else(|| space.outputs().next().cloned())
+}
+
+// Size of `region`, or all, of `output` in physical pixels, as it is shown on screen
+pub fn upright_size(
+    output: &Output,
+    region: Option<Rectangle<i32, Logical>>,
+) -> Option<Size<i32, Physical>> {
+    let mode = output.current_mode()?;
+    match region {
+        None => Some(output.current_transform().transform_size(mode.size)),
+        Some(region) => {
+            let scale = output.current_scale().fractional_scale();
+            Some(region.to_physical_precise_round(scale).size)
+        }
+    }
+}
+
+// A new file for a screenshot or recording. The directory comes from the config, else the xdg
+// user directory variable, else `~/<fallback>`, and is created if needed
+pub fn output_path(
+    configured: Option<PathBuf>,
+    xdg_variable: &str,
+    fallback: &str,
+    prefix: &str,
+    extension: &str,
+) -> io::Result<PathBuf> {
+    let dir = configured
+        .or_else(|| env::var_os(xdg_variable).map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+    fs::create_dir_all(&dir)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(dir.join(format!(
+        "{}-{}-{:03}.{}",
+        prefix,
+        now.as_secs(),
+        now.subsec_millis(),
+        extension
+    )))
+}
+
+pub fn check_buffer(buffer: &WlBuffer, size: Size<i32, Physical>) -> Result<(), CaptureError> {
+    match buffer_type(buffer) {
+        Some(BufferType::Shm) => with_buffer_contents(buffer, |_, _, data| {
+            check_shm(data.format, data.width, data.height, data.stride, size)
+        })
+        .map_err(|_| CaptureError::BufferAccess)?,
+        Some(BufferType::Dmabuf) => {
+            let dmabuf = get_dmabuf(buffer).map_err(|_| CaptureError::BufferAccess)?;
+            if (dmabuf.width(), dmabuf.height()) != (size.w as u32, size.h as u32) {
+                Err(CaptureError::BufferSize)
+            } else if !DMABUF_FORMATS.contains(&dmabuf.format().code) {
+                Err(CaptureError::BufferFormat)
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(CaptureError::BufferType),
+    }
+}
+
+fn check_shm(
+    format: wl_shm::Format,
+    width: i32,
+    height: i32,
+    stride: i32,
+    size: Size<i32, Physical>,
+) -> Result<(), CaptureError> {
+    if (width, height) != (size.w, size.h) {
+        Err(CaptureError::BufferSize)
+    } else if !SHM_FORMATS.contains(&format) || stride < width * 4 {
+        Err(CaptureError::BufferFormat)
+    } else {
+        Ok(())
+    }
+}
+
+// Draws the captured area into a client's buffer. Dmabufs are rendered to directly, shm buffers get
+// a copy of an offscreen render
+fn render_to_buffer<R>(
+    renderer: &mut R,
+    output: &Output,
+    region: Option<Rectangle<i32, Logical>>,
+    buffer: &WlBuffer,
+    size: Size<i32, Physical>,
+    transform: Transform,
+    elements: Vec<OutputRenderElements<R>>,
+) -> Result<(), CaptureError>
+where
+    R: Renderer + ImportAll + ImportMem + Bind<Dmabuf> + Offscreen<GlesRenderbuffer> + ExportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    match buffer_type(buffer) {
+        Some(BufferType::Dmabuf) => {
+            let dmabuf = get_dmabuf(buffer).map_err(|_| CaptureError::BufferAccess)?;
+            renderer
+                .bind(dmabuf)
+                .map_err(|err| CaptureError::Render(err.to_string()))?;
+            render_region(renderer, output, region, size, transform, elements)
+        }
+        Some(BufferType::Shm) => {
+            let pixels = render_to_memory(renderer, output, region, size, transform, elements)?;
+            copy_to_shm(buffer, &pixels, size)
+        }
+        _ => Err(CaptureError::BufferType),
+    }
+}
+
+// Renders offscreen and reads the result back as tightly packed Argb8888 rows
+fn render_to_memory<R>(
+    renderer: &mut R,
+    output: &Output,
+    region: Option<Rectangle<i32, Logical>>,
+    size: Size<i32, Physical>,
+    transform: Transform,
+    elements: Vec<OutputRenderElements<R>>,
+) -> Result<Vec<u8>, CaptureError>
+where
+    R: Renderer + ImportAll + ImportMem + Offscreen<GlesRenderbuffer> + ExportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let buffer_size = Size::<i32, Buffer>::from((size.w, size.h));
+    let renderbuffer: GlesRenderbuffer = renderer
+        .create_buffer(Fourcc::Argb8888, buffer_size)
+        .map_err(|err| CaptureError::Render(err.to_string()))?;
+    renderer
+        .bind(renderbuffer)
+        .map_err(|err| CaptureError::Render(err.to_string()))?;
+    render_region(renderer, output, region, size, transform, elements)?;
+    let mapping = renderer
+        .copy_framebuffer(
+            Rectangle::from_loc_and_size((0, 0), buffer_size),
+            Fourcc::Argb8888,
+        )
+        .map_err(|err| CaptureError::Render(err.to_string()))?;
+    let pixels = renderer
+        .map_texture(&mapping)
+        .map_err(|err| CaptureError::Render(err.to_string()))?;
+    Ok(pixels.to_vec())
+}
+
+// Renders the elements of `output` into whatever is bound, shifted so `region` starts at the
+// origin. A fresh damage tracker makes it a full repaint
+fn render_region<R>(
+    renderer: &mut R,
+    output: &Output,
+    region: Option<Rectangle<i32, Logical>>,
+    size: Size<i32, Physical>,
+    transform: Transform,
+    elements: Vec<OutputRenderElements<R>>,
+) -> Result<(), CaptureError>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let scale = output.current_scale().fractional_scale();
+    let offset = region
+        .map(|region| region.loc.to_physical_precise_round(scale))
+        .unwrap_or_default();
+    let elements: Vec<_> = elements
+        .into_iter()
+        .map(|element| {
+            RelocateRenderElement::from_element(
+                element,
+                Point::from((-offset.x, -offset.y)),
+                Relocate::Relative,
+            )
+        })
+        .collect();
+    let mut damage_tracker = OutputDamageTracker::new(size, scale, transform);
+    let clear_color = CONFIG.read().unwrap().clear_color;
+    damage_tracker
+        .render_output(renderer, 0, &elements, clear_color)
+        .map_err(|err| CaptureError::Render(format!("{:?}", err)))?;
+    Ok(())
+}
+
+// Copies tightly packed rows into the shm buffer, which may have a larger stride
+fn copy_to_shm(
+    buffer: &WlBuffer,
+    pixels: &[u8],
+    size: Size<i32, Physical>,
+) -> Result<(), CaptureError> {
+    with_buffer_contents_mut(buffer, |ptr, len, data| {
+        // The pool stays mapped while this runs
+        let memory = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+        copy_rows(
+            memory,
+            data.offset as usize,
+            data.stride as usize,
+            pixels,
+            size,
+        )
+    })
+    .map_err(|_| CaptureError::BufferAccess)?
+}
+
+// Copies the rows of `pixels` to `memory`, starting at `offset` and `stride` bytes apart
+fn copy_rows(
+    memory: &mut [u8],
+    offset: usize,
+    stride: usize,
+    pixels: &[u8],
+    size: Size<i32, Physical>,
+) -> Result<(), CaptureError> {
+    let row = size.w as usize * 4;
+    let rows = size.h as usize;
+    if pixels.len() < row * rows || stride < row {
+        return Err(CaptureError::BufferAccess);
+    }
+    if rows > 0 && offset + stride * (rows - 1) + row > memory.len() {
+        return Err(CaptureError::BufferAccess);
+    }
+    for y in 0..rows {
+        let start = offset + y * stride;
+        memory[start..start + row].copy_from_slice(&pixels[y * row..(y + 1) * row]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(w: i32, h: i32) -> Size<i32, Physical> {
+        (w, h).into()
+    }
+
+    #[test]
+    fn shm_buffer_must_match_the_capture() {
+        let format = wl_shm::Format::Xrgb8888;
+        assert!(check_shm(format, 4, 2, 16, size(4, 2)).is_ok());
+        assert!(check_shm(wl_shm::Format::Argb8888, 4, 2, 32, size(4, 2)).is_ok());
+        assert!(matches!(
+            check_shm(format, 4, 3, 16, size(4, 2)),
+            Err(CaptureError::BufferSize)
+        ));
+        assert!(matches!(
+            check_shm(format, 2, 4, 8, size(4, 2)),
+            Err(CaptureError::BufferSize)
+        ));
+        assert!(matches!(
+            check_shm(wl_shm::Format::Abgr8888, 4, 2, 16, size(4, 2)),
+            Err(CaptureError::BufferFormat)
+        ));
+        assert!(matches!(
+            check_shm(format, 4, 2, 12, size(4, 2)),
+            Err(CaptureError::BufferFormat)
+        ));
+    }
+
+    #[test]
+    fn rows_are_copied_with_offset_and_stride() {
+        let pixels: Vec<u8> = (1..=16).collect();
+        let mut memory = vec![0u8; 4 + 12 * 2];
+        copy_rows(&mut memory, 4, 12, &pixels, size(2, 2)).unwrap();
+        assert_eq!(&memory[..4], &[0; 4]);
+        assert_eq!(&memory[4..12], &pixels[..8]);
+        assert_eq!(&memory[12..16], &[0; 4]);
+        assert_eq!(&memory[16..24], &pixels[8..]);
+        assert_eq!(&memory[24..], &[0; 4]);
+    }
+
+    #[test]
+    fn copies_stay_in_bounds() {
+        let pixels = vec![0xffu8; 16];
+        // The last row doesn't fit
+        let mut memory = vec![0u8; 15];
+        assert!(matches!(
+            copy_rows(&mut memory, 0, 8, &pixels, size(2, 2)),
+            Err(CaptureError::BufferAccess)
+        ));
+        // The offset pushes the last row out
+        let mut memory = vec![0u8; 16];
+        assert!(matches!(
+            copy_rows(&mut memory, 1, 8, &pixels, size(2, 2)),
+            Err(CaptureError::BufferAccess)
+        ));
+        // Rows would overlap
+        let mut memory = vec![0u8; 64];
+        assert!(matches!(
+            copy_rows(&mut memory, 0, 4, &pixels, size(2, 2)),
+            Err(CaptureError::BufferAccess)
+        ));
+        // Not enough pixels for the size
+        assert!(matches!(
+            copy_rows(&mut memory, 0, 8, &pixels[..12], size(2, 2)),
+            Err(CaptureError::BufferAccess)
+        ));
+        assert!(memory.iter().all(|byte| *byte == 0));
+        assert!(copy_rows(&mut [], 0, 0, &[], size(0, 0)).is_ok());
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
+use smithay::utils::Transform;
 use std::{
+    collections::HashMap,
     env,
     fs::{self, File},
     io::{self, Write},
@@ -8,7 +10,7 @@ use std::{
 use thiserror::Error;
 use toml::de::Error as DeserializeError;
 
-use crate::USER_DATA;
+use crate::{recording::RecordingFormat, USER_DATA};
 
 #[derive(Serialize, Deserialize)]
 pub struct WaysightConfig {
@@ -17,6 +19,27 @@ pub struct WaysightConfig {
     pub input: InputConfig,
     #[serde(default = "default_cc")]
     pub clear_color: [f32; 4],
+    #[serde(default)]
+    pub autostart: AutostartConfig,
+    // Extra variables exported to every process spawned by the compositor
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    #[serde(default, rename = "output")]
+    pub outputs: Vec<OutputConfig>,
+    #[serde(default)]
+    pub drm: DrmConfig,
+    #[serde(default)]
+    pub decoration: DecorationConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub focus: FocusConfig,
+    // Key combinations mapped to ipc commands, e.g. `"Super+Shift+s" = "screenshot window"`.
+    // Modifiers are Ctrl, Alt, Shift and Super, the key is an xkb keysym name
+    #[serde(default)]
+    pub bindings: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,6 +48,200 @@ pub struct InputConfig {
     pub keyboard_layout: String,
     #[serde(default = "default_variant")]
     pub layout_variant: String,
+    // xkb rules and model, empty for the system default
+    #[serde(default)]
+    pub rules: String,
+    #[serde(default)]
+    pub model: String,
+    // Comma separated xkb options, e.g. "caps:escape,compose:ralt"
+    #[serde(default)]
+    pub options: Option<String>,
+    // Milliseconds a key is held before it repeats
+    #[serde(default = "default_repeat_delay")]
+    pub repeat_delay: i32,
+    // Repeats per second
+    #[serde(default = "default_repeat_rate")]
+    pub repeat_rate: i32,
+    // Turn outputs that were turned off, e.g. by swayidle, back on with any input
+    #[serde(default)]
+    pub wake_outputs: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct AutostartConfig {
+    // Commands run once, after the wayland socket is up
+    #[serde(default)]
+    pub exec: Vec<String>,
+    // Commands run at startup and again on every config reload
+    #[serde(default)]
+    pub exec_always: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SystemdConfig {
+    // Send READY=1/STOPPING=1 to $NOTIFY_SOCKET, for use with `Type=notify` units
+    #[serde(default)]
+    pub notify: bool,
+    // Import WAYLAND_DISPLAY, DISPLAY, WAYSIGHT_SOCKET and XDG_CURRENT_DESKTOP into the systemd
+    // user environment
+    #[serde(default)]
+    pub import_environment: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DrmConfig {
+    // Gpu used for rendering, e.g. "/dev/dri/renderD129". Either the primary or the render node
+    // can be given. Defaults to the primary gpu of the seat
+    pub render_device: Option<PathBuf>,
+    // Show the pointer on the cursor plane when the output has one and the image fits. Turning it
+    // off always composites the cursor, a workaround for drivers with broken cursor planes
+    #[serde(default = "default_enabled")]
+    pub hardware_cursor: bool,
+}
+
+impl Default for DrmConfig {
+    fn default() -> Self {
+        DrmConfig {
+            render_device: None,
+            hardware_cursor: true,
+        }
+    }
+}
+
+// Where the screenshot and recording actions save their files. Unset directories default to
+// $XDG_PICTURES_DIR and $XDG_VIDEOS_DIR, else ~/Pictures and ~/Videos
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct CaptureConfig {
+    pub screenshot_dir: Option<PathBuf>,
+    pub recording_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub recording_format: RecordingFormat,
+}
+
+// Windows are always focused when they map and when they are clicked
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub struct FocusConfig {
+    // Focus the window under the pointer, without raising it
+    #[serde(default)]
+    pub follows_mouse: bool,
+    // Move the pointer to the center of windows focused with the keyboard, activation or ipc
+    #[serde(default)]
+    pub mouse_follows_focus: bool,
+}
+
+// Server side borders drawn around every window
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct DecorationConfig {
+    // In logical pixels, 0 disables the borders
+    #[serde(default = "default_border_width")]
+    pub border_width: i32,
+    #[serde(default = "default_border_color")]
+    pub border_color: [f32; 4],
+}
+
+impl Default for DecorationConfig {
+    fn default() -> Self {
+        DecorationConfig {
+            border_width: default_border_width(),
+            border_color: default_border_color(),
+        }
+    }
+}
+
+// An `[[output]]` entry. Outputs are matched by connector name, or by any combination of
+// make/model/serial for setups where connector names aren't stable (e.g. docks)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutputConfig {
+    pub name: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub mode: Option<OutputModeConfig>,
+    pub position: Option<[i32; 2]>,
+    pub scale: Option<f64>,
+    pub transform: Option<OutputTransform>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // Variable refresh rate, only has an effect on outputs reporting `vrr_capable`
+    pub adaptive_sync: Option<AdaptiveSync>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AdaptiveSync {
+    #[default]
+    #[serde(rename = "off")]
+    Off,
+    #[serde(rename = "on")]
+    On,
+    // Only while a fullscreen window is shown, desktop use stays at the fixed refresh rate
+    #[serde(rename = "fullscreen-only")]
+    FullscreenOnly,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct OutputModeConfig {
+    pub width: i32,
+    pub height: i32,
+    // In Hz. The mode with the closest refresh rate is picked when unset or not an exact match
+    pub refresh: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputTransform {
+    #[serde(rename = "normal")]
+    Normal,
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+    #[serde(rename = "flipped")]
+    Flipped,
+    #[serde(rename = "flipped-90")]
+    Flipped90,
+    #[serde(rename = "flipped-180")]
+    Flipped180,
+    #[serde(rename = "flipped-270")]
+    Flipped270,
+}
+
+impl From<OutputTransform> for Transform {
+    fn from(transform: OutputTransform) -> Self {
+        match transform {
+            OutputTransform::Normal => Transform::Normal,
+            OutputTransform::Rotate90 => Transform::_90,
+            OutputTransform::Rotate180 => Transform::_180,
+            OutputTransform::Rotate270 => Transform::_270,
+            OutputTransform::Flipped => Transform::Flipped,
+            OutputTransform::Flipped90 => Transform::Flipped90,
+            OutputTransform::Flipped180 => Transform::Flipped180,
+            OutputTransform::Flipped270 => Transform::Flipped270,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn matches(&self, connector: &str, make: &str, model: &str, serial: &str) -> bool {
+        let criteria = [
+            (&self.name, connector),
+            (&self.make, make),
+            (&self.model, model),
+            (&self.serial, serial),
+        ];
+        // An entry without any criteria would match every output, which is never what's intended
+        criteria.iter().any(|(wanted, _)| wanted.is_some())
+            && criteria
+                .iter()
+                .all(|(wanted, value)| wanted.as_ref().map_or(true, |wanted| wanted == value))
+    }
+
+    // The refresh rate in mHz, as used by `smithay::output::Mode`
+    pub fn refresh_mhz(&self) -> Option<i32> {
+        self.mode
+            .and_then(|mode| mode.refresh)
+            .map(|refresh| (refresh * 1000.0).round() as i32)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -96,12 +313,27 @@ pub fn generate_config(user_path: Option<PathBuf>) -> Result<PathBuf, ConfigErro
     let input_config = InputConfig {
         keyboard_layout: "us".to_owned(),
         layout_variant: "".to_owned(),
+        rules: "".to_owned(),
+        model: "".to_owned(),
+        options: None,
+        repeat_delay: default_repeat_delay(),
+        repeat_rate: default_repeat_rate(),
+        wake_outputs: false,
     };
 
     let config = WaysightConfig {
         workspaces: 10,
         input: input_config,
         clear_color: [0.0f32, 1.0f32, 1.0f32, 1.0f32],
+        autostart: AutostartConfig::default(),
+        environment: HashMap::new(),
+        systemd: SystemdConfig::default(),
+        outputs: Vec::new(),
+        drm: DrmConfig::default(),
+        decoration: DecorationConfig::default(),
+        capture: CaptureConfig::default(),
+        focus: FocusConfig::default(),
+        bindings: HashMap::new(),
     };
 
     let config_str = toml::to_string_pretty::<WaysightConfig>(&config).unwrap();
@@ -114,6 +346,19 @@ pub fn generate_config(user_path: Option<PathBuf>) -> Result<PathBuf, ConfigErro
 }
 
 impl WaysightConfig {
+    // Returns the first `[[output]]` entry matching the given output
+    pub fn output_config(
+        &self,
+        connector: &str,
+        make: &str,
+        model: &str,
+        serial: &str,
+    ) -> Option<&OutputConfig> {
+        self.outputs
+            .iter()
+            .find(|output| output.matches(connector, make, model, serial))
+    }
+
     pub fn load_config() -> WaysightConfig {
         let mutex_data = USER_DATA.lock().unwrap();
         match parse(mutex_data.config_path.clone()) {
@@ -146,6 +391,13 @@ impl WaysightConfig {
     }
 }
 
+// Re-reads the config file. Unlike `load_config`, errors are handed back to the caller so a typo
+// while editing the config doesn't bring down a running session
+pub fn reload() -> Result<WaysightConfig, ConfigError> {
+    let config_path = USER_DATA.lock().unwrap().config_path.clone();
+    parse(config_path)
+}
+
 pub fn default_layout() -> String {
     "us".to_owned()
 }
@@ -158,6 +410,76 @@ fn default_variant() -> String {
     "".to_owned()
 }
 
+fn default_repeat_delay() -> i32 {
+    200
+}
+
+fn default_repeat_rate() -> i32 {
+    25
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_border_width() -> i32 {
+    2
+}
+
+fn default_border_color() -> [f32; 4] {
+    [0.3f32, 0.3f32, 0.3f32, 1.0f32]
+}
+
 fn default_cc() -> [f32; 4] {
     [1.0f32, 1.0f32, 1.0f32, 1.0f32]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(entry: &str) -> OutputConfig {
+        toml::from_str(entry).unwrap()
+    }
+
+    #[test]
+    fn outputs_match_by_connector() {
+        let config = output(r#"name = "DP-1""#);
+        assert!(config.matches("DP-1", "Dell", "U2720Q", "ABC123"));
+        assert!(!config.matches("DP-2", "Dell", "U2720Q", "ABC123"));
+    }
+
+    #[test]
+    fn outputs_match_by_make_model_and_serial() {
+        let config = output(
+            r#"
+            make = "Dell"
+            model = "U2720Q"
+            serial = "ABC123"
+            "#,
+        );
+        // Any connector, e.g. after replugging into a dock
+        assert!(config.matches("DP-1", "Dell", "U2720Q", "ABC123"));
+        assert!(config.matches("DP-5", "Dell", "U2720Q", "ABC123"));
+        assert!(!config.matches("DP-1", "Dell", "U2720Q", "XYZ789"));
+        assert!(!config.matches("DP-1", "Dell", "P2419H", "ABC123"));
+        assert!(!config.matches("DP-1", "LG", "U2720Q", "ABC123"));
+
+        // Every criterion given has to match
+        let config = output(
+            r#"
+            name = "DP-1"
+            model = "U2720Q"
+            "#,
+        );
+        assert!(config.matches("DP-1", "Dell", "U2720Q", ""));
+        assert!(!config.matches("DP-1", "Dell", "P2419H", ""));
+        assert!(!config.matches("HDMI-A-1", "Dell", "U2720Q", ""));
+    }
+
+    #[test]
+    fn outputs_without_criteria_match_nothing() {
+        let config = output("scale = 2.0");
+        assert!(!config.matches("DP-1", "Dell", "U2720Q", "ABC123"));
+    }
+}
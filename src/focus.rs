@@ -0,0 +1,180 @@
+// Keyboard focus. Windows are focused when they map, when they are clicked, when they are activated
+// and, with `focus.follows_mouse`, when the pointer enters them. The seat keeps a history of focused
+// windows so focus goes back to the previous window when the focused one closes or is minimized
+use std::{cell::RefCell, time::Duration};
+
+use smithay::{
+    desktop::Window,
+    input::Seat,
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    utils::{Logical, Point, SERIAL_COUNTER},
+};
+
+use crate::state::{Backend, Waysight, CONFIG};
+
+// Focused windows, the most recent last
+#[derive(Default)]
+struct FocusHistory(Vec<Window>);
+
+// Marks windows that were already focused on map
+struct FocusedOnMap;
+
+fn with_history<B: Backend + 'static, T>(
+    seat: &Seat<Waysight<B>>,
+    f: impl FnOnce(&mut Vec<Window>) -> T,
+) -> T {
+    seat.user_data()
+        .insert_if_missing(|| RefCell::new(FocusHistory::default()));
+    let history = seat.user_data().get::<RefCell<FocusHistory>>().unwrap();
+    let mut history = history.borrow_mut();
+    f(&mut history.0)
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Gives `window` keyboard focus without raising it
+    pub fn focus_window(&mut self, window: &Window) {
+        // The locker keeps the keyboard until the session is unlocked
+        if self.session_lock.is_locked() || self.space.element_location(window).is_none() {
+            return;
+        }
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            if !self.is_focused(window) {
+                let surface = window.toplevel().wl_surface().clone();
+                keyboard.set_focus(self, Some(surface), SERIAL_COUNTER.next_serial());
+            }
+        }
+        if CONFIG.read().unwrap().focus.mouse_follows_focus {
+            self.warp_pointer_to(window);
+        }
+    }
+
+    // Focus goes back to the most recently focused window that is still mapped, or nowhere
+    pub fn focus_previous(&mut self) {
+        if self.session_lock.is_locked() {
+            return;
+        }
+        let previous = with_history(&self.seat, |history| {
+            history
+                .iter()
+                .rev()
+                .find(|window| self.space.element_location(window).is_some())
+                .cloned()
+        });
+        match previous {
+            Some(window) => self.focus_window(&window),
+            None => {
+                if let Some(keyboard) = self.seat.get_keyboard() {
+                    keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+                }
+            }
+        }
+    }
+
+    // Called when a window closes, focus moves on if it had it
+    pub fn forget_window(&mut self, window: &Window) {
+        let focused = self.is_focused(window);
+        with_history(&self.seat, |history| {
+            history.retain(|other| other != window)
+        });
+        if focused {
+            self.focus_previous();
+        }
+    }
+
+    // Called by the seat whenever keyboard focus changed, to windows, their popups, lock surfaces
+    // or nothing. The keyboard is locked while this runs, so it must not be used here
+    pub fn keyboard_focus_changed(&mut self, surface: Option<&WlSurface>) {
+        let focused = surface.and_then(|surface| self.window_for_child_surface(surface));
+        // Windows look active while they or one of their popups have focus
+        let windows: Vec<Window> = self
+            .space
+            .elements()
+            .chain(self.minimized_windows.iter().map(|(window, _)| window))
+            .cloned()
+            .collect();
+        for window in windows.iter() {
+            if window.set_activated(Some(window) == focused.as_ref()) {
+                window.toplevel().send_configure();
+            }
+        }
+        if let Some(window) = focused {
+            with_history(&self.seat, |history| {
+                history.retain(|other| other != &window);
+                history.push(window.clone());
+            });
+            self.activation.set_urgent(&window, false);
+        }
+    }
+
+    // New windows are focused once they committed their first buffer
+    pub fn focus_on_map(&mut self, window: &Window) {
+        let size = window.bbox().size;
+        if size.w <= 0 || size.h <= 0 || !window.user_data().insert_if_missing(|| FocusedOnMap) {
+            return;
+        }
+        self.activate_window(window);
+    }
+
+    // Click to focus, clicked windows are raised too
+    pub fn focus_under_pointer(&mut self, location: Point<f64, Logical>) {
+        if self.session_lock.is_locked() {
+            self.focus_lock_surface_under(location);
+            return;
+        }
+        let window = self
+            .space
+            .element_under(location)
+            .map(|(window, _)| window.clone());
+        if let Some(window) = window {
+            self.activate_window(&window);
+        }
+    }
+
+    // Focus follows mouse, without raising. Popup grabs keep their focus
+    pub fn focus_follows_pointer(&mut self, location: Point<f64, Logical>) {
+        if !CONFIG.read().unwrap().focus.follows_mouse || self.session_lock.is_locked() {
+            return;
+        }
+        let grabbed = self
+            .seat
+            .get_keyboard()
+            .map_or(false, |keyboard| keyboard.is_grabbed())
+            || self
+                .seat
+                .get_pointer()
+                .map_or(false, |pointer| pointer.is_grabbed());
+        if grabbed {
+            return;
+        }
+        let window = self
+            .space
+            .element_under(location)
+            .map(|(window, _)| window.clone());
+        if let Some(window) = window {
+            self.focus_window(&window);
+        }
+    }
+
+    // Mouse follows focus, the pointer moves to the center of `window` unless it's already on it
+    fn warp_pointer_to(&mut self, window: &Window) {
+        let geometry = match self.space.element_geometry(window) {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let on_window = self.pointer_location().map_or(false, |location| {
+            self.space
+                .element_under(location)
+                .map_or(false, |(under, _)| under == window)
+        });
+        if on_window {
+            return;
+        }
+        let center: Point<i32, Logical> = (
+            geometry.loc.x + geometry.size.w / 2,
+            geometry.loc.y + geometry.size.h / 2,
+        )
+            .into();
+        let time = Duration::from(self.clock.now()).as_millis() as u32;
+        self.move_pointer(center.to_f64(), time);
+    }
+}
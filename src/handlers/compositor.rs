@@ -12,6 +12,7 @@ use smithay::{
     },
 };
 
+use super::xdg_shell;
 use crate::state::{Backend, ClientState, Waysight};
 
 impl<B: Backend + 'static> CompositorHandler for Waysight<B> {
@@ -28,11 +29,31 @@ impl<B: Backend + 'static> CompositorHandler for Waysight<B> {
 
     fn commit(&mut self, surface: &WlSurface) {
         utils::on_commit_buffer_handler::<Self>(surface);
+        let mut root = surface.clone();
+        while let Some(parent) = compositor::get_parent(&root) {
+            root = parent;
+        }
         if !compositor::is_sync_subsurface(surface) {
-            let mut root = surface.clone();
-            while let Some(parent) = compositor::get_parent(&root) {
-                root = parent;
+            if let Some(window) = self.window_for_surface(&root) {
+                window.on_commit();
+                self.focus_on_map(&window);
+            }
+        }
+        self.popups.commit(surface);
+        xdg_shell::handle_commit(&self.space, &self.popups, surface);
+
+        self.schedule_render_for_surface(&root);
+
+        if let Some(window) = self.window_for_child_surface(&root) {
+            // Captured windows may not be visible anywhere, their captures need a repaint of their
+            // own
+            for output in self.capture_state.window_committed(&window) {
+                self.backend_data.schedule_render(&output);
             }
+            // Titles, app ids and window sizes are double buffered, they change with commits.
+            // Mapping, unmapping and output changes refresh every window
+            self.refresh_foreign_toplevel(&window);
+            self.update_window_capture_sessions(&window);
         }
     }
 }
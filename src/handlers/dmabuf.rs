@@ -0,0 +1,29 @@
+use smithay::{
+    backend::allocator::dmabuf::Dmabuf,
+    delegate_dmabuf,
+    wayland::dmabuf::{DmabufGlobal, DmabufHandler, DmabufState, ImportError},
+};
+
+use crate::state::{Backend, Waysight};
+
+impl<B: Backend + 'static> DmabufHandler for Waysight<B> {
+    fn dmabuf_state(&mut self) -> &mut DmabufState {
+        &mut self.dmabuf_state
+    }
+
+    // Importing right away lets the client know about unusable buffers through a `failed` event
+    // instead of us failing to render them later
+    fn dmabuf_imported(
+        &mut self,
+        _global: &DmabufGlobal,
+        dmabuf: Dmabuf,
+    ) -> Result<(), ImportError> {
+        if self.backend_data.import_dmabuf(&dmabuf) {
+            Ok(())
+        } else {
+            Err(ImportError::Failed)
+        }
+    }
+}
+
+delegate_dmabuf!(@<B: Backend + 'static> Waysight<B>);
@@ -0,0 +1,41 @@
+use smithay::{
+    delegate_fractional_scale,
+    desktop::utils::surface_primary_scanout_output,
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    wayland::{
+        compositor::{get_parent, with_states},
+        fractional_scale::{with_fractional_scale, FractionalScaleHandler},
+    },
+};
+
+use crate::state::{Backend, Waysight};
+
+impl<B: Backend + 'static> FractionalScaleHandler for Waysight<B> {
+    // Sends the scale right away so the client can size its first buffer. Afterwards the scale
+    // follows the output the surface is primarily shown on, see `render::post_repaint`
+    fn new_fractional_scale(&mut self, surface: WlSurface) {
+        let mut root = surface.clone();
+        while let Some(parent) = get_parent(&root) {
+            root = parent;
+        }
+        let output = with_states(&root, |states| {
+            surface_primary_scanout_output(&root, states)
+        })
+        .or_else(|| {
+            self.space
+                .elements()
+                .find(|window| window.toplevel().wl_surface() == &root)
+                .and_then(|window| self.space.outputs_for_element(window).into_iter().next())
+        })
+        .or_else(|| self.space.outputs().next().cloned());
+        if let Some(output) = output {
+            with_states(&surface, |states| {
+                with_fractional_scale(states, |fractional_scale| {
+                    fractional_scale.set_preferred_scale(output.current_scale().fractional_scale());
+                });
+            });
+        }
+    }
+}
+
+delegate_fractional_scale!(@<B: Backend + 'static> Waysight<B>);
@@ -1,31 +1,313 @@
 use smithay::{
     backend::{
-        input::{Device, DeviceCapability, Event, InputEvent, KeyState, KeyboardKeyEvent},
+        input::{
+            AbsolutePositionEvent, ButtonState, Device, DeviceCapability, Event, InputBackend,
+            InputEvent, KeyState, KeyboardKeyEvent, PointerButtonEvent, PointerMotionEvent,
+        },
         libinput::LibinputInputBackend,
         winit::WinitInput,
     },
-    input::keyboard::{keysyms, FilterResult, KeysymHandle, ModifiersState, XkbConfig},
-    utils::SERIAL_COUNTER,
+    desktop::WindowSurfaceType,
+    input::{
+        keyboard::{keysyms, xkb, FilterResult, KeysymHandle, ModifiersState, XkbConfig},
+        pointer::{ButtonEvent, MotionEvent},
+    },
+    output::Output,
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    utils::{Logical, Point, SERIAL_COUNTER},
 };
 
 use crate::{
     backend::{drm::DrmBackend, winit::WinitBackend},
-    state::{Waysight, CONFIG},
+    config::InputConfig,
+    state::{Backend, Waysight, CONFIG},
+    utils::process,
 };
 
+// Whether `keys`, e.g. "Super+Shift+s", is the pressed combination. Modifiers have to match
+// exactly, the key is compared without the effect of the modifiers
+fn binding_matches(keys: &str, modifiers: &ModifiersState, keysym: &KeysymHandle<'_>) -> bool {
+    let (mut ctrl, mut alt, mut shift, mut logo) = (false, false, false, false);
+    let mut key = None;
+    for part in keys.split('+').map(str::trim) {
+        match part.to_lowercase().as_str() {
+            "ctrl" | "control" => ctrl = true,
+            "alt" => alt = true,
+            "shift" => shift = true,
+            "super" | "logo" | "mod4" => logo = true,
+            _ => key = Some(xkb::keysym_from_name(part, xkb::KEYSYM_CASE_INSENSITIVE)),
+        }
+    }
+    let key = match key {
+        Some(key) if key != keysyms::KEY_NoSymbol => key,
+        _ => return false,
+    };
+    (ctrl, alt, shift, logo)
+        == (
+            modifiers.ctrl,
+            modifiers.alt,
+            modifiers.shift,
+            modifiers.logo,
+        )
+        && (keysym.raw_syms().contains(&key) || keysym.modified_sym() == key)
+}
+
+fn xkb_config(config: &InputConfig) -> XkbConfig<'_> {
+    XkbConfig {
+        rules: &config.rules,
+        model: &config.model,
+        layout: &config.keyboard_layout,
+        variant: &config.layout_variant,
+        options: config.options.clone(),
+    }
+}
+
+// Input from the user, as opposed to devices coming and going
+fn is_activity<I: InputBackend>(event: &InputEvent<I>) -> bool {
+    !matches!(
+        event,
+        InputEvent::DeviceAdded { .. } | InputEvent::DeviceRemoved { .. }
+    )
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // A keymap that doesn't compile, e.g. because of a typo in the options, falls back to the
+    // default one instead of leaving the seat without a keyboard
+    fn add_keyboard(&mut self) {
+        let config = CONFIG.read().unwrap();
+        let input = &config.input;
+        let result =
+            self.seat
+                .add_keyboard(xkb_config(input), input.repeat_delay, input.repeat_rate);
+        if let Err(err) = result {
+            tracing::error!(
+                "Invalid keymap in the config, using the default one: {}",
+                err
+            );
+            self.seat
+                .add_keyboard(XkbConfig::default(), input.repeat_delay, input.repeat_rate)
+                .expect("Failure adding keyboard");
+        }
+    }
+
+    // Applies the keymap and repeat info to the keyboard after the config was reloaded. An
+    // invalid keymap leaves the current one in place
+    pub fn apply_keyboard_config(&mut self) {
+        let keyboard = match self.seat.get_keyboard() {
+            Some(keyboard) => keyboard,
+            None => return,
+        };
+        let config = CONFIG.read().unwrap();
+        let input = &config.input;
+        keyboard.change_repeat_info(input.repeat_rate, input.repeat_delay);
+        if let Err(err) = keyboard.set_xkb_config(self, xkb_config(input)) {
+            tracing::error!(
+                "Invalid keymap in the config, keeping the current one: {}",
+                err
+            );
+        }
+    }
+
+    fn on_activity(&mut self) {
+        self.notify_activity();
+        if CONFIG.read().unwrap().input.wake_outputs {
+            self.wake_outputs();
+        }
+    }
+
+    // Runs the ipc command bound to the pressed keys. Keys that triggered a binding are hidden
+    // from clients, their release included
+    fn intercept_binding(
+        &mut self,
+        key_code: u32,
+        key_state: KeyState,
+        modifiers: &ModifiersState,
+        keysym: &KeysymHandle<'_>,
+    ) -> bool {
+        if key_state == KeyState::Released {
+            let suppressed = self.suppressed_keys.contains(&key_code);
+            self.suppressed_keys.retain(|key| *key != key_code);
+            return suppressed;
+        }
+        // Everything goes to the locker while the session is locked
+        if self.session_lock.is_locked() {
+            return false;
+        }
+        let command = CONFIG
+            .read()
+            .unwrap()
+            .bindings
+            .iter()
+            .find(|(keys, _)| binding_matches(keys, modifiers, keysym))
+            .map(|(_, command)| command.clone());
+        let command = match command {
+            Some(command) => command,
+            None => return false,
+        };
+        self.suppressed_keys.push(key_code);
+        let reply = self.handle_ipc_command(&command);
+        if let Some(err) = reply.get("error") {
+            tracing::warn!("Binding `{}` failed: {}", command, err);
+        }
+        true
+    }
+
+    fn on_pointer_motion<I: InputBackend>(&mut self, event: I::PointerMotionEvent) {
+        let location = match self.pointer_location() {
+            Some(location) => location + event.delta(),
+            None => return,
+        };
+        self.move_pointer(location, event.time_msec());
+    }
+
+    // Absolute devices (tablets, the pointer of most vms, the winit window) span the first output
+    fn on_pointer_motion_absolute<I: InputBackend>(
+        &mut self,
+        event: I::PointerMotionAbsoluteEvent,
+    ) {
+        let geometry = match self
+            .space
+            .outputs()
+            .next()
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let location = event.position_transformed(geometry.size) + geometry.loc.to_f64();
+        self.move_pointer(location, event.time_msec());
+    }
+
+    // Moves the pointer, kept inside the output layout, and repaints the outputs the cursor left
+    // and entered. While the cursor is on its own plane these frames only move the plane
+    pub fn move_pointer(&mut self, location: Point<f64, Logical>, time: u32) {
+        let pointer = match self.seat.get_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+        let previous = pointer.current_location();
+        let location = self.clamp_to_outputs(location, previous);
+        let focus = self.surface_under(location);
+        pointer.motion(
+            self,
+            focus,
+            &MotionEvent {
+                location,
+                serial: SERIAL_COUNTER.next_serial(),
+                time,
+            },
+        );
+        self.focus_follows_pointer(location);
+
+        let outputs: Vec<Output> = self
+            .space
+            .output_under(previous)
+            .chain(self.space.output_under(location))
+            .cloned()
+            .collect();
+        for output in outputs.iter() {
+            self.backend_data.schedule_render(output);
+        }
+    }
+
+    // Presses focus and raise the clicked window before the client gets the button
+    fn on_pointer_button<I: InputBackend>(&mut self, event: I::PointerButtonEvent) {
+        let pointer = match self.seat.get_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+        let state = event.state();
+        if state == ButtonState::Pressed && !pointer.is_grabbed() {
+            self.focus_under_pointer(pointer.current_location());
+        }
+        let serial = SERIAL_COUNTER.next_serial();
+        if state == ButtonState::Pressed {
+            self.record_input_serial(serial);
+        }
+        pointer.button(
+            self,
+            &ButtonEvent {
+                button: event.button_code(),
+                state,
+                serial,
+                time: event.time_msec(),
+            },
+        );
+    }
+
+    // Locations outside of every output are clamped to the output the pointer was on
+    fn clamp_to_outputs(
+        &self,
+        location: Point<f64, Logical>,
+        previous: Point<f64, Logical>,
+    ) -> Point<f64, Logical> {
+        if self.space.output_under(location).next().is_some() {
+            return location;
+        }
+        let geometry = match self
+            .space
+            .output_under(previous)
+            .next()
+            .or_else(|| self.space.outputs().next())
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry.to_f64(),
+            None => return location,
+        };
+        let max_x = geometry.loc.x + (geometry.size.w - 1.0).max(0.0);
+        let max_y = geometry.loc.y + (geometry.size.h - 1.0).max(0.0);
+        (
+            location.x.clamp(geometry.loc.x, max_x),
+            location.y.clamp(geometry.loc.y, max_y),
+        )
+            .into()
+    }
+
+    // The surface under `location` and its location in global coordinates. While the session is
+    // locked only lock surfaces get input
+    pub fn surface_under(
+        &self,
+        location: Point<f64, Logical>,
+    ) -> Option<(WlSurface, Point<i32, Logical>)> {
+        if self.session_lock.is_locked() {
+            return self.lock_surface_under(location);
+        }
+        let (window, window_location) = self.space.element_under(location)?;
+        window
+            .surface_under(location - window_location.to_f64(), WindowSurfaceType::ALL)
+            .map(|(surface, offset)| (surface, offset + window_location))
+    }
+}
+
 impl Waysight<DrmBackend> {
+    // Built-in bindings, like `intercept_binding` the keys and their release are hidden from clients
+    fn on_keyboard_input(
+        &mut self,
+        key_code: u32,
+        modifier_state: &ModifiersState,
+        keysym: KeysymHandle<'_>,
+    ) -> FilterResult<()> {
+        let sym = keysym.modified_sym();
+        // xkb turns Ctrl+Alt+Fn into the XF86Switch_VT_n keysyms
+        if (keysyms::KEY_XF86Switch_VT_1..=keysyms::KEY_XF86Switch_VT_12).contains(&sym) {
+            let vt = (sym - keysyms::KEY_XF86Switch_VT_1 + 1) as i32;
+            self.backend_data.change_vt(vt);
+        } else if modifier_state.alt && sym == keysyms::KEY_z && !self.session_lock.is_locked() {
+            self.stop();
+        } else {
+            return FilterResult::Forward;
+        }
+        self.suppressed_keys.push(key_code);
+        FilterResult::Intercept(())
+    }
     pub fn parse_input_event(&mut self, event: InputEvent<LibinputInputBackend>) {
+        if is_activity(&event) {
+            self.on_activity();
+        }
         match event {
             InputEvent::DeviceAdded { device } => {
                 if Device::has_capability(&device, DeviceCapability::Keyboard) {
-                    let xkb_config = XkbConfig {
-                        layout: &CONFIG.input.keyboard_layout,
-                        variant: &CONFIG.input.layout_variant,
-                        ..XkbConfig::default()
-                    };
-                    self.seat
-                        .add_keyboard(xkb_config, 200, 25)
-                        .expect("Failure adding keyboard");
+                    self.add_keyboard();
                 }
             }
             InputEvent::Keyboard { event } => {
@@ -34,50 +316,70 @@ impl Waysight<DrmBackend> {
                     None => return,
                 };
 
+                let (key_code, key_state) = (event.key_code(), event.state());
+                let serial = SERIAL_COUNTER.next_serial();
+                if key_state == KeyState::Pressed {
+                    self.record_input_serial(serial);
+                }
                 keyboard.input(
                     self,
-                    event.key_code(),
-                    KeyState::Pressed,
-                    SERIAL_COUNTER.next_serial(),
+                    key_code,
+                    key_state,
+                    serial,
                     Event::time_msec(&event),
-                    |state, modifier_state, key_code| {
-                        if modifier_state.alt && key_code.modified_sym() == keysyms::KEY_z {
-                            state.loop_signal.stop();
+                    |state, modifier_state, keysym| {
+                        if state.intercept_binding(key_code, key_state, modifier_state, &keysym) {
+                            return FilterResult::Intercept(());
+                        }
+                        if key_state == KeyState::Pressed {
+                            return state.on_keyboard_input(key_code, modifier_state, keysym);
                         }
-                        FilterResult::<i32>::Forward
+                        FilterResult::Forward
                     },
                 );
             }
+            InputEvent::PointerMotion { event } => {
+                self.on_pointer_motion::<LibinputInputBackend>(event)
+            }
+            InputEvent::PointerMotionAbsolute { event } => {
+                self.on_pointer_motion_absolute::<LibinputInputBackend>(event)
+            }
+            InputEvent::PointerButton { event } => {
+                self.on_pointer_button::<LibinputInputBackend>(event)
+            }
             _ => {}
         }
     }
 }
 
 impl Waysight<WinitBackend> {
-    fn on_keyboard_input<T>(
+    fn on_keyboard_input(
         &mut self,
+        key_code: u32,
         modifier_state: &ModifiersState,
         keysym: KeysymHandle<'_>,
-    ) -> FilterResult<T> {
+    ) -> FilterResult<()> {
+        if self.session_lock.is_locked() {
+            return FilterResult::Forward;
+        }
         if modifier_state.alt && keysym.modified_sym() == keysyms::KEY_z {
-            self.loop_signal.stop();
+            self.stop();
         } else if modifier_state.logo && keysym.modified_sym() == keysyms::KEY_Return {
-            std::process::Command::new("kitty").spawn().unwrap();
+            process::spawn("kitty", &self.socket_name);
+        } else {
+            return FilterResult::Forward;
         }
-        FilterResult::Forward
+        self.suppressed_keys.push(key_code);
+        FilterResult::Intercept(())
     }
     pub fn parse_input_event_winit(&mut self, event: InputEvent<WinitInput>) {
+        if is_activity(&event) {
+            self.on_activity();
+        }
         match event {
             InputEvent::DeviceAdded { device } => {
                 if Device::has_capability(&device, DeviceCapability::Keyboard) {
-                    let xkb_config = XkbConfig {
-                        layout: &CONFIG.input.keyboard_layout,
-                        variant: &CONFIG.input.layout_variant,
-                        ..XkbConfig::default()
-                    };
-                    self.seat
-                        .add_keyboard(xkb_config, 200, 25)
-                        .expect("Failure adding keyboard");
+                    self.add_keyboard();
                 }
             }
             InputEvent::Keyboard { event } => {
@@ -86,20 +388,33 @@ impl Waysight<WinitBackend> {
                     None => return,
                 };
 
+                let (key_code, key_state) = (event.key_code(), event.state());
+                let serial = SERIAL_COUNTER.next_serial();
+                if key_state == KeyState::Pressed {
+                    self.record_input_serial(serial);
+                }
                 keyboard.input(
                     self,
-                    event.key_code(),
-                    event.state(),
-                    SERIAL_COUNTER.next_serial(),
+                    key_code,
+                    key_state,
+                    serial,
                     Event::time_msec(&event),
                     |state, modifier_state, keysym| {
-                        if event.state() == KeyState::Pressed {
-                            return state.on_keyboard_input::<i32>(modifier_state, keysym);
+                        if state.intercept_binding(key_code, key_state, modifier_state, &keysym) {
+                            return FilterResult::Intercept(());
+                        }
+                        if key_state == KeyState::Pressed {
+                            return state.on_keyboard_input(key_code, modifier_state, keysym);
                         }
                         FilterResult::Forward
                     },
                 );
             }
+            InputEvent::PointerMotion { event } => self.on_pointer_motion::<WinitInput>(event),
+            InputEvent::PointerMotionAbsolute { event } => {
+                self.on_pointer_motion_absolute::<WinitInput>(event)
+            }
+            InputEvent::PointerButton { event } => self.on_pointer_button::<WinitInput>(event),
             _ => {}
         }
     }
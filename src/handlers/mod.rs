@@ -1,3 +1,8 @@
 pub mod compositor;
+pub mod dmabuf;
+pub mod fractional_scale;
 pub mod input;
 pub mod seat;
+pub mod session_lock;
+pub mod xdg_activation;
+pub mod xdg_shell;
@@ -2,7 +2,6 @@ use smithay::{
     delegate_seat,
     input::{pointer::CursorImageStatus, Seat, SeatHandler},
     reexports::wayland_server::protocol::wl_surface::WlSurface,
-    utils::SERIAL_COUNTER,
 };
 
 use crate::state::{Backend, Waysight};
@@ -17,13 +16,10 @@ impl<B: Backend + 'static> SeatHandler for Waysight<B> {
 
     fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
         *self.cursor_image_status.lock().unwrap() = image;
+        self.schedule_render_for_pointer();
     }
-    fn focus_changed(&mut self, seat: &Seat<Self>, focused: Option<&Self::KeyboardFocus>) {
-        if let Some(target) = focused {
-            if let Some(kb) = seat.get_keyboard() {
-                kb.set_focus(self, Some(target.clone()), SERIAL_COUNTER.next_serial())
-            }
-        }
+    fn focus_changed(&mut self, _seat: &Seat<Self>, focused: Option<&Self::KeyboardFocus>) {
+        self.keyboard_focus_changed(focused);
     }
 }
 
@@ -0,0 +1,242 @@
+// ext-session-lock-v1 for screen lockers. While locked only the lock surfaces are drawn and get
+// input. A locker that crashes leaves the session locked with blank outputs, a new locker can take
+// over from it
+use std::{mem, time::Duration};
+
+use smithay::{
+    delegate_session_lock,
+    desktop::{
+        utils::{send_frames_surface_tree, under_from_surface_tree},
+        WindowSurfaceType,
+    },
+    output::Output,
+    reexports::{
+        wayland_protocols::ext::session_lock::v1::server::ext_session_lock_v1::ExtSessionLockV1,
+        wayland_server::{
+            protocol::{wl_output::WlOutput, wl_surface::WlSurface},
+            DisplayHandle,
+        },
+    },
+    utils::{IsAlive, Logical, Point, Size, SERIAL_COUNTER},
+    wayland::session_lock::{
+        LockSurface, SessionLockHandler, SessionLockManagerState, SessionLocker,
+    },
+};
+
+use crate::state::{Backend, Waysight};
+
+enum LockState {
+    Unlocked,
+    // Outputs still showing the desktop, the lock is confirmed once all were repainted
+    Locking(SessionLocker, Vec<Output>),
+    Locked(ExtSessionLockV1),
+}
+
+pub struct SessionLock {
+    manager_state: SessionLockManagerState,
+    state: LockState,
+    surfaces: Vec<(Output, LockSurface)>,
+    // Keyboard focus from before the lock, given back after unlocking
+    previous_focus: Option<WlSurface>,
+}
+
+impl SessionLock {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        SessionLock {
+            manager_state: SessionLockManagerState::new::<Waysight<B>, _>(display_handle, |_| true),
+            state: LockState::Unlocked,
+            surfaces: Vec::new(),
+            previous_focus: None,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        !matches!(self.state, LockState::Unlocked)
+    }
+
+    // Whether the client holding the lock is still around
+    fn locker_alive(&self) -> bool {
+        match &self.state {
+            LockState::Unlocked => false,
+            LockState::Locking(locker, _) => locker.ext_session_lock().alive(),
+            LockState::Locked(lock) => lock.alive(),
+        }
+    }
+
+    pub fn surface(&self, output: &Output) -> Option<&WlSurface> {
+        self.surfaces
+            .iter()
+            .find(|(surface_output, surface)| {
+                surface_output == output && surface.wl_surface().alive()
+            })
+            .map(|(_, surface)| surface.wl_surface())
+    }
+
+    // Called by the backends once `output` was repainted
+    pub fn post_repaint(&mut self, output: &Output, time: Duration) {
+        if let Some(surface) = self.surface(output) {
+            send_frames_surface_tree(surface, output, time, None, |_, _| Some(output.clone()));
+        }
+        if let LockState::Locking(_, pending) = &mut self.state {
+            pending.retain(|pending| pending != output);
+        }
+        self.confirm_if_shown();
+    }
+
+    // Forgets outputs that went away, the lock doesn't wait for them
+    pub fn retain_outputs(&mut self, outputs: &[Output]) {
+        self.surfaces.retain(|(output, _)| outputs.contains(output));
+        if let LockState::Locking(_, pending) = &mut self.state {
+            pending.retain(|pending| outputs.contains(pending));
+        }
+        self.confirm_if_shown();
+    }
+
+    // Tells the locker the session is locked once no output shows the desktop anymore
+    fn confirm_if_shown(&mut self) {
+        if !matches!(&self.state, LockState::Locking(_, pending) if pending.is_empty()) {
+            return;
+        }
+        if let LockState::Locking(locker, _) = mem::replace(&mut self.state, LockState::Unlocked) {
+            let lock = locker.ext_session_lock().clone();
+            locker.lock();
+            self.state = LockState::Locked(lock);
+            tracing::info!("Session locked");
+        }
+    }
+}
+
+fn configure_lock_surface(surface: &LockSurface, size: Size<i32, Logical>) {
+    surface.with_pending_state(|state| {
+        state.size = Some((size.w as u32, size.h as u32).into());
+    });
+    surface.send_configure();
+}
+
+impl<B: Backend + 'static> SessionLockHandler for Waysight<B> {
+    fn lock_state(&mut self) -> &mut SessionLockManagerState {
+        &mut self.session_lock.manager_state
+    }
+
+    fn lock(&mut self, confirmation: SessionLocker) {
+        // Dropping the locker tells the client the session is already locked by someone else
+        if self.session_lock.locker_alive() {
+            tracing::warn!("Refusing a second session lock");
+            return;
+        }
+        let session_lock = &mut self.session_lock;
+        session_lock.surfaces.clear();
+        if session_lock.is_locked() {
+            // Taking over from a crashed locker, the outputs are already blank
+            let lock = confirmation.ext_session_lock().clone();
+            confirmation.lock();
+            session_lock.state = LockState::Locked(lock);
+            tracing::info!("Session lock taken over by a new locker");
+            return;
+        }
+
+        let outputs = self.space.outputs().cloned().collect();
+        session_lock.state = LockState::Locking(confirmation, outputs);
+        session_lock.confirm_if_shown();
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            session_lock.previous_focus = keyboard.current_focus();
+            // A menu grabbing the keyboard would keep getting keys under the lock
+            keyboard.unset_grab();
+            keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+        }
+        self.schedule_render_all();
+    }
+
+    fn unlock(&mut self) {
+        self.session_lock.state = LockState::Unlocked;
+        self.session_lock.surfaces.clear();
+        tracing::info!("Session unlocked");
+        let focus = self
+            .session_lock
+            .previous_focus
+            .take()
+            .filter(|surface| surface.alive());
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            keyboard.set_focus(self, focus, SERIAL_COUNTER.next_serial());
+        }
+        self.schedule_render_all();
+    }
+
+    fn new_surface(&mut self, surface: LockSurface, wl_output: WlOutput) {
+        let output = match Output::from_resource(&wl_output) {
+            Some(output) => output,
+            None => return,
+        };
+        let geometry = match self.space.output_geometry(&output) {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        configure_lock_surface(&surface, geometry.size);
+
+        // The first lock surface gets the keyboard, later ones only if the pointer is on them
+        let pointer_on_output = self
+            .pointer_location()
+            .map_or(false, |location| geometry.to_f64().contains(location));
+        let wl_surface = surface.wl_surface().clone();
+        self.session_lock
+            .surfaces
+            .retain(|(surface_output, _)| surface_output != &output);
+        self.session_lock.surfaces.push((output.clone(), surface));
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            let other_focused = keyboard
+                .current_focus()
+                .map_or(false, |focus| focus.alive() && focus != wl_surface);
+            if !other_focused || pointer_on_output {
+                keyboard.set_focus(self, Some(wl_surface), SERIAL_COUNTER.next_serial());
+            }
+        }
+        self.backend_data.schedule_render(&output);
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // The lock surface under `location` and its location in global coordinates
+    pub fn lock_surface_under(
+        &self,
+        location: Point<f64, Logical>,
+    ) -> Option<(WlSurface, Point<i32, Logical>)> {
+        let output = self.space.output_under(location).next()?;
+        let geometry = self.space.output_geometry(output)?;
+        let surface = self.session_lock.surface(output)?;
+        under_from_surface_tree(
+            surface,
+            location - geometry.loc.to_f64(),
+            (0, 0),
+            WindowSurfaceType::ALL,
+        )
+        .map(|(surface, offset)| (surface, offset + geometry.loc))
+    }
+
+    // Lockers with a surface on every output take the keyboard on the output that is clicked
+    pub fn focus_lock_surface_under(&mut self, location: Point<f64, Logical>) {
+        let surface = self
+            .space
+            .output_under(location)
+            .next()
+            .and_then(|output| self.session_lock.surface(output))
+            .cloned();
+        if let (Some(surface), Some(keyboard)) = (surface, self.seat.get_keyboard()) {
+            if keyboard.current_focus().as_ref() != Some(&surface) {
+                keyboard.set_focus(self, Some(surface), SERIAL_COUNTER.next_serial());
+            }
+        }
+    }
+
+    // Lock surfaces always cover their whole output
+    pub fn configure_lock_surfaces(&mut self) {
+        let outputs: Vec<Output> = self.space.outputs().cloned().collect();
+        self.session_lock.retain_outputs(&outputs);
+        for (output, surface) in self.session_lock.surfaces.iter() {
+            if let Some(geometry) = self.space.output_geometry(output) {
+                configure_lock_surface(surface, geometry.size);
+            }
+        }
+    }
+}
+
+delegate_session_lock!(@<B: Backend + 'static> Waysight<B>);
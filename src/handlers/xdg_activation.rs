@@ -0,0 +1,143 @@
+// xdg-activation-v1, how launchers hand focus to the windows they start. Tokens only grant focus
+// if they were requested by the focused client with the serial of input it got, other activation
+// requests mark the window urgent instead of stealing focus
+use std::time::{Duration, Instant};
+
+use smithay::{
+    delegate_xdg_activation,
+    desktop::Window,
+    input::Seat,
+    reexports::wayland_server::{
+        backend::ClientId, protocol::wl_surface::WlSurface, DisplayHandle, Resource,
+    },
+    utils::Serial,
+    wayland::xdg_activation::{
+        XdgActivationHandler, XdgActivationState, XdgActivationToken, XdgActivationTokenData,
+    },
+};
+
+use crate::state::{Backend, Waysight};
+
+// Slow applications take a while to map their window, after this the user moved on
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct Activation {
+    manager_state: XdgActivationState,
+    // Tokens that may grant focus and when they were created
+    valid_tokens: Vec<(XdgActivationToken, Instant)>,
+    // The focused client and the serial of the first input it got since it was focused
+    input_serial: Option<(ClientId, Serial)>,
+    // Windows that asked for attention, until they are activated
+    urgent: Vec<Window>,
+}
+
+impl Activation {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        Activation {
+            manager_state: XdgActivationState::new::<Waysight<B>>(display_handle),
+            valid_tokens: Vec::new(),
+            input_serial: None,
+            urgent: Vec::new(),
+        }
+    }
+
+    pub fn is_urgent(&self, window: &Window) -> bool {
+        self.urgent.contains(window)
+    }
+
+    pub fn set_urgent(&mut self, window: &Window, urgent: bool) {
+        if !urgent {
+            self.urgent.retain(|other| other != window);
+        } else if !self.is_urgent(window) {
+            self.urgent.push(window.clone());
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Called with the serial of every key press and button press sent to the focused client
+    pub fn record_input_serial(&mut self, serial: Serial) {
+        let client = self.focused_client();
+        let activation = &mut self.activation;
+        activation.input_serial = match (client, activation.input_serial.take()) {
+            (Some(client), Some((focused, first))) if client == focused => Some((focused, first)),
+            (Some(client), _) => Some((client, serial)),
+            (None, _) => None,
+        };
+    }
+
+    fn focused_client(&self) -> Option<ClientId> {
+        self.seat
+            .get_keyboard()
+            .and_then(|keyboard| keyboard.current_focus())
+            .and_then(|surface| surface.client())
+            .map(|client| client.id())
+    }
+
+    // Whether a token was requested by the focused client for input it got while focused
+    fn token_grants_focus(&self, data: &XdgActivationTokenData) -> bool {
+        let (serial, wl_seat) = match &data.serial {
+            Some(serial) => serial,
+            None => return false,
+        };
+        if Seat::<Self>::from_resource(wl_seat).as_ref() != Some(&self.seat) {
+            return false;
+        }
+        match (&self.activation.input_serial, &data.client_id) {
+            (Some((focused, first)), Some(client)) => {
+                focused == client
+                    && self.focused_client().as_ref() == Some(client)
+                    && serial.is_no_older_than(first)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<B: Backend + 'static> XdgActivationHandler for Waysight<B> {
+    fn activation_state(&mut self) -> &mut XdgActivationState {
+        &mut self.activation.manager_state
+    }
+
+    // Every token is handed out, the ones that can't grant focus still let windows ask for
+    // attention
+    fn token_created(&mut self, token: XdgActivationToken, data: XdgActivationTokenData) -> bool {
+        self.activation
+            .valid_tokens
+            .retain(|(_, created)| created.elapsed() < TOKEN_TIMEOUT);
+        if self.token_grants_focus(&data) {
+            self.activation.valid_tokens.push((token, Instant::now()));
+        }
+        true
+    }
+
+    fn request_activation(
+        &mut self,
+        token: XdgActivationToken,
+        _token_data: XdgActivationTokenData,
+        surface: WlSurface,
+    ) {
+        let valid = self
+            .activation
+            .valid_tokens
+            .iter()
+            .any(|(valid, created)| valid == &token && created.elapsed() < TOKEN_TIMEOUT);
+        // Tokens are single use
+        self.activation
+            .valid_tokens
+            .retain(|(valid, _)| valid != &token);
+        self.activation.manager_state.remove_request(&token);
+
+        let window = match self.window_for_surface(&surface) {
+            Some(window) => window,
+            None => return,
+        };
+        if valid && !self.session_lock.is_locked() {
+            self.activate_window(&window);
+        } else if !self.is_focused(&window) {
+            self.activation.set_urgent(&window, true);
+        }
+    }
+}
+
+delegate_xdg_activation!(@<B: Backend + 'static> Waysight<B>);
@@ -0,0 +1,529 @@
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use smithay::{
+    delegate_xdg_shell,
+    desktop::{
+        find_popup_root_surface, PopupKeyboardGrab, PopupKind, PopupManager, PopupPointerGrab,
+        PopupUngrabStrategy, Space, Window,
+    },
+    input::{pointer::Focus, Seat},
+    output::Output,
+    reexports::{
+        wayland_protocols::xdg::shell::server::xdg_toplevel,
+        wayland_server::protocol::{wl_output::WlOutput, wl_seat::WlSeat, wl_surface::WlSurface},
+    },
+    utils::{Logical, Point, Rectangle, Serial},
+    wayland::{
+        compositor::{self, with_states},
+        shell::xdg::{
+            PopupSurface, PositionerState, ToplevelSurface, XdgPopupSurfaceData, XdgShellHandler,
+            XdgShellState, XdgToplevelSurfaceData,
+        },
+    },
+};
+
+use crate::state::{Backend, Waysight};
+
+impl<B: Backend + 'static> XdgShellHandler for Waysight<B> {
+    fn xdg_shell_state(&mut self) -> &mut XdgShellState {
+        &mut self.xdg_shell_state
+    }
+
+    fn new_toplevel(&mut self, surface: ToplevelSurface) {
+        // New windows open in the top left corner of the first output until there is a layout
+        let location = self
+            .space
+            .outputs()
+            .next()
+            .and_then(|output| self.space.output_geometry(output))
+            .map(|geometry| geometry.loc)
+            .unwrap_or_default();
+        let window = Window::new(surface);
+        self.space.map_element(window, location, true);
+        self.refresh_foreign_toplevels();
+    }
+
+    fn new_popup(&mut self, surface: PopupSurface, _positioner: PositionerState) {
+        if let Err(err) = self.popups.track_popup(PopupKind::Xdg(surface)) {
+            tracing::warn!("Failed to track popup: {}", err);
+        }
+    }
+
+    fn toplevel_destroyed(&mut self, surface: ToplevelSurface) {
+        self.minimized_windows
+            .retain(|(window, _)| window.toplevel() != &surface);
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.activation.set_urgent(&window, false);
+            let outputs = self.space.outputs_for_element(&window);
+            self.space.unmap_element(&window);
+            self.forget_window(&window);
+            for output in outputs.iter() {
+                self.backend_data.schedule_render(output);
+            }
+        }
+        self.refresh_foreign_toplevels();
+        self.update_capture_sessions();
+    }
+
+    // Menus grab the keyboard and pointer until they are dismissed. Grabs are only granted in
+    // response to a click or key press that is still going on
+    fn grab(&mut self, surface: PopupSurface, seat: WlSeat, serial: Serial) {
+        if self.session_lock.is_locked() {
+            return;
+        }
+        let seat = match Seat::<Self>::from_resource(&seat) {
+            Some(seat) => seat,
+            None => return,
+        };
+        let popup = PopupKind::Xdg(surface);
+        let root = match find_popup_root_surface(&popup) {
+            Ok(root) if self.window_for_surface(&root).is_some() => root,
+            _ => return,
+        };
+        let mut grab = match self.popups.grab_popup(root, popup, &seat, serial) {
+            Ok(grab) => grab,
+            Err(err) => {
+                tracing::debug!("Denied popup grab: {:?}", err);
+                return;
+            }
+        };
+        let previous_serial = grab.previous_serial().unwrap_or(serial);
+        if let Some(keyboard) = seat.get_keyboard() {
+            if keyboard.is_grabbed()
+                && !(keyboard.has_grab(serial) || keyboard.has_grab(previous_serial))
+            {
+                grab.ungrab(PopupUngrabStrategy::All);
+                return;
+            }
+            keyboard.set_focus(self, grab.current_grab(), serial);
+            keyboard.set_grab(PopupKeyboardGrab::new(&grab), serial);
+        }
+        if let Some(pointer) = seat.get_pointer() {
+            if pointer.is_grabbed()
+                && !(pointer.has_grab(serial) || pointer.has_grab(previous_serial))
+            {
+                grab.ungrab(PopupUngrabStrategy::All);
+                return;
+            }
+            pointer.set_grab(self, PopupPointerGrab::new(&grab), serial, Focus::Keep);
+        }
+    }
+
+    fn fullscreen_request(&mut self, surface: ToplevelSurface, wl_output: Option<WlOutput>) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.fullscreen_window(&window, wl_output);
+        }
+    }
+
+    fn unfullscreen_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.unfullscreen_window(&window);
+        }
+    }
+
+    fn maximize_request(&mut self, surface: ToplevelSurface) {
+        match self.window_for_toplevel(&surface) {
+            Some(window) => self.maximize_window(&window),
+            // The protocol wants a configure in reply either way
+            None => surface.send_configure(),
+        }
+    }
+
+    fn unmaximize_request(&mut self, surface: ToplevelSurface) {
+        match self.window_for_toplevel(&surface) {
+            Some(window) => self.unmaximize_window(&window),
+            None => surface.send_configure(),
+        }
+    }
+
+    fn minimize_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.minimize_window(&window);
+        }
+    }
+}
+
+// Where a fullscreen or maximized window goes back to when it leaves that state
+#[derive(Default)]
+struct RestoreLocation(Option<Point<i32, Logical>>);
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Mapped and minimized windows
+    fn window_for_toplevel(&self, surface: &ToplevelSurface) -> Option<Window> {
+        self.space
+            .elements()
+            .chain(self.minimized_windows.iter().map(|(window, _)| window))
+            .find(|window| window.toplevel() == surface)
+            .cloned()
+    }
+
+    // The window `surface` is the toplevel surface of, mapped or minimized
+    pub fn window_for_surface(&self, surface: &WlSurface) -> Option<Window> {
+        self.space
+            .elements()
+            .chain(self.minimized_windows.iter().map(|(window, _)| window))
+            .find(|window| window.toplevel().wl_surface() == surface)
+            .cloned()
+    }
+
+    // The window a surface belongs to, which may be a subsurface or popup of it
+    pub fn window_for_child_surface(&self, surface: &WlSurface) -> Option<Window> {
+        let mut root = surface.clone();
+        while let Some(parent) = compositor::get_parent(&root) {
+            root = parent;
+        }
+        if let Some(popup) = self.popups.find_popup(&root) {
+            root = find_popup_root_surface(&popup).ok()?;
+        }
+        self.window_for_surface(&root)
+    }
+
+    fn save_restore_location(&self, window: &Window) {
+        if let Some(location) = self.space.element_location(window) {
+            window
+                .user_data()
+                .insert_if_missing(|| Mutex::new(RestoreLocation::default()));
+            let restore = window.user_data().get::<Mutex<RestoreLocation>>().unwrap();
+            // Keep the location from before the first request, clients may ask repeatedly
+            let mut restore = restore.lock().unwrap();
+            if restore.0.is_none() {
+                restore.0 = Some(location);
+            }
+        }
+    }
+
+    fn restore_location(&mut self, window: &Window) {
+        let location = window
+            .user_data()
+            .get::<Mutex<RestoreLocation>>()
+            .and_then(|restore| restore.lock().unwrap().0.take());
+        if let Some(location) = location {
+            self.space.map_element(window.clone(), location, true);
+        }
+        for output in self.space.outputs_for_element(window) {
+            self.backend_data.schedule_render(&output);
+        }
+    }
+
+    // Fullscreen windows cover their output exactly, so their buffer can be scanned out directly
+    pub fn fullscreen_window(&mut self, window: &Window, wl_output: Option<WlOutput>) {
+        self.unminimize_window(window);
+        // The requested output, else the one the window is on, else any
+        let output = wl_output
+            .as_ref()
+            .and_then(Output::from_resource)
+            .or_else(|| self.space.outputs_for_element(window).into_iter().next())
+            .or_else(|| self.space.outputs().next().cloned());
+        let geometry = match output
+            .as_ref()
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let output = output.unwrap();
+
+        self.save_restore_location(window);
+        let surface = window.toplevel();
+        surface.with_pending_state(|state| {
+            state.states.set(xdg_toplevel::State::Fullscreen);
+            state.size = Some(geometry.size);
+            state.fullscreen_output = wl_output;
+        });
+        surface.send_configure();
+        self.space.map_element(window.clone(), geometry.loc, true);
+        self.backend_data.schedule_render(&output);
+    }
+
+    pub fn unfullscreen_window(&mut self, window: &Window) {
+        let surface = window.toplevel();
+        let states = surface.current_state().states;
+        if !states.contains(xdg_toplevel::State::Fullscreen) {
+            return;
+        }
+        surface.with_pending_state(|state| {
+            state.states.unset(xdg_toplevel::State::Fullscreen);
+            state.size = None;
+            state.fullscreen_output = None;
+        });
+        // Windows maximized while fullscreen go back to being maximized
+        if states.contains(xdg_toplevel::State::Maximized) {
+            self.maximize_window(window);
+            return;
+        }
+        surface.send_configure();
+        self.restore_location(window);
+    }
+
+    // Maximized windows fill the output they are on
+    pub fn maximize_window(&mut self, window: &Window) {
+        self.unminimize_window(window);
+        let output = self
+            .space
+            .outputs_for_element(window)
+            .into_iter()
+            .next()
+            .or_else(|| self.space.outputs().next().cloned());
+        let geometry = match output
+            .as_ref()
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry,
+            None => {
+                window.toplevel().send_configure();
+                return;
+            }
+        };
+        let output = output.unwrap();
+
+        self.save_restore_location(window);
+        let surface = window.toplevel();
+        // Fullscreen wins, the window only becomes maximized once it leaves fullscreen
+        let fullscreen = surface.with_pending_state(|state| {
+            state.states.set(xdg_toplevel::State::Maximized);
+            let fullscreen = state.states.contains(xdg_toplevel::State::Fullscreen);
+            if !fullscreen {
+                state.size = Some(geometry.size);
+            }
+            fullscreen
+        });
+        surface.send_configure();
+        if !fullscreen {
+            self.space.map_element(window.clone(), geometry.loc, true);
+            self.backend_data.schedule_render(&output);
+        }
+    }
+
+    pub fn unmaximize_window(&mut self, window: &Window) {
+        let surface = window.toplevel();
+        let states = surface.current_state().states;
+        if !states.contains(xdg_toplevel::State::Maximized) {
+            surface.send_configure();
+            return;
+        }
+        let fullscreen = surface.with_pending_state(|state| {
+            state.states.unset(xdg_toplevel::State::Maximized);
+            let fullscreen = state.states.contains(xdg_toplevel::State::Fullscreen);
+            if !fullscreen {
+                state.size = None;
+            }
+            fullscreen
+        });
+        surface.send_configure();
+        if !fullscreen {
+            self.restore_location(window);
+        }
+    }
+
+    // Minimized windows are taken out of the space but stay open, taskbars and captures still see
+    // them
+    pub fn minimize_window(&mut self, window: &Window) {
+        let location = match self.space.element_location(window) {
+            Some(location) => location,
+            None => return,
+        };
+        let outputs = self.space.outputs_for_element(window);
+        self.space.unmap_element(window);
+        self.minimized_windows.push((window.clone(), location));
+        if self.is_focused(window) {
+            self.focus_previous();
+        }
+        for output in outputs.iter() {
+            self.backend_data.schedule_render(output);
+        }
+        self.refresh_foreign_toplevels();
+    }
+
+    // Maps a minimized window again where it was, does nothing for other windows
+    pub fn unminimize_window(&mut self, window: &Window) {
+        let index = match self
+            .minimized_windows
+            .iter()
+            .position(|(minimized, _)| minimized == window)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let (window, location) = self.minimized_windows.remove(index);
+        self.space.map_element(window.clone(), location, false);
+        for output in self.space.outputs_for_element(&window) {
+            self.backend_data.schedule_render(&output);
+        }
+        self.refresh_foreign_toplevels();
+    }
+
+    pub fn is_focused(&self, window: &Window) -> bool {
+        self.seat.get_keyboard().map_or(false, |keyboard| {
+            keyboard.current_focus().as_ref() == Some(window.toplevel().wl_surface())
+        })
+    }
+
+    // Raises `window`, gives it keyboard focus and shows it as active, minimized windows are
+    // restored first
+    pub fn activate_window(&mut self, window: &Window) {
+        // The locker keeps the keyboard until the session is unlocked
+        if self.session_lock.is_locked() {
+            return;
+        }
+        self.unminimize_window(window);
+        if self.space.element_location(window).is_none() {
+            return;
+        }
+        self.space.raise_element(window, false);
+        self.focus_window(window);
+        for output in self.space.outputs_for_element(window) {
+            self.backend_data.schedule_render(&output);
+        }
+    }
+
+    // Moves windows that no output shows anymore, e.g. because their output was unplugged or
+    // disabled, onto the output under the pointer or else the first one
+    pub fn migrate_windows(&mut self) {
+        let output = self
+            .pointer_location()
+            .and_then(|location| self.space.output_under(location).next().cloned())
+            .or_else(|| self.space.outputs().next().cloned());
+        let geometry = match output
+            .as_ref()
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let output = output.unwrap();
+
+        let visible = |space: &Space<Window>, rect: Rectangle<i32, Logical>| {
+            space
+                .outputs()
+                .filter_map(|output| space.output_geometry(output))
+                .any(|geometry| geometry.overlaps(rect))
+        };
+        let stranded: Vec<Window> = self
+            .space
+            .elements()
+            .filter(|window| {
+                self.space
+                    .element_geometry(window)
+                    .map_or(false, |rect| !visible(&self.space, rect))
+            })
+            .cloned()
+            .collect();
+        for window in stranded.iter() {
+            // The old restore location is on the removed output too
+            if let Some(restore) = window.user_data().get::<Mutex<RestoreLocation>>() {
+                restore.lock().unwrap().0 = None;
+            }
+            self.space.map_element(window.clone(), geometry.loc, false);
+            let states = window.toplevel().current_state().states;
+            if states.contains(xdg_toplevel::State::Fullscreen) {
+                self.fullscreen_window(window, None);
+            } else if states.contains(xdg_toplevel::State::Maximized) {
+                self.maximize_window(window);
+            }
+        }
+        // Minimized windows are mapped at their stored location again when restored
+        for (window, location) in self.minimized_windows.iter_mut() {
+            let rect = Rectangle::from_loc_and_size(*location, window.geometry().size);
+            if !visible(&self.space, rect) {
+                *location = geometry.loc;
+            }
+        }
+
+        // Keep the focused window focused, on top of the windows already on the output
+        if let Some(window) = stranded.iter().find(|window| self.is_focused(window)) {
+            self.space.raise_element(window, false);
+            self.focus_window(window);
+        }
+        if !stranded.is_empty() {
+            self.backend_data.schedule_render(&output);
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // `windows`: every open window, for bars that show urgent windows and window switchers
+    pub fn windows_command(&self) -> Value {
+        let windows: Vec<Value> = self
+            .space
+            .elements()
+            .map(|window| (window, false))
+            .chain(
+                self.minimized_windows
+                    .iter()
+                    .map(|(window, _)| (window, true)),
+            )
+            .map(|(window, minimized)| {
+                let (title, app_id) = with_states(window.toplevel().wl_surface(), |states| {
+                    let data = states
+                        .data_map
+                        .get::<XdgToplevelSurfaceData>()
+                        .unwrap()
+                        .lock()
+                        .unwrap();
+                    (data.title.clone(), data.app_id.clone())
+                });
+                let states = window.toplevel().current_state().states;
+                let outputs: Vec<String> = self
+                    .space
+                    .outputs_for_element(window)
+                    .iter()
+                    .map(|output| output.name())
+                    .collect();
+                json!({
+                    "title": title,
+                    "app_id": app_id,
+                    "focused": self.is_focused(window),
+                    "urgent": self.activation.is_urgent(window),
+                    "minimized": minimized,
+                    "maximized": states.contains(xdg_toplevel::State::Maximized),
+                    "fullscreen": states.contains(xdg_toplevel::State::Fullscreen),
+                    "outputs": outputs,
+                })
+            })
+            .collect();
+        json!({ "windows": windows })
+    }
+}
+
+// Sends the initial configure once the client committed its first state. Clients don't attach a
+// buffer before receiving it
+pub fn handle_commit(space: &Space<Window>, popups: &PopupManager, surface: &WlSurface) {
+    if let Some(window) = space
+        .elements()
+        .find(|window| window.toplevel().wl_surface() == surface)
+    {
+        let initial_configure_sent = with_states(surface, |states| {
+            states
+                .data_map
+                .get::<XdgToplevelSurfaceData>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .initial_configure_sent
+        });
+        if !initial_configure_sent {
+            window.toplevel().send_configure();
+        }
+    }
+
+    if let Some(popup) = popups.find_popup(surface) {
+        let PopupKind::Xdg(ref popup) = popup;
+        let initial_configure_sent = with_states(surface, |states| {
+            states
+                .data_map
+                .get::<XdgPopupSurfaceData>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .initial_configure_sent
+        });
+        if !initial_configure_sent {
+            if let Err(err) = popup.send_configure() {
+                tracing::warn!("Failed to configure popup: {}", err);
+            }
+        }
+    }
+}
+
+delegate_xdg_shell!(@<B: Backend + 'static> Waysight<B>);
@@ -0,0 +1,156 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use smithay::reexports::calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+
+use crate::state::{Backend, CalloopData, Waysight};
+
+// Commands longer than this are rejected, nothing legitimate comes close
+const MAX_COMMAND_LEN: usize = 4096;
+
+// Frame and plane statistics of an output, reported by the `stats` command
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct OutputStats {
+    pub name: String,
+    pub frames: u64,
+    // Frames where a client buffer went straight to the primary plane, without composition
+    pub direct_scanout_frames: u64,
+    // Plane usage of the last frame
+    pub direct_scanout: bool,
+    pub overlay_planes: usize,
+    pub cursor_plane: bool,
+    // Whether variable refresh rate is active right now
+    pub adaptive_sync: bool,
+    // Whether the fullscreen window asked for async presentation through tearing control
+    pub tearing_requested: bool,
+}
+
+// The control socket lives at `$XDG_RUNTIME_DIR/waysight-<wayland socket>.sock` and its path is
+// exported to clients as `WAYSIGHT_SOCKET`. A connection carries one command terminated by a
+// newline and gets one line of JSON back, e.g. `echo stats | socat - UNIX-CONNECT:$WAYSIGHT_SOCKET`
+pub fn init<B: Backend + 'static>(
+    handle: &LoopHandle<'static, CalloopData<B>>,
+    socket_name: &str,
+) -> Option<PathBuf> {
+    let runtime_dir = match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) => dir,
+        Err(_) => {
+            tracing::warn!("XDG_RUNTIME_DIR is not set, not creating the ipc socket");
+            return None;
+        }
+    };
+    let path = PathBuf::from(runtime_dir).join(format!("waysight-{}.sock", socket_name));
+    // Left behind by a previous instance that crashed, the wayland socket name is ours now
+    let _ = fs::remove_file(&path);
+
+    let listener = match UnixListener::bind(&path).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }) {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("Failed to bind the ipc socket {}: {}", path.display(), err);
+            return None;
+        }
+    };
+
+    let client_handle = handle.clone();
+    let result = handle.insert_source(
+        Generic::new(listener, Interest::READ, Mode::Level),
+        move |_, listener, _| {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => add_client(&client_handle, stream),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        tracing::warn!("Failed to accept ipc connection: {}", err);
+                        break;
+                    }
+                }
+            }
+            Ok(PostAction::Continue)
+        },
+    );
+    if let Err(err) = result {
+        tracing::error!("Failed to listen on the ipc socket: {}", err);
+        return None;
+    }
+
+    env::set_var("WAYSIGHT_SOCKET", &path);
+    tracing::info!("Listening for ipc commands on {}", path.display());
+    Some(path)
+}
+
+fn add_client<B: Backend + 'static>(
+    handle: &LoopHandle<'static, CalloopData<B>>,
+    stream: UnixStream,
+) {
+    if let Err(err) = stream.set_nonblocking(true) {
+        tracing::warn!("Failed to set up ipc connection: {}", err);
+        return;
+    }
+    let mut buffer = Vec::new();
+    let result = handle.insert_source(
+        Generic::new(stream, Interest::READ, Mode::Level),
+        move |_, stream, data| {
+            let mut chunk = [0u8; 512];
+            let closed = loop {
+                match stream.read(&mut chunk) {
+                    Ok(0) => break true,
+                    Ok(len) => buffer.extend_from_slice(&chunk[..len]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                    Err(_) => return Ok(PostAction::Remove),
+                }
+            };
+
+            let line_end = buffer.iter().position(|byte| *byte == b'\n');
+            if line_end.is_none() && !closed && buffer.len() <= MAX_COMMAND_LEN {
+                return Ok(PostAction::Continue);
+            }
+            let reply = if buffer.len() > MAX_COMMAND_LEN {
+                error("command too long")
+            } else {
+                let line = &buffer[..line_end.unwrap_or(buffer.len())];
+                match std::str::from_utf8(line) {
+                    Ok(command) => data.state.handle_ipc_command(command.trim()),
+                    Err(_) => error("command is not valid utf-8"),
+                }
+            };
+            // Replies are small enough for the socket buffer, a client that doesn't read them
+            // only loses its own reply
+            let _ = stream
+                .write_all(format!("{}\n", reply).as_bytes())
+                .and_then(|_| stream.flush());
+            Ok(PostAction::Remove)
+        },
+    );
+    if let Err(err) = result {
+        tracing::warn!("Failed to listen on ipc connection: {}", err);
+    }
+}
+
+pub fn error(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    pub fn handle_ipc_command(&mut self, command: &str) -> Value {
+        let mut args = command.split_whitespace();
+        match args.next() {
+            Some("stats") => json!({ "outputs": self.backend_data.output_stats() }),
+            Some("screenshot") => self.screenshot_command(args),
+            Some("record") => self.record_command(args),
+            Some("idle") => self.idle_command(),
+            Some("power") => self.power_command(args),
+            Some("windows") => self.windows_command(),
+            Some(command) => error(&format!("unknown command `{}`", command)),
+            None => error("empty command"),
+        }
+    }
+}
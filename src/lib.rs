@@ -1,11 +1,22 @@
 use std::{path::PathBuf, sync::Mutex};
 pub mod backend;
+pub mod capture;
 pub mod config;
+pub mod focus;
 pub mod handlers;
+pub mod ipc;
+pub mod protocols;
+pub mod recording;
+pub mod render;
+pub mod screenshot;
 pub mod state;
 pub mod utils;
 
 pub struct UserData {
     pub config_path: Option<PathBuf>,
+    pub backend: Option<String>,
 }
-pub static USER_DATA: Mutex<UserData> = Mutex::new(UserData { config_path: None });
+pub static USER_DATA: Mutex<UserData> = Mutex::new(UserData {
+    config_path: None,
+    backend: None,
+});
@@ -15,7 +15,7 @@ Options:
                                 Defalts to $XDG_CONFIG_HOME/waysight/waysight.toml
 
     -b=value  --backend=value   Sets the type of backend for waysight to run.
-                                Available values are \"drm\" and \"winit\"
+                                Available values are \"drm\", \"winit\" and \"headless\"
                                 Will automatically choose backend if option isn't set";
     println!("{}", usage_str);
 }
@@ -30,6 +30,9 @@ fn parse_args(args: Vec<String>, data: &mut UserData) {
                 ("--config" | "-c", config_path) => {
                     data.config_path = Some(PathBuf::from(config_path));
                 }
+                ("--backend" | "-b", backend) => {
+                    data.backend = Some(backend.to_owned());
+                }
                 _ => {
                     print_usage();
                     return;
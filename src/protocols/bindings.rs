@@ -0,0 +1,41 @@
+// Server bindings for protocols the wayland-protocols release smithay re-exports doesn't ship yet.
+// The XML is vendored from wayland-protocols in resources/protocols and the code is generated the
+// way wayland-protocols does it, so the interfaces they refer to are the ones smithay uses
+macro_rules! server_protocol {
+    ($path:expr, [$($imports:path),*]) => {
+        pub mod server {
+            #![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+            #![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+            #![allow(missing_docs, clippy::all)]
+
+            use smithay::reexports::wayland_server;
+            use smithay::reexports::wayland_server::protocol::*;
+            $(use $imports::{*};)*
+
+            pub mod __interfaces {
+                use smithay::reexports::wayland_server::protocol::__interfaces::*;
+                $(use $imports::{__interfaces::*};)*
+                wayland_scanner::generate_interfaces!($path);
+            }
+            use self::__interfaces::*;
+
+            wayland_scanner::generate_server_code!($path);
+        }
+    };
+}
+
+// ext-image-capture-source-v1, capture sources for outputs and foreign toplevels
+pub mod image_capture_source {
+    server_protocol!(
+        "resources/protocols/ext-image-capture-source-v1.xml",
+        [smithay::reexports::wayland_protocols::ext::foreign_toplevel_list::v1::server]
+    );
+}
+
+// ext-image-copy-capture-v1, copies capture sources into client buffers
+pub mod image_copy_capture {
+    server_protocol!(
+        "resources/protocols/ext-image-copy-capture-v1.xml",
+        [crate::protocols::bindings::image_capture_source::server]
+    );
+}
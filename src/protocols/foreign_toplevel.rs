@@ -0,0 +1,493 @@
+// Implementation of ext-foreign-toplevel-list-v1 and wlr-foreign-toplevel-management-unstable-v1.
+// The ext list is read-only, its handles are how other protocols, like toplevel capture, refer to
+// a window. The wlr manager is what taskbars and window switchers use, it also has the window
+// state and outputs and lets clients activate, close, minimize, maximize and fullscreen windows
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use smithay::{
+    desktop::Window,
+    output::Output,
+    reexports::{
+        wayland_protocols::{
+            ext::foreign_toplevel_list::v1::server::{
+                ext_foreign_toplevel_handle_v1::{self, ExtForeignToplevelHandleV1},
+                ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+            },
+            xdg::shell::server::xdg_toplevel,
+        },
+        wayland_protocols_wlr::foreign_toplevel::v1::server::{
+            zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+            zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+        },
+        wayland_server::{
+            backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+            Resource,
+        },
+    },
+    utils::IsAlive,
+    wayland::{compositor::with_states, shell::xdg::XdgToplevelSurfaceData},
+};
+
+use crate::state::{Backend, Waysight};
+
+const LIST_VERSION: u32 = 1;
+const MANAGER_VERSION: u32 = 3;
+
+#[derive(Clone, Default, PartialEq)]
+struct ToplevelInfo {
+    title: String,
+    app_id: String,
+    states: Vec<zwlr_foreign_toplevel_handle_v1::State>,
+    outputs: Vec<Output>,
+}
+
+impl ToplevelInfo {
+    fn new(window: &Window, minimized: bool, outputs: Vec<Output>) -> Self {
+        let (title, app_id) = with_states(window.toplevel().wl_surface(), |states| {
+            let data = states
+                .data_map
+                .get::<XdgToplevelSurfaceData>()
+                .unwrap()
+                .lock()
+                .unwrap();
+            (
+                data.title.clone().unwrap_or_default(),
+                data.app_id.clone().unwrap_or_default(),
+            )
+        });
+
+        let current = window.toplevel().current_state().states;
+        let mut states = Vec::new();
+        for (xdg_state, state) in [
+            (
+                xdg_toplevel::State::Maximized,
+                zwlr_foreign_toplevel_handle_v1::State::Maximized,
+            ),
+            (
+                xdg_toplevel::State::Activated,
+                zwlr_foreign_toplevel_handle_v1::State::Activated,
+            ),
+            (
+                xdg_toplevel::State::Fullscreen,
+                zwlr_foreign_toplevel_handle_v1::State::Fullscreen,
+            ),
+        ] {
+            if current.contains(xdg_state) {
+                states.push(state);
+            }
+        }
+        if minimized {
+            states.push(zwlr_foreign_toplevel_handle_v1::State::Minimized);
+        }
+
+        ToplevelInfo {
+            title,
+            app_id,
+            states,
+            outputs,
+        }
+    }
+
+    // The `state` event takes an array of native endian u32
+    fn state_bytes(&self) -> Vec<u8> {
+        self.states
+            .iter()
+            .flat_map(|state| (*state as u32).to_ne_bytes())
+            .collect()
+    }
+}
+
+struct Toplevel {
+    window: Window,
+    // Never reused, so clients can tell windows apart across restarts of their own
+    identifier: String,
+    info: ToplevelInfo,
+    ext_handles: Vec<ExtForeignToplevelHandleV1>,
+    wlr_handles: Vec<ZwlrForeignToplevelHandleV1>,
+}
+
+pub struct ForeignToplevelState {
+    lists: Vec<ExtForeignToplevelListV1>,
+    managers: Vec<ZwlrForeignToplevelManagerV1>,
+    toplevels: Vec<Toplevel>,
+    // Identifiers are this prefix and a counter
+    identifier_prefix: String,
+    next_identifier: u64,
+}
+
+impl ForeignToplevelState {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        display_handle.create_global::<Waysight<B>, ExtForeignToplevelListV1, _>(LIST_VERSION, ());
+        display_handle
+            .create_global::<Waysight<B>, ZwlrForeignToplevelManagerV1, _>(MANAGER_VERSION, ());
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        ForeignToplevelState {
+            lists: Vec::new(),
+            managers: Vec::new(),
+            toplevels: Vec::new(),
+            identifier_prefix: format!("{:x}", started.as_nanos()),
+            next_identifier: 0,
+        }
+    }
+}
+
+fn send_ext_toplevel<B: Backend + 'static>(
+    display_handle: &DisplayHandle,
+    list: &ExtForeignToplevelListV1,
+    toplevel: &mut Toplevel,
+) {
+    let client = match list.client() {
+        Some(client) => client,
+        None => return,
+    };
+    let handle = match client.create_resource::<ExtForeignToplevelHandleV1, _, Waysight<B>>(
+        display_handle,
+        list.version(),
+        toplevel.window.clone(),
+    ) {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+    list.toplevel(&handle);
+    handle.identifier(toplevel.identifier.clone());
+    handle.title(toplevel.info.title.clone());
+    handle.app_id(toplevel.info.app_id.clone());
+    handle.done();
+    toplevel.ext_handles.push(handle);
+}
+
+fn send_wlr_toplevel<B: Backend + 'static>(
+    display_handle: &DisplayHandle,
+    manager: &ZwlrForeignToplevelManagerV1,
+    toplevel: &mut Toplevel,
+) {
+    let client = match manager.client() {
+        Some(client) => client,
+        None => return,
+    };
+    let handle = match client.create_resource::<ZwlrForeignToplevelHandleV1, _, Waysight<B>>(
+        display_handle,
+        manager.version(),
+        toplevel.window.clone(),
+    ) {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+    manager.toplevel(&handle);
+    handle.title(toplevel.info.title.clone());
+    handle.app_id(toplevel.info.app_id.clone());
+    send_outputs(&handle, &[], &toplevel.info.outputs);
+    handle.state(toplevel.info.state_bytes());
+    handle.done();
+    toplevel.wlr_handles.push(handle);
+}
+
+// Sends `output_enter` and `output_leave` for the change from `old` to `new`, for every wl_output
+// the client bound
+fn send_outputs(handle: &ZwlrForeignToplevelHandleV1, old: &[Output], new: &[Output]) {
+    let client = match handle.client() {
+        Some(client) => client,
+        None => return,
+    };
+    for output in old.iter().filter(|output| !new.contains(output)) {
+        for wl_output in output.client_outputs(&client) {
+            handle.output_leave(&wl_output);
+        }
+    }
+    for output in new.iter().filter(|output| !old.contains(output)) {
+        for wl_output in output.client_outputs(&client) {
+            handle.output_enter(&wl_output);
+        }
+    }
+}
+
+fn send_changes(toplevel: &Toplevel, info: &ToplevelInfo) {
+    let old = &toplevel.info;
+    let title_changed = old.title != info.title;
+    let app_id_changed = old.app_id != info.app_id;
+
+    if title_changed || app_id_changed {
+        for handle in toplevel.ext_handles.iter() {
+            if title_changed {
+                handle.title(info.title.clone());
+            }
+            if app_id_changed {
+                handle.app_id(info.app_id.clone());
+            }
+            handle.done();
+        }
+    }
+
+    for handle in toplevel.wlr_handles.iter() {
+        if title_changed {
+            handle.title(info.title.clone());
+        }
+        if app_id_changed {
+            handle.app_id(info.app_id.clone());
+        }
+        if old.outputs != info.outputs {
+            send_outputs(handle, &old.outputs, &info.outputs);
+        }
+        if old.states != info.states {
+            handle.state(info.state_bytes());
+        }
+        handle.done();
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Brings the lists in line with the open windows: new windows are announced, closed ones
+    // removed and changes to their title, app id, state or outputs sent. Called when windows map,
+    // are minimized or go away
+    pub fn refresh_foreign_toplevels(&mut self) {
+        let windows: Vec<(Window, bool)> = self
+            .space
+            .elements()
+            .map(|window| (window.clone(), false))
+            .chain(
+                self.minimized_windows
+                    .iter()
+                    .map(|(window, _)| (window.clone(), true)),
+            )
+            .collect();
+
+        self.foreign_toplevel_state.toplevels.retain(|toplevel| {
+            let open = windows.iter().any(|(window, _)| window == &toplevel.window);
+            if !open {
+                for handle in toplevel.ext_handles.iter() {
+                    handle.closed();
+                }
+                for handle in toplevel.wlr_handles.iter() {
+                    handle.closed();
+                }
+            }
+            open
+        });
+
+        for (window, minimized) in windows.iter() {
+            self.update_foreign_toplevel(window, *minimized);
+        }
+    }
+
+    // Sends what changed about a single window, e.g. after it committed. Windows that aren't
+    // mapped or minimized are left to `refresh_foreign_toplevels`
+    pub fn refresh_foreign_toplevel(&mut self, window: &Window) {
+        if self.space.element_location(window).is_some() {
+            self.update_foreign_toplevel(window, false);
+        } else if self
+            .minimized_windows
+            .iter()
+            .any(|(minimized, _)| minimized == window)
+        {
+            self.update_foreign_toplevel(window, true);
+        }
+    }
+
+    fn update_foreign_toplevel(&mut self, window: &Window, minimized: bool) {
+        let state = &mut self.foreign_toplevel_state;
+        let info = ToplevelInfo::new(window, minimized, self.space.outputs_for_element(window));
+        match state
+            .toplevels
+            .iter_mut()
+            .find(|toplevel| &toplevel.window == window)
+        {
+            Some(toplevel) => {
+                if toplevel.info != info {
+                    send_changes(toplevel, &info);
+                    toplevel.info = info;
+                }
+            }
+            None => {
+                let mut toplevel = Toplevel {
+                    window: window.clone(),
+                    identifier: format!("{}-{}", state.identifier_prefix, state.next_identifier),
+                    info,
+                    ext_handles: Vec::new(),
+                    wlr_handles: Vec::new(),
+                };
+                state.next_identifier += 1;
+                for list in state.lists.iter() {
+                    send_ext_toplevel::<B>(&self.display_handle, list, &mut toplevel);
+                }
+                for manager in state.managers.iter() {
+                    send_wlr_toplevel::<B>(&self.display_handle, manager, &mut toplevel);
+                }
+                state.toplevels.push(toplevel);
+            }
+        }
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ExtForeignToplevelListV1, ()> for Waysight<B> {
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtForeignToplevelListV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let list = data_init.init(resource, ());
+        let toplevel_state = &mut state.foreign_toplevel_state;
+        for toplevel in toplevel_state.toplevels.iter_mut() {
+            send_ext_toplevel::<B>(handle, &list, toplevel);
+        }
+        toplevel_state.lists.push(list);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtForeignToplevelListV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        list: &ExtForeignToplevelListV1,
+        request: ext_foreign_toplevel_list_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // No more toplevels are sent after `stop`, the client destroys the list once it got
+        // `finished`
+        if let ext_foreign_toplevel_list_v1::Request::Stop = request {
+            state
+                .foreign_toplevel_state
+                .lists
+                .retain(|tracked| tracked != list);
+            list.finished();
+        }
+    }
+
+    fn destroyed(state: &mut Self, _client: ClientId, list: ExtForeignToplevelListV1, _data: &()) {
+        state
+            .foreign_toplevel_state
+            .lists
+            .retain(|tracked| tracked != &list);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtForeignToplevelHandleV1, Window> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _toplevel: &ExtForeignToplevelHandleV1,
+        _request: ext_foreign_toplevel_handle_v1::Request,
+        _data: &Window,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        handle: ExtForeignToplevelHandleV1,
+        _data: &Window,
+    ) {
+        for toplevel in state.foreign_toplevel_state.toplevels.iter_mut() {
+            toplevel.ext_handles.retain(|tracked| tracked != &handle);
+        }
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ZwlrForeignToplevelManagerV1, ()> for Waysight<B> {
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrForeignToplevelManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let manager = data_init.init(resource, ());
+        let toplevel_state = &mut state.foreign_toplevel_state;
+        for toplevel in toplevel_state.toplevels.iter_mut() {
+            send_wlr_toplevel::<B>(handle, &manager, toplevel);
+        }
+        toplevel_state.managers.push(manager);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrForeignToplevelManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        manager: &ZwlrForeignToplevelManagerV1,
+        request: zwlr_foreign_toplevel_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Request::Stop = request {
+            state
+                .foreign_toplevel_state
+                .managers
+                .retain(|tracked| tracked != manager);
+            manager.finished();
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        manager: ZwlrForeignToplevelManagerV1,
+        _data: &(),
+    ) {
+        state
+            .foreign_toplevel_state
+            .managers
+            .retain(|tracked| tracked != &manager);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrForeignToplevelHandleV1, Window> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _toplevel: &ZwlrForeignToplevelHandleV1,
+        request: zwlr_foreign_toplevel_handle_v1::Request,
+        window: &Window,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Handles of closed windows stay around until the client destroys them
+        if !window.alive() {
+            return;
+        }
+        match request {
+            zwlr_foreign_toplevel_handle_v1::Request::SetMaximized => state.maximize_window(window),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMaximized => {
+                state.unmaximize_window(window)
+            }
+            zwlr_foreign_toplevel_handle_v1::Request::SetMinimized => state.minimize_window(window),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMinimized => {
+                state.unminimize_window(window)
+            }
+            // There is only one seat
+            zwlr_foreign_toplevel_handle_v1::Request::Activate { .. } => {
+                state.activate_window(window)
+            }
+            zwlr_foreign_toplevel_handle_v1::Request::Close => window.toplevel().send_close(),
+            zwlr_foreign_toplevel_handle_v1::Request::SetFullscreen { output } => {
+                state.fullscreen_window(window, output)
+            }
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetFullscreen => {
+                state.unfullscreen_window(window)
+            }
+            // `set_rectangle` is a hint for minimize animations, there are none
+            _ => {}
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        handle: ZwlrForeignToplevelHandleV1,
+        _data: &Window,
+    ) {
+        for toplevel in state.foreign_toplevel_state.toplevels.iter_mut() {
+            toplevel.wlr_handles.retain(|tracked| tracked != &handle);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Implementation of idle-inhibit-unstable-v1, used by video players. An inhibitor only keeps the
+// session from going idle while its surface is visible on an output
+use serde_json::{json, Value};
+use smithay::{
+    desktop::Window,
+    reexports::{
+        wayland_protocols::wp::idle_inhibit::zv1::server::{
+            zwp_idle_inhibit_manager_v1::{self, ZwpIdleInhibitManagerV1},
+            zwp_idle_inhibitor_v1::{self, ZwpIdleInhibitorV1},
+        },
+        wayland_server::{
+            backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+            DisplayHandle, GlobalDispatch, New, Resource,
+        },
+    },
+    utils::IsAlive,
+    wayland::{compositor::with_states, shell::xdg::XdgToplevelSurfaceData},
+};
+
+use crate::state::{Backend, Waysight};
+
+const MANAGER_VERSION: u32 = 1;
+
+pub struct IdleInhibitState {
+    inhibitors: Vec<ZwpIdleInhibitorV1>,
+}
+
+impl IdleInhibitState {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        display_handle
+            .create_global::<Waysight<B>, ZwpIdleInhibitManagerV1, _>(MANAGER_VERSION, ());
+        IdleInhibitState {
+            inhibitors: Vec::new(),
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Whether a visible surface inhibits idling
+    pub fn idle_inhibited(&self) -> bool {
+        self.idle_inhibit_state.inhibitors.iter().any(|inhibitor| {
+            inhibitor
+                .data::<WlSurface>()
+                .and_then(|surface| self.inhibiting_window(surface))
+                .map_or(false, |window| self.window_visible(&window))
+        })
+    }
+
+    fn inhibiting_window(&self, surface: &WlSurface) -> Option<Window> {
+        if !surface.alive() {
+            return None;
+        }
+        self.window_for_child_surface(surface)
+    }
+
+    // Minimized windows, windows off every output and everything while locked are hidden
+    fn window_visible(&self, window: &Window) -> bool {
+        !self.session_lock.is_locked() && !self.space.outputs_for_element(window).is_empty()
+    }
+
+    // The inhibitors for the `idle` ipc command
+    pub fn idle_inhibitors(&self) -> Vec<Value> {
+        self.idle_inhibit_state
+            .inhibitors
+            .iter()
+            .filter_map(|inhibitor| inhibitor.data::<WlSurface>())
+            .map(|surface| {
+                let window = self.inhibiting_window(surface);
+                let (title, app_id) = match &window {
+                    Some(window) => with_states(window.toplevel().wl_surface(), |states| {
+                        let data = states
+                            .data_map
+                            .get::<XdgToplevelSurfaceData>()
+                            .unwrap()
+                            .lock()
+                            .unwrap();
+                        (data.title.clone(), data.app_id.clone())
+                    }),
+                    None => (None, None),
+                };
+                json!({
+                    "title": title,
+                    "app_id": app_id,
+                    "visible": window.map_or(false, |window| self.window_visible(&window)),
+                })
+            })
+            .collect()
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ZwpIdleInhibitManagerV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwpIdleInhibitManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwpIdleInhibitManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwpIdleInhibitManagerV1,
+        request: zwp_idle_inhibit_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_idle_inhibit_manager_v1::Request::CreateInhibitor { id, surface } = request {
+            let inhibitor = data_init.init(id, surface);
+            state.idle_inhibit_state.inhibitors.push(inhibitor);
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwpIdleInhibitorV1, WlSurface> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _inhibitor: &ZwpIdleInhibitorV1,
+        _request: zwp_idle_inhibitor_v1::Request,
+        _data: &WlSurface,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        inhibitor: ZwpIdleInhibitorV1,
+        _data: &WlSurface,
+    ) {
+        state
+            .idle_inhibit_state
+            .inhibitors
+            .retain(|tracked| tracked != &inhibitor);
+    }
+}
@@ -0,0 +1,199 @@
+// Implementation of ext-idle-notify-v1, used by swayidle to dim, lock and suspend. Any input through
+// the seat counts as activity, visible windows with an idle inhibitor keep the session from going
+// idle
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use smithay::reexports::{
+    calloop::{
+        timer::{TimeoutAction, Timer},
+        RegistrationToken,
+    },
+    wayland_protocols::ext::idle_notify::v1::server::{
+        ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+        ext_idle_notifier_v1::{self, ExtIdleNotifierV1},
+    },
+    wayland_server::{
+        backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+    },
+};
+
+use crate::state::{Backend, Waysight};
+
+const NOTIFIER_VERSION: u32 = 1;
+
+struct Notification {
+    notification: ExtIdleNotificationV1,
+    timeout: Duration,
+    idle: bool,
+    // Fires once the timeout passed since the last activity, `None` while idle
+    timer: Option<RegistrationToken>,
+}
+
+pub struct IdleNotifyState {
+    notifications: Vec<Notification>,
+    last_activity: Instant,
+}
+
+impl IdleNotifyState {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        display_handle.create_global::<Waysight<B>, ExtIdleNotifierV1, _>(NOTIFIER_VERSION, ());
+        IdleNotifyState {
+            notifications: Vec::new(),
+            last_activity: Instant::now(),
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Called for every input event. Idle notifications resume and their timers start over
+    pub fn notify_activity(&mut self) {
+        self.idle_notify_state.last_activity = Instant::now();
+        let resumed: Vec<ExtIdleNotificationV1> = self
+            .idle_notify_state
+            .notifications
+            .iter_mut()
+            .filter(|notification| notification.idle)
+            .map(|notification| {
+                notification.idle = false;
+                notification.notification.resumed();
+                notification.notification.clone()
+            })
+            .collect();
+        for notification in resumed {
+            self.start_idle_timer(&notification);
+        }
+    }
+
+    fn start_idle_timer(&mut self, resource: &ExtIdleNotificationV1) {
+        let last_activity = self.idle_notify_state.last_activity;
+        let notification = match self
+            .idle_notify_state
+            .notifications
+            .iter_mut()
+            .find(|notification| &notification.notification == resource)
+        {
+            Some(notification) => notification,
+            None => return,
+        };
+        let timer = Timer::from_deadline(last_activity + notification.timeout);
+        let resource = resource.clone();
+        let token = self
+            .loop_handle
+            .insert_source(timer, move |_, _, data| data.state.on_idle_timer(&resource));
+        match token {
+            Ok(token) => notification.timer = Some(token),
+            Err(err) => tracing::error!("Failed to start an idle timer: {}", err),
+        }
+    }
+
+    fn on_idle_timer(&mut self, resource: &ExtIdleNotificationV1) -> TimeoutAction {
+        let inhibited = self.idle_inhibited();
+        let last_activity = self.idle_notify_state.last_activity;
+        let notification = match self
+            .idle_notify_state
+            .notifications
+            .iter_mut()
+            .find(|notification| &notification.notification == resource)
+        {
+            Some(notification) => notification,
+            None => return TimeoutAction::Drop,
+        };
+        // There was input since the timer was started
+        let idle_at = last_activity + notification.timeout;
+        if Instant::now() < idle_at {
+            return TimeoutAction::ToInstant(idle_at);
+        }
+        // Checked again after another timeout, the inhibitor may be gone by then
+        if inhibited {
+            return TimeoutAction::ToDuration(notification.timeout);
+        }
+        notification.idle = true;
+        notification.timer = None;
+        notification.notification.idled();
+        TimeoutAction::Drop
+    }
+
+    // `idle`: how long there was no input, the notifications and the inhibitors
+    pub fn idle_command(&self) -> Value {
+        let notifications: Vec<Value> = self
+            .idle_notify_state
+            .notifications
+            .iter()
+            .map(|notification| {
+                json!({
+                    "timeout_ms": notification.timeout.as_millis() as u64,
+                    "idle": notification.idle,
+                })
+            })
+            .collect();
+        json!({
+            "idle_for_ms": self.idle_notify_state.last_activity.elapsed().as_millis() as u64,
+            "inhibited": self.idle_inhibited(),
+            "notifications": notifications,
+            "inhibitors": self.idle_inhibitors(),
+        })
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ExtIdleNotifierV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtIdleNotifierV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtIdleNotifierV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _notifier: &ExtIdleNotifierV1,
+        request: ext_idle_notifier_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        // There is only one seat
+        if let ext_idle_notifier_v1::Request::GetIdleNotification { id, timeout, .. } = request {
+            let notification = data_init.init(id, ());
+            state.idle_notify_state.notifications.push(Notification {
+                notification: notification.clone(),
+                timeout: Duration::from_millis(timeout as u64),
+                idle: false,
+                timer: None,
+            });
+            state.start_idle_timer(&notification);
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtIdleNotificationV1, ()> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _notification: &ExtIdleNotificationV1,
+        _request: ext_idle_notification_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+
+    fn destroyed(state: &mut Self, _client: ClientId, resource: ExtIdleNotificationV1, _data: &()) {
+        let notifications = &mut state.idle_notify_state.notifications;
+        if let Some(index) = notifications
+            .iter()
+            .position(|notification| notification.notification == resource)
+        {
+            if let Some(token) = notifications.remove(index).timer {
+                state.loop_handle.remove(token);
+            }
+        }
+    }
+}
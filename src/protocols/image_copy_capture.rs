@@ -0,0 +1,571 @@
+// Implementation of ext-image-copy-capture-v1, the successor of wlr-screencopy, with output
+// (ext-output-image-capture-source-manager-v1) and toplevel
+// (ext-foreign-toplevel-image-capture-source-manager-v1) capture sources
+use std::{sync::Mutex, time::Duration};
+
+use smithay::{
+    backend::{allocator::Modifier, drm::DrmNode},
+    desktop::{Space, Window},
+    output::Output,
+    reexports::wayland_server::{
+        backend::ClientId, protocol::wl_buffer::WlBuffer, Client, DataInit, Dispatch,
+        DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+    },
+    utils::{IsAlive, Physical, Size, Transform},
+};
+
+use crate::{
+    capture::{
+        self, CaptureError, CaptureFrame, CaptureRequest, CaptureSource, CaptureTarget,
+        DMABUF_FORMATS, SHM_FORMATS,
+    },
+    protocols::bindings::{
+        image_capture_source::server::{
+            ext_foreign_toplevel_image_capture_source_manager_v1::{
+                self, ExtForeignToplevelImageCaptureSourceManagerV1,
+            },
+            ext_image_capture_source_v1::{self, ExtImageCaptureSourceV1},
+            ext_output_image_capture_source_manager_v1::{
+                self, ExtOutputImageCaptureSourceManagerV1,
+            },
+        },
+        image_copy_capture::server::{
+            ext_image_copy_capture_cursor_session_v1::{self, ExtImageCopyCaptureCursorSessionV1},
+            ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+            ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+            ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+        },
+    },
+    state::{Backend, Waysight},
+};
+
+const MANAGER_VERSION: u32 = 1;
+const SOURCE_MANAGER_VERSION: u32 = 1;
+
+// What a capture source refers to. `None` as source data means the output or window was already
+// gone
+#[derive(Clone)]
+pub enum ImageCaptureSource {
+    Output(Output),
+    Toplevel(Window),
+}
+
+pub struct SessionData {
+    source: Option<ImageCaptureSource>,
+    paint_cursors: bool,
+    // The size last advertised through `buffer_size`
+    size: Option<Size<i32, Physical>>,
+    // Sessions have at most one frame at a time
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+    // The first frame is sent right away, later ones wait for damage
+    captured: bool,
+    stopped: bool,
+}
+
+pub struct FrameData {
+    session: ExtImageCopyCaptureSessionV1,
+    buffer: Option<WlBuffer>,
+    captured: bool,
+}
+
+#[derive(Default)]
+pub struct ImageCopyCaptureState {
+    sessions: Vec<ExtImageCopyCaptureSessionV1>,
+}
+
+// A frame waiting in the capture queue
+pub struct Frame(ExtImageCopyCaptureFrameV1);
+
+impl Frame {
+    pub fn ready(&self, size: Size<i32, Physical>, transform: Transform, time: Duration) {
+        let Frame(frame) = self;
+        if let Some(data) = frame.data::<Mutex<FrameData>>() {
+            let session = data.lock().unwrap().session.clone();
+            if let Some(session_data) = session.data::<Mutex<SessionData>>() {
+                session_data.lock().unwrap().captured = true;
+            }
+        }
+        frame.transform(transform.into());
+        // The whole buffer counts as damaged, the frame is only sent once something changed
+        frame.damage(0, 0, size.w, size.h);
+        frame.presentation_time(
+            (time.as_secs() >> 32) as u32,
+            time.as_secs() as u32,
+            time.subsec_nanos(),
+        );
+        frame.ready();
+    }
+
+    pub fn failed(&self, err: &CaptureError) {
+        let reason = match err {
+            CaptureError::SourceGone => ext_image_copy_capture_frame_v1::FailureReason::Stopped,
+            err if err.is_buffer_constraint() => {
+                ext_image_copy_capture_frame_v1::FailureReason::BufferConstraints
+            }
+            _ => ext_image_copy_capture_frame_v1::FailureReason::Unknown,
+        };
+        self.0.failed(reason);
+    }
+}
+
+pub fn init<B: Backend + 'static>(display_handle: &DisplayHandle) {
+    display_handle
+        .create_global::<Waysight<B>, ExtImageCopyCaptureManagerV1, _>(MANAGER_VERSION, ());
+    display_handle.create_global::<Waysight<B>, ExtOutputImageCaptureSourceManagerV1, _>(
+        SOURCE_MANAGER_VERSION,
+        (),
+    );
+    display_handle.create_global::<Waysight<B>, ExtForeignToplevelImageCaptureSourceManagerV1, _>(
+        SOURCE_MANAGER_VERSION,
+        (),
+    );
+}
+
+impl ImageCaptureSource {
+    // The output the capture is done on and what is captured, `None` once the source is gone
+    fn resolve(&self, space: &Space<Window>) -> Option<(Output, CaptureSource)> {
+        match self {
+            ImageCaptureSource::Output(output) => space
+                .outputs()
+                .any(|mapped| mapped == output)
+                .then(|| (output.clone(), CaptureSource::Output(None))),
+            ImageCaptureSource::Toplevel(window) => {
+                if !window.alive() {
+                    return None;
+                }
+                let output = capture::toplevel_output(space, window)?;
+                Some((output, CaptureSource::Toplevel(window.clone())))
+            }
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Sends new buffer constraints to sessions whose output or window changed its size, and stops
+    // the sessions of outputs and windows that went away
+    pub fn update_capture_sessions(&mut self) {
+        let render_node = self.backend_data.render_node();
+        let space = &self.space;
+        self.image_copy_capture_state
+            .sessions
+            .retain(|session| update_session(session, space, render_node));
+    }
+
+    // Like `update_capture_sessions`, but only for the sessions capturing `window`, e.g. after it
+    // committed a new size
+    pub fn update_window_capture_sessions(&mut self, window: &Window) {
+        let render_node = self.backend_data.render_node();
+        let space = &self.space;
+        self.image_copy_capture_state.sessions.retain(|session| {
+            let captures_window = session.data::<Mutex<SessionData>>().map_or(false, |data| {
+                matches!(
+                    &data.lock().unwrap().source,
+                    Some(ImageCaptureSource::Toplevel(captured)) if captured == window
+                )
+            });
+            !captures_window || update_session(session, space, render_node)
+        });
+    }
+}
+
+// Sends new constraints if the size of the session's source changed, returns false once it stopped
+fn update_session(
+    session: &ExtImageCopyCaptureSessionV1,
+    space: &Space<Window>,
+    render_node: Option<DrmNode>,
+) -> bool {
+    let data = match session.data::<Mutex<SessionData>>() {
+        Some(data) => data,
+        None => return false,
+    };
+    let mut data = data.lock().unwrap();
+    let size = match data
+        .source
+        .as_ref()
+        .and_then(|source| source.resolve(space))
+    {
+        Some((output, CaptureSource::Output(region))) => capture::buffer_size(&output, region),
+        Some((output, source)) => capture::source_size(&output, &source),
+        None => None,
+    };
+    match size {
+        Some(size) => {
+            if data.size != Some(size) {
+                data.size = Some(size);
+                send_constraints(session, size, render_node.map(|node| node.dev_id()));
+            }
+            true
+        }
+        None => {
+            data.stopped = true;
+            session.stopped();
+            false
+        }
+    }
+}
+
+fn send_constraints(
+    session: &ExtImageCopyCaptureSessionV1,
+    size: Size<i32, Physical>,
+    dmabuf_device: Option<u64>,
+) {
+    session.buffer_size(size.w as u32, size.h as u32);
+    for format in SHM_FORMATS {
+        session.shm_format(format);
+    }
+    if let Some(device) = dmabuf_device {
+        session.dmabuf_device(device.to_ne_bytes().to_vec());
+        // Linear buffers can be allocated and rendered to by every gpu
+        let modifiers = u64::from(Modifier::Linear).to_ne_bytes().to_vec();
+        for format in DMABUF_FORMATS {
+            session.dmabuf_format(format as u32, modifiers.clone());
+        }
+    }
+    session.done();
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ExtImageCopyCaptureManagerV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtImageCopyCaptureManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtImageCopyCaptureManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        manager: &ExtImageCopyCaptureManagerV1,
+        request: ext_image_copy_capture_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_image_copy_capture_manager_v1::Request::CreateSession {
+                session,
+                source,
+                options,
+            } => {
+                let paint_cursors = match options {
+                    WEnum::Value(options) => {
+                        options.contains(ext_image_copy_capture_manager_v1::Options::PaintCursors)
+                    }
+                    WEnum::Unknown(_) => {
+                        manager.post_error(
+                            ext_image_copy_capture_manager_v1::Error::InvalidOption,
+                            "unknown capture option",
+                        );
+                        return;
+                    }
+                };
+                let source = source
+                    .data::<Option<ImageCaptureSource>>()
+                    .cloned()
+                    .flatten();
+                let session = data_init.init(
+                    session,
+                    Mutex::new(SessionData {
+                        source,
+                        paint_cursors,
+                        size: None,
+                        frame: None,
+                        captured: false,
+                        stopped: false,
+                    }),
+                );
+                // Sends the initial constraints, or stops the session if the source is gone
+                state.image_copy_capture_state.sessions.push(session);
+                state.update_capture_sessions();
+            }
+            ext_image_copy_capture_manager_v1::Request::CreatePointerCursorSession {
+                session,
+                ..
+            } => {
+                data_init.init(session, ());
+            }
+            _ => {}
+        }
+    }
+}
+
+// Cursors are only available painted into the captured image, their capture sessions are stopped
+// right away
+impl<B: Backend + 'static> Dispatch<ExtImageCopyCaptureCursorSessionV1, ()> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _cursor_session: &ExtImageCopyCaptureCursorSessionV1,
+        request: ext_image_copy_capture_cursor_session_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_image_copy_capture_cursor_session_v1::Request::GetCaptureSession { session } =
+            request
+        {
+            let session = data_init.init(
+                session,
+                Mutex::new(SessionData {
+                    source: None,
+                    paint_cursors: false,
+                    size: None,
+                    frame: None,
+                    captured: false,
+                    stopped: true,
+                }),
+            );
+            session.stopped();
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtImageCopyCaptureSessionV1, Mutex<SessionData>>
+    for Waysight<B>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        session: &ExtImageCopyCaptureSessionV1,
+        request: ext_image_copy_capture_session_v1::Request,
+        data: &Mutex<SessionData>,
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_image_copy_capture_session_v1::Request::CreateFrame { frame } = request {
+            let mut data = data.lock().unwrap();
+            if data.frame.is_some() {
+                session.post_error(
+                    ext_image_copy_capture_session_v1::Error::DuplicateFrame,
+                    "session already has a frame",
+                );
+                return;
+            }
+            let frame = data_init.init(
+                frame,
+                Mutex::new(FrameData {
+                    session: session.clone(),
+                    buffer: None,
+                    captured: false,
+                }),
+            );
+            data.frame = Some(frame);
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        session: ExtImageCopyCaptureSessionV1,
+        _data: &Mutex<SessionData>,
+    ) {
+        state
+            .image_copy_capture_state
+            .sessions
+            .retain(|tracked| tracked != &session);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtImageCopyCaptureFrameV1, Mutex<FrameData>> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        frame: &ExtImageCopyCaptureFrameV1,
+        request: ext_image_copy_capture_frame_v1::Request,
+        data: &Mutex<FrameData>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let mut data = data.lock().unwrap();
+        match request {
+            ext_image_copy_capture_frame_v1::Request::AttachBuffer { buffer } => {
+                data.buffer = Some(buffer);
+            }
+            ext_image_copy_capture_frame_v1::Request::DamageBuffer {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                // Only a hint which parts of the buffer are stale, every capture redraws all of it
+                if x < 0 || y < 0 || width <= 0 || height <= 0 {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::InvalidBufferDamage,
+                        "damage rectangle is invalid",
+                    );
+                }
+            }
+            ext_image_copy_capture_frame_v1::Request::Capture => {
+                if data.captured {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::AlreadyCaptured,
+                        "frame was already captured",
+                    );
+                    return;
+                }
+                let buffer = match data.buffer.clone() {
+                    Some(buffer) => buffer,
+                    None => {
+                        frame.post_error(
+                            ext_image_copy_capture_frame_v1::Error::NoBuffer,
+                            "no buffer attached",
+                        );
+                        return;
+                    }
+                };
+                data.captured = true;
+
+                let session = match data.session.data::<Mutex<SessionData>>() {
+                    Some(session) => session.lock().unwrap(),
+                    None => return,
+                };
+                let target = match (&session.source, session.stopped) {
+                    (Some(source), false) => source.resolve(&state.space),
+                    _ => None,
+                };
+                let (output, source) = match target {
+                    Some(target) => target,
+                    None => {
+                        frame.failed(ext_image_copy_capture_frame_v1::FailureReason::Stopped);
+                        return;
+                    }
+                };
+                let fits = session
+                    .size
+                    .map_or(false, |size| capture::check_buffer(&buffer, size).is_ok());
+                if !fits {
+                    frame.failed(ext_image_copy_capture_frame_v1::FailureReason::BufferConstraints);
+                    return;
+                }
+
+                state.capture_state.queue(CaptureRequest {
+                    output: output.clone(),
+                    source,
+                    overlay_cursor: session.paint_cursors,
+                    wait_for_damage: session.captured,
+                    target: CaptureTarget::Buffer {
+                        buffer,
+                        frame: CaptureFrame::Ext(Frame(frame.clone())),
+                    },
+                });
+                state.backend_data.schedule_render(&output);
+            }
+            _ => {}
+        }
+    }
+
+    fn destroyed(
+        _state: &mut Self,
+        _client: ClientId,
+        frame: ExtImageCopyCaptureFrameV1,
+        data: &Mutex<FrameData>,
+    ) {
+        let session = data.lock().unwrap().session.clone();
+        if let Some(session_data) = session.data::<Mutex<SessionData>>() {
+            let mut session_data = session_data.lock().unwrap();
+            if session_data.frame.as_ref() == Some(&frame) {
+                session_data.frame = None;
+            }
+        }
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, ()>
+    for Waysight<B>
+{
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtOutputImageCaptureSourceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _manager: &ExtOutputImageCaptureSourceManagerV1,
+        request: ext_output_image_capture_source_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_output_image_capture_source_manager_v1::Request::CreateSource {
+            source,
+            output,
+        } = request
+        {
+            let source_data = Output::from_resource(&output).map(ImageCaptureSource::Output);
+            data_init.init(source, source_data);
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtImageCaptureSourceV1, Option<ImageCaptureSource>>
+    for Waysight<B>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _source: &ExtImageCaptureSourceV1,
+        _request: ext_image_capture_source_v1::Request,
+        _data: &Option<ImageCaptureSource>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>
+    for Waysight<B>
+{
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtForeignToplevelImageCaptureSourceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>
+    for Waysight<B>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _manager: &ExtForeignToplevelImageCaptureSourceManagerV1,
+        request: ext_foreign_toplevel_image_capture_source_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_foreign_toplevel_image_capture_source_manager_v1::Request::CreateSource {
+            source,
+            toplevel_handle,
+        } = request
+        {
+            // Handles of closed windows give a source that is gone from the start
+            let source_data = toplevel_handle
+                .data::<Window>()
+                .filter(|window| window.alive())
+                .cloned()
+                .map(ImageCaptureSource::Toplevel);
+            data_init.init(source, source_data);
+        }
+    }
+}
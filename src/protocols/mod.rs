@@ -0,0 +1,9 @@
+pub mod bindings;
+pub mod foreign_toplevel;
+pub mod idle_inhibit;
+pub mod idle_notify;
+pub mod image_copy_capture;
+pub mod output_management;
+pub mod output_power;
+pub mod screencopy;
+pub mod tearing_control;
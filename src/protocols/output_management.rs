@@ -0,0 +1,682 @@
+// Implementation of wlr-output-management-unstable-v1, used by tools like kanshi, wlr-randr and
+// wdisplays to query and change the output layout
+use std::{collections::HashMap, sync::Mutex};
+
+use smithay::{
+    output::{Mode, Output},
+    reexports::{
+        wayland_protocols_wlr::output_management::v1::server::{
+            zwlr_output_configuration_head_v1::{self, ZwlrOutputConfigurationHeadV1},
+            zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+            zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+            zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+            zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+        },
+        wayland_server::{
+            backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+            Resource, WEnum,
+        },
+    },
+    utils::{Logical, Physical, Point, Size, Transform},
+};
+
+use crate::state::{Backend, Waysight};
+
+const MANAGER_VERSION: u32 = 4;
+// zwlr_output_mode_v1 stopped at version 3
+const MODE_VERSION: u32 = 3;
+
+// An output as seen by output management. Disabled outputs are still listed so clients can
+// enable them again
+#[derive(Clone)]
+pub struct Head {
+    pub output: Output,
+    pub enabled: bool,
+    // Whether variable refresh rate is turned on, even if it only kicks in for fullscreen windows
+    pub adaptive_sync: bool,
+}
+
+// The state of a head at the time it was last advertised, used to only resend what changed
+#[derive(Clone, PartialEq)]
+struct HeadSnapshot {
+    name: String,
+    description: String,
+    physical_size: Size<i32, Physical>,
+    make: String,
+    model: String,
+    modes: Vec<Mode>,
+    preferred_mode: Option<Mode>,
+    enabled: bool,
+    current_mode: Option<Mode>,
+    position: Point<i32, Logical>,
+    transform: Transform,
+    scale: f64,
+    adaptive_sync: bool,
+}
+
+impl From<&Head> for HeadSnapshot {
+    fn from(head: &Head) -> Self {
+        let properties = head.output.physical_properties();
+        HeadSnapshot {
+            name: head.output.name(),
+            description: head.output.description(),
+            physical_size: properties.size,
+            make: properties.make,
+            model: properties.model,
+            modes: head.output.modes(),
+            preferred_mode: head.output.preferred_mode(),
+            enabled: head.enabled,
+            current_mode: head.output.current_mode(),
+            position: head.output.current_location(),
+            transform: head.output.current_transform(),
+            scale: head.output.current_scale().fractional_scale(),
+            adaptive_sync: head.adaptive_sync,
+        }
+    }
+}
+
+// The mode a head should switch to. Custom modes may not be supported by every backend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModeConfiguration {
+    Mode(Mode),
+    Custom {
+        size: Size<i32, Physical>,
+        // In mHz, `None` lets the backend pick
+        refresh: Option<i32>,
+    },
+}
+
+// Requested state of a single head. `None` fields keep their current value
+#[derive(Debug, Clone)]
+pub struct HeadConfiguration {
+    pub output: Output,
+    pub enabled: bool,
+    pub mode: Option<ModeConfiguration>,
+    pub position: Option<Point<i32, Logical>>,
+    pub transform: Option<Transform>,
+    pub scale: Option<f64>,
+    pub adaptive_sync: Option<bool>,
+}
+
+impl HeadConfiguration {
+    fn new(output: Output, enabled: bool) -> Self {
+        HeadConfiguration {
+            output,
+            enabled,
+            mode: None,
+            position: None,
+            transform: None,
+            scale: None,
+            adaptive_sync: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputConfigurationError {
+    #[error("Output {0} does not exist")]
+    UnknownOutput(String),
+    #[error("Output {0} doesn't support the requested mode")]
+    UnsupportedMode(String),
+    #[error("Output {0} can't be disabled")]
+    CannotDisable(String),
+    #[error("Output {0} doesn't support adaptive sync")]
+    AdaptiveSyncUnsupported(String),
+    #[error("Failed to configure output {0}: {1}")]
+    Backend(String, String),
+}
+
+struct HeadInstance {
+    head: ZwlrOutputHeadV1,
+    modes: Vec<(Mode, ZwlrOutputModeV1)>,
+}
+
+struct ManagerInstance {
+    manager: ZwlrOutputManagerV1,
+    // Keyed by output name
+    heads: HashMap<String, HeadInstance>,
+}
+
+pub struct ConfigurationData {
+    serial: u32,
+    used: bool,
+    heads: Vec<(ZwlrOutputHeadV1, Option<ZwlrOutputConfigurationHeadV1>)>,
+}
+
+pub struct OutputManagementState {
+    serial: u32,
+    heads: Vec<(Head, HeadSnapshot)>,
+    managers: Vec<ManagerInstance>,
+}
+
+impl OutputManagementState {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        display_handle.create_global::<Waysight<B>, ZwlrOutputManagerV1, _>(MANAGER_VERSION, ());
+        OutputManagementState {
+            serial: 0,
+            heads: Vec::new(),
+            managers: Vec::new(),
+        }
+    }
+
+    // Sends every change between the last advertised heads and `heads` to the bound clients
+    pub fn update<B: Backend + 'static>(
+        &mut self,
+        display_handle: &DisplayHandle,
+        heads: Vec<Head>,
+    ) {
+        let heads: Vec<(Head, HeadSnapshot)> = heads
+            .into_iter()
+            .map(|head| {
+                let snapshot = HeadSnapshot::from(&head);
+                (head, snapshot)
+            })
+            .collect();
+
+        let unchanged = heads.len() == self.heads.len()
+            && heads
+                .iter()
+                .zip(self.heads.iter())
+                .all(|((_, new), (_, old))| new == old);
+        if unchanged {
+            return;
+        }
+
+        for instance in self.managers.iter_mut() {
+            let client = match display_handle.get_client(instance.manager.id()) {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+
+            // Heads that went away
+            instance.heads.retain(|name, head_instance| {
+                let still_there = heads.iter().any(|(_, snapshot)| &snapshot.name == name);
+                if !still_there {
+                    for (_, mode) in head_instance.modes.drain(..) {
+                        mode.finished();
+                    }
+                    head_instance.head.finished();
+                }
+                still_there
+            });
+
+            for (_, snapshot) in heads.iter() {
+                let previous = self
+                    .heads
+                    .iter()
+                    .find(|(_, old)| old.name == snapshot.name)
+                    .map(|(_, old)| old);
+                send_head::<B>(display_handle, &client, instance, snapshot, previous);
+            }
+        }
+
+        self.heads = heads;
+        self.serial = self.serial.wrapping_add(1);
+        for instance in self.managers.iter() {
+            instance.manager.done(self.serial);
+        }
+    }
+}
+
+// Advertises `snapshot` to a manager. When `previous` is set only the differences are sent
+fn send_head<B: Backend + 'static>(
+    display_handle: &DisplayHandle,
+    client: &Client,
+    instance: &mut ManagerInstance,
+    snapshot: &HeadSnapshot,
+    previous: Option<&HeadSnapshot>,
+) {
+    let is_new = !instance.heads.contains_key(&snapshot.name);
+    let previous = if is_new { None } else { previous };
+
+    if is_new {
+        let head = match client.create_resource::<ZwlrOutputHeadV1, _, Waysight<B>>(
+            display_handle,
+            instance.manager.version(),
+            snapshot.name.clone(),
+        ) {
+            Ok(head) => head,
+            Err(_) => return,
+        };
+        instance.manager.head(&head);
+        head.name(snapshot.name.clone());
+        head.description(snapshot.description.clone());
+        head.physical_size(snapshot.physical_size.w, snapshot.physical_size.h);
+        if head.version() >= 2 {
+            head.make(snapshot.make.clone());
+            head.model(snapshot.model.clone());
+        }
+        instance.heads.insert(
+            snapshot.name.clone(),
+            HeadInstance {
+                head,
+                modes: Vec::new(),
+            },
+        );
+    }
+    let head_instance = instance.heads.get_mut(&snapshot.name).unwrap();
+    let head = head_instance.head.clone();
+
+    if previous.map_or(true, |previous| {
+        previous.modes != snapshot.modes || previous.preferred_mode != snapshot.preferred_mode
+    }) {
+        for (_, mode) in head_instance.modes.drain(..) {
+            mode.finished();
+        }
+        for mode in snapshot.modes.iter() {
+            let mode_resource = match client.create_resource::<ZwlrOutputModeV1, _, Waysight<B>>(
+                display_handle,
+                head.version().min(MODE_VERSION),
+                *mode,
+            ) {
+                Ok(mode_resource) => mode_resource,
+                Err(_) => continue,
+            };
+            head.mode(&mode_resource);
+            mode_resource.size(mode.size.w, mode.size.h);
+            mode_resource.refresh(mode.refresh);
+            if snapshot.preferred_mode == Some(*mode) {
+                mode_resource.preferred();
+            }
+            head_instance.modes.push((*mode, mode_resource));
+        }
+    }
+
+    if previous.map_or(true, |previous| previous.enabled != snapshot.enabled) {
+        head.enabled(snapshot.enabled as i32);
+    }
+    // The remaining properties are only meaningful for enabled heads
+    if !snapshot.enabled {
+        return;
+    }
+    let state_changed = |previous: &HeadSnapshot| previous.enabled != snapshot.enabled;
+
+    if previous.map_or(true, |previous| {
+        state_changed(previous)
+            || previous.current_mode != snapshot.current_mode
+            || previous.modes != snapshot.modes
+    }) {
+        if let Some(current_mode) = snapshot.current_mode {
+            if let Some((_, mode)) = head_instance
+                .modes
+                .iter()
+                .find(|(mode, _)| *mode == current_mode)
+            {
+                head.current_mode(mode);
+            }
+        }
+    }
+    if previous.map_or(true, |previous| {
+        state_changed(previous) || previous.position != snapshot.position
+    }) {
+        head.position(snapshot.position.x, snapshot.position.y);
+    }
+    if previous.map_or(true, |previous| {
+        state_changed(previous) || previous.transform != snapshot.transform
+    }) {
+        head.transform(snapshot.transform.into());
+    }
+    if previous.map_or(true, |previous| {
+        state_changed(previous) || previous.scale != snapshot.scale
+    }) {
+        head.scale(snapshot.scale);
+    }
+    if head.version() >= 4
+        && previous.map_or(true, |previous| {
+            state_changed(previous) || previous.adaptive_sync != snapshot.adaptive_sync
+        })
+    {
+        head.adaptive_sync(if snapshot.adaptive_sync {
+            zwlr_output_head_v1::AdaptiveSyncState::Enabled
+        } else {
+            zwlr_output_head_v1::AdaptiveSyncState::Disabled
+        });
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // Re-reads the outputs from the backend and notifies output management clients of changes
+    pub fn update_output_heads(&mut self) {
+        let heads = self.backend_data.heads();
+        self.output_management_state
+            .update::<B>(&self.display_handle, heads);
+    }
+
+    fn apply_head_configuration(&mut self, heads: Vec<HeadConfiguration>, test_only: bool) -> bool {
+        let result =
+            self.backend_data
+                .apply_output_configuration(&self.display_handle, &heads, test_only);
+        match &result {
+            Ok(()) if test_only => tracing::debug!("Output configuration test succeeded"),
+            Ok(()) => tracing::info!("Applied output configuration"),
+            Err(err) => tracing::warn!("Output configuration rejected: {}", err),
+        }
+        if !test_only {
+            self.outputs_changed();
+        }
+        result.is_ok()
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ZwlrOutputManagerV1, ()> for Waysight<B> {
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        client: &Client,
+        resource: New<ZwlrOutputManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let manager = data_init.init(resource, ());
+        let mut instance = ManagerInstance {
+            manager,
+            heads: HashMap::new(),
+        };
+        let management_state = &mut state.output_management_state;
+        for (_, snapshot) in management_state.heads.iter() {
+            send_head::<B>(handle, client, &mut instance, snapshot, None);
+        }
+        instance.manager.done(management_state.serial);
+        management_state.managers.push(instance);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        manager: &ZwlrOutputManagerV1,
+        request: zwlr_output_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            zwlr_output_manager_v1::Request::CreateConfiguration { id, serial } => {
+                data_init.init(
+                    id,
+                    Mutex::new(ConfigurationData {
+                        serial,
+                        used: false,
+                        heads: Vec::new(),
+                    }),
+                );
+            }
+            zwlr_output_manager_v1::Request::Stop => {
+                manager.finished();
+                state
+                    .output_management_state
+                    .managers
+                    .retain(|instance| &instance.manager != manager);
+            }
+            _ => {}
+        }
+    }
+
+    fn destroyed(state: &mut Self, _client: ClientId, manager: ZwlrOutputManagerV1, _data: &()) {
+        state
+            .output_management_state
+            .managers
+            .retain(|instance| instance.manager != manager);
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputHeadV1, String> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        head: &ZwlrOutputHeadV1,
+        request: zwlr_output_head_v1::Request,
+        name: &String,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_output_head_v1::Request::Release = request {
+            for instance in state.output_management_state.managers.iter_mut() {
+                if instance
+                    .heads
+                    .get(name)
+                    .map_or(false, |instance| &instance.head == head)
+                {
+                    instance.heads.remove(name);
+                }
+            }
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputModeV1, Mode> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _mode: &ZwlrOutputModeV1,
+        _request: zwlr_output_mode_v1::Request,
+        _data: &Mode,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputConfigurationV1, Mutex<ConfigurationData>>
+    for Waysight<B>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        configuration: &ZwlrOutputConfigurationV1,
+        request: zwlr_output_configuration_v1::Request,
+        data: &Mutex<ConfigurationData>,
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let mut data = data.lock().unwrap();
+        let test_only = match request {
+            zwlr_output_configuration_v1::Request::EnableHead { id, head } => {
+                if data.heads.iter().any(|(configured, _)| configured == &head) {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyConfiguredHead,
+                        "head has been configured twice",
+                    );
+                    return;
+                }
+                let output = match state.head_output(&head) {
+                    Some(output) => output,
+                    None => {
+                        // The head is gone, the serial check below will cancel the configuration
+                        data_init.init(id, Mutex::new(None));
+                        return;
+                    }
+                };
+                let configuration_head =
+                    data_init.init(id, Mutex::new(Some(HeadConfiguration::new(output, true))));
+                data.heads.push((head, Some(configuration_head)));
+                return;
+            }
+            zwlr_output_configuration_v1::Request::DisableHead { head } => {
+                if data.heads.iter().any(|(configured, _)| configured == &head) {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyConfiguredHead,
+                        "head has been configured twice",
+                    );
+                    return;
+                }
+                data.heads.push((head, None));
+                return;
+            }
+            zwlr_output_configuration_v1::Request::Apply => false,
+            zwlr_output_configuration_v1::Request::Test => true,
+            _ => return,
+        };
+
+        if data.used {
+            configuration.post_error(
+                zwlr_output_configuration_v1::Error::AlreadyUsed,
+                "configuration has already been applied or tested",
+            );
+            return;
+        }
+        data.used = true;
+
+        if data.serial != state.output_management_state.serial {
+            configuration.cancelled();
+            return;
+        }
+
+        let mut heads = Vec::new();
+        for (head, configuration_head) in data.heads.iter() {
+            let head_configuration = match configuration_head {
+                Some(configuration_head) => configuration_head
+                    .data::<Mutex<Option<HeadConfiguration>>>()
+                    .and_then(|data| data.lock().unwrap().clone()),
+                None => state
+                    .head_output(head)
+                    .map(|output| HeadConfiguration::new(output, false)),
+            };
+            match head_configuration {
+                Some(head_configuration) => heads.push(head_configuration),
+                None => {
+                    configuration.cancelled();
+                    return;
+                }
+            }
+        }
+
+        if state.apply_head_configuration(heads, test_only) {
+            configuration.succeeded();
+        } else {
+            configuration.failed();
+        }
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    fn head_output(&self, head: &ZwlrOutputHeadV1) -> Option<Output> {
+        let name = head.data::<String>()?;
+        self.output_management_state
+            .heads
+            .iter()
+            .find(|(head, _)| &head.output.name() == name)
+            .map(|(head, _)| head.output.clone())
+    }
+}
+
+// `None` when the head was already gone when it got enabled
+impl<B: Backend + 'static> Dispatch<ZwlrOutputConfigurationHeadV1, Mutex<Option<HeadConfiguration>>>
+    for Waysight<B>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        configuration_head: &ZwlrOutputConfigurationHeadV1,
+        request: zwlr_output_configuration_head_v1::Request,
+        data: &Mutex<Option<HeadConfiguration>>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let mut data = data.lock().unwrap();
+        let head = match data.as_mut() {
+            Some(head) => head,
+            None => return,
+        };
+
+        let already_set = |configuration_head: &ZwlrOutputConfigurationHeadV1| {
+            configuration_head.post_error(
+                zwlr_output_configuration_head_v1::Error::AlreadySet,
+                "property has already been set",
+            );
+        };
+
+        match request {
+            zwlr_output_configuration_head_v1::Request::SetMode { mode } => {
+                if head.mode.is_some() {
+                    return already_set(configuration_head);
+                }
+                let mode = match mode.data::<Mode>() {
+                    Some(mode) if head.output.modes().contains(mode) => *mode,
+                    _ => {
+                        configuration_head.post_error(
+                            zwlr_output_configuration_head_v1::Error::InvalidMode,
+                            "mode doesn't belong to head",
+                        );
+                        return;
+                    }
+                };
+                head.mode = Some(ModeConfiguration::Mode(mode));
+            }
+            zwlr_output_configuration_head_v1::Request::SetCustomMode {
+                width,
+                height,
+                refresh,
+            } => {
+                if head.mode.is_some() {
+                    return already_set(configuration_head);
+                }
+                if width <= 0 || height <= 0 || refresh < 0 {
+                    configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidCustomMode,
+                        "mode is invalid",
+                    );
+                    return;
+                }
+                head.mode = Some(ModeConfiguration::Custom {
+                    size: (width, height).into(),
+                    refresh: (refresh != 0).then_some(refresh),
+                });
+            }
+            zwlr_output_configuration_head_v1::Request::SetPosition { x, y } => {
+                if head.position.is_some() {
+                    return already_set(configuration_head);
+                }
+                head.position = Some((x, y).into());
+            }
+            zwlr_output_configuration_head_v1::Request::SetTransform { transform } => {
+                if head.transform.is_some() {
+                    return already_set(configuration_head);
+                }
+                let transform = match transform {
+                    WEnum::Value(transform) => Transform::from(transform),
+                    WEnum::Unknown(_) => {
+                        configuration_head.post_error(
+                            zwlr_output_configuration_head_v1::Error::InvalidTransform,
+                            "transform value outside enum",
+                        );
+                        return;
+                    }
+                };
+                head.transform = Some(transform);
+            }
+            zwlr_output_configuration_head_v1::Request::SetScale { scale } => {
+                if head.scale.is_some() {
+                    return already_set(configuration_head);
+                }
+                if scale <= 0.0 {
+                    configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidScale,
+                        "scale negative or zero",
+                    );
+                    return;
+                }
+                head.scale = Some(scale);
+            }
+            zwlr_output_configuration_head_v1::Request::SetAdaptiveSync { state } => {
+                if head.adaptive_sync.is_some() {
+                    return already_set(configuration_head);
+                }
+                let enabled = match state {
+                    WEnum::Value(zwlr_output_head_v1::AdaptiveSyncState::Enabled) => true,
+                    WEnum::Value(zwlr_output_head_v1::AdaptiveSyncState::Disabled) => false,
+                    _ => {
+                        configuration_head.post_error(
+                            zwlr_output_configuration_head_v1::Error::InvalidAdaptiveSyncState,
+                            "adaptive sync state value outside enum",
+                        );
+                        return;
+                    }
+                };
+                head.adaptive_sync = Some(enabled);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Implementation of wlr-output-power-management-unstable-v1, used by swayidle to turn screens off.
+// Outputs that are off stay in the layout, they just aren't repainted
+use serde_json::{json, Value};
+use smithay::{
+    output::Output,
+    reexports::{
+        wayland_protocols_wlr::output_power_management::v1::server::{
+            zwlr_output_power_manager_v1::{self, ZwlrOutputPowerManagerV1},
+            zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+        },
+        wayland_server::{
+            backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+            Resource, WEnum,
+        },
+    },
+};
+
+use crate::{
+    ipc,
+    state::{Backend, Waysight},
+};
+
+const MANAGER_VERSION: u32 = 1;
+
+pub struct OutputPowerState {
+    powers: Vec<ZwlrOutputPowerV1>,
+    // Outputs that were turned off
+    off: Vec<Output>,
+}
+
+impl OutputPowerState {
+    pub fn new<B: Backend + 'static>(display_handle: &DisplayHandle) -> Self {
+        display_handle
+            .create_global::<Waysight<B>, ZwlrOutputPowerManagerV1, _>(MANAGER_VERSION, ());
+        OutputPowerState {
+            powers: Vec::new(),
+            off: Vec::new(),
+        }
+    }
+}
+
+fn mode(on: bool) -> zwlr_output_power_v1::Mode {
+    if on {
+        zwlr_output_power_v1::Mode::On
+    } else {
+        zwlr_output_power_v1::Mode::Off
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    pub fn output_powered(&self, output: &Output) -> bool {
+        !self.output_power_state.off.contains(output)
+    }
+
+    // Returns false if the backend can't turn `output` off
+    pub fn set_output_power(&mut self, output: &Output, on: bool) -> bool {
+        if !self.backend_data.set_output_power(output, on) {
+            return false;
+        }
+        let was_on = self.output_powered(output);
+        let state = &mut self.output_power_state;
+        if on {
+            state.off.retain(|off| off != output);
+        } else if was_on {
+            state.off.push(output.clone());
+        }
+        if was_on != on {
+            for power in state
+                .powers
+                .iter()
+                .filter(|power| match power.data::<Option<Output>>() {
+                    Some(Some(tracked)) => tracked == output,
+                    _ => false,
+                })
+            {
+                power.mode(mode(on));
+            }
+        }
+        if on {
+            self.backend_data.schedule_render(output);
+        } else {
+            // Nothing is repainted, captures of the output would wait forever
+            self.capture_state.fail_output(output);
+        }
+        true
+    }
+
+    // Turns every output that is off back on, for `input.wake_outputs`
+    pub fn wake_outputs(&mut self) {
+        let off = self.output_power_state.off.clone();
+        for output in off.iter() {
+            self.set_output_power(output, true);
+        }
+    }
+
+    // Called after outputs changed. Power objects of outputs that went away fail, and outputs that
+    // are off stay off after being reconfigured
+    pub fn update_output_power(&mut self) {
+        let outputs: Vec<Output> = self.space.outputs().cloned().collect();
+        let state = &mut self.output_power_state;
+        state.off.retain(|output| outputs.contains(output));
+        state
+            .powers
+            .retain(|power| match power.data::<Option<Output>>() {
+                Some(Some(output)) if outputs.contains(output) => true,
+                _ => {
+                    power.failed();
+                    false
+                }
+            });
+        for output in state.off.iter() {
+            self.backend_data.set_output_power(output, false);
+        }
+    }
+
+    // `power on|off|toggle [output]`. Without an output all of them are switched, toggling turns
+    // them all off if any is on
+    pub fn power_command<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Value {
+        let action = args.next();
+        let outputs: Vec<Output> = match args.next() {
+            Some(name) => match self.find_output(Some(name)) {
+                Some(output) => vec![output],
+                None => return ipc::error("no such output"),
+            },
+            None => self.space.outputs().cloned().collect(),
+        };
+        let on = match action {
+            Some("on") => true,
+            Some("off") => false,
+            Some("toggle") => !outputs.iter().any(|output| self.output_powered(output)),
+            _ => return ipc::error("usage: power on|off|toggle [output]"),
+        };
+        for output in outputs.iter() {
+            if !self.set_output_power(output, on) {
+                return ipc::error(&format!("{} can't be turned on or off", output.name()));
+            }
+        }
+        let states: Vec<Value> = outputs
+            .iter()
+            .map(|output| json!({ "output": output.name(), "on": self.output_powered(output) }))
+            .collect();
+        json!({ "outputs": states })
+    }
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ZwlrOutputPowerManagerV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrOutputPowerManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputPowerManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwlrOutputPowerManagerV1,
+        request: zwlr_output_power_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_output_power_manager_v1::Request::GetOutputPower { id, output } = request {
+            let output = Output::from_resource(&output)
+                .filter(|output| state.space.outputs().any(|mapped| mapped == output));
+            let power = data_init.init(id, output.clone());
+            match output {
+                Some(output) => {
+                    power.mode(mode(state.output_powered(&output)));
+                    state.output_power_state.powers.push(power);
+                }
+                None => power.failed(),
+            }
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrOutputPowerV1, Option<Output>> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        power: &ZwlrOutputPowerV1,
+        request: zwlr_output_power_v1::Request,
+        output: &Option<Output>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let mode = match request {
+            zwlr_output_power_v1::Request::SetMode { mode } => mode,
+            _ => return,
+        };
+        // Failed objects are inert
+        let output = match output {
+            Some(output) if state.output_power_state.powers.contains(power) => output,
+            _ => return,
+        };
+        let on = match mode {
+            WEnum::Value(zwlr_output_power_v1::Mode::On) => true,
+            WEnum::Value(zwlr_output_power_v1::Mode::Off) => false,
+            _ => {
+                power.post_error(
+                    zwlr_output_power_v1::Error::InvalidMode,
+                    "unknown power mode",
+                );
+                return;
+            }
+        };
+        if !state.set_output_power(output, on) {
+            power.failed();
+            state
+                .output_power_state
+                .powers
+                .retain(|tracked| tracked != power);
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        power: ZwlrOutputPowerV1,
+        _data: &Option<Output>,
+    ) {
+        state
+            .output_power_state
+            .powers
+            .retain(|tracked| tracked != &power);
+    }
+}
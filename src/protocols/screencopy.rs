@@ -0,0 +1,236 @@
+// Implementation of wlr-screencopy-unstable-v1, used by grim, wf-recorder and most screen sharing
+// portals on wlroots-like compositors
+use std::{sync::Mutex, time::Duration};
+
+use smithay::{
+    output::Output,
+    reexports::{
+        wayland_protocols_wlr::screencopy::v1::server::{
+            zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+            zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+        },
+        wayland_server::{
+            protocol::wl_output::WlOutput, Client, DataInit, Dispatch, DisplayHandle,
+            GlobalDispatch, New, Resource,
+        },
+    },
+    utils::{Logical, Physical, Rectangle, Size},
+};
+
+use crate::{
+    capture::{
+        self, CaptureFrame, CaptureRequest, CaptureSource, CaptureTarget, DMABUF_FORMATS,
+        SHM_FORMATS,
+    },
+    state::{Backend, Waysight},
+};
+
+const MANAGER_VERSION: u32 = 3;
+
+pub struct FrameData {
+    // `None` if the output was gone when the frame was created, the frame has failed then
+    output: Option<Output>,
+    region: Option<Rectangle<i32, Logical>>,
+    overlay_cursor: bool,
+    size: Size<i32, Physical>,
+    used: bool,
+}
+
+// A frame waiting in the capture queue
+pub struct Frame(ZwlrScreencopyFrameV1, bool);
+
+impl Frame {
+    pub fn ready(&self, size: Size<i32, Physical>, time: Duration) {
+        let Frame(frame, with_damage) = self;
+        frame.flags(zwlr_screencopy_frame_v1::Flags::empty());
+        // The whole buffer counts as damaged, the frame is only sent once something changed
+        if *with_damage {
+            frame.damage(0, 0, size.w as u32, size.h as u32);
+        }
+        frame.ready(
+            (time.as_secs() >> 32) as u32,
+            time.as_secs() as u32,
+            time.subsec_nanos(),
+        );
+    }
+
+    pub fn failed(&self) {
+        self.0.failed();
+    }
+}
+
+pub fn init<B: Backend + 'static>(display_handle: &DisplayHandle) {
+    display_handle.create_global::<Waysight<B>, ZwlrScreencopyManagerV1, _>(MANAGER_VERSION, ());
+}
+
+impl<B: Backend + 'static> GlobalDispatch<ZwlrScreencopyManagerV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrScreencopyManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrScreencopyManagerV1, ()> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwlrScreencopyManagerV1,
+        request: zwlr_screencopy_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (frame, overlay_cursor, wl_output, region) = match request {
+            zwlr_screencopy_manager_v1::Request::CaptureOutput {
+                frame,
+                overlay_cursor,
+                output,
+            } => (frame, overlay_cursor, output, None),
+            zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+                frame,
+                overlay_cursor,
+                output,
+                x,
+                y,
+                width,
+                height,
+            } => (
+                frame,
+                overlay_cursor,
+                output,
+                Some(Rectangle::from_loc_and_size((x, y), (width, height))),
+            ),
+            _ => return,
+        };
+        state.create_frame(data_init, frame, overlay_cursor != 0, &wl_output, region);
+    }
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    fn create_frame(
+        &mut self,
+        data_init: &mut DataInit<'_, Self>,
+        frame: New<ZwlrScreencopyFrameV1>,
+        overlay_cursor: bool,
+        wl_output: &WlOutput,
+        region: Option<Rectangle<i32, Logical>>,
+    ) {
+        let target = Output::from_resource(wl_output)
+            .filter(|output| self.space.outputs().any(|mapped| mapped == output))
+            .and_then(|output| {
+                // Regions are clipped to the output, one entirely outside of it can't be captured
+                let region =
+                    match region {
+                        Some(region) => {
+                            let geometry = self.space.output_geometry(&output)?;
+                            Some(region.intersection(Rectangle::from_loc_and_size(
+                                (0, 0),
+                                geometry.size,
+                            ))?)
+                        }
+                        None => None,
+                    };
+                let size = capture::buffer_size(&output, region)
+                    .filter(|size| size.w > 0 && size.h > 0)?;
+                Some((output, region, size))
+            });
+
+        let (output, region, size) = match target {
+            Some(target) => target,
+            None => {
+                let frame = data_init.init(
+                    frame,
+                    Mutex::new(FrameData {
+                        output: None,
+                        region: None,
+                        overlay_cursor,
+                        size: Size::default(),
+                        used: true,
+                    }),
+                );
+                frame.failed();
+                return;
+            }
+        };
+
+        let frame = data_init.init(
+            frame,
+            Mutex::new(FrameData {
+                output: Some(output),
+                region,
+                overlay_cursor,
+                size,
+                used: false,
+            }),
+        );
+        for format in SHM_FORMATS {
+            frame.buffer(format, size.w as u32, size.h as u32, size.w as u32 * 4);
+        }
+        if frame.version() >= 3 {
+            if self.backend_data.render_node().is_some() {
+                for format in DMABUF_FORMATS {
+                    frame.linux_dmabuf(format as u32, size.w as u32, size.h as u32);
+                }
+            }
+            frame.buffer_done();
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<ZwlrScreencopyFrameV1, Mutex<FrameData>> for Waysight<B> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        frame: &ZwlrScreencopyFrameV1,
+        request: zwlr_screencopy_frame_v1::Request,
+        data: &Mutex<FrameData>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (buffer, with_damage) = match request {
+            zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+            zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+            _ => return,
+        };
+
+        let mut data = data.lock().unwrap();
+        // Frames without an output already failed
+        let output = match data.output.clone() {
+            Some(output) => output,
+            None => return,
+        };
+        if data.used {
+            frame.post_error(
+                zwlr_screencopy_frame_v1::Error::AlreadyUsed,
+                "frame was already used",
+            );
+            return;
+        }
+        data.used = true;
+        if let Err(err) = capture::check_buffer(&buffer, data.size) {
+            frame.post_error(
+                zwlr_screencopy_frame_v1::Error::InvalidBuffer,
+                err.to_string(),
+            );
+            return;
+        }
+
+        state.capture_state.queue(CaptureRequest {
+            output: output.clone(),
+            source: CaptureSource::Output(data.region),
+            overlay_cursor: data.overlay_cursor,
+            wait_for_damage: with_damage,
+            target: CaptureTarget::Buffer {
+                buffer,
+                frame: CaptureFrame::Wlr(Frame(frame.clone(), with_damage)),
+            },
+        });
+        state.backend_data.schedule_render(&output);
+    }
+}
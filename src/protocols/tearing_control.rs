@@ -0,0 +1,147 @@
+// Implementation of tearing-control-v1, lets clients (mostly games) hint that they prefer their
+// frames to be shown as soon as possible over waiting for the vblank. The hint is advisory. It is
+// stored per surface and reported in the output stats, but async page flips are not honoured yet:
+// the drm compositor only does vsynced commits, so every frame still waits for the vblank
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use smithay::{
+    reexports::{
+        wayland_protocols::wp::tearing_control::v1::server::{
+            wp_tearing_control_manager_v1::{self, WpTearingControlManagerV1},
+            wp_tearing_control_v1::{self, WpTearingControlV1},
+        },
+        wayland_server::{
+            backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+            DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+        },
+    },
+    wayland::compositor::{with_states, Cacheable},
+};
+
+use crate::state::{Backend, Waysight};
+
+const MANAGER_VERSION: u32 = 1;
+
+// The hint is double buffered, it applies with the next commit of the surface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PresentationHint {
+    #[default]
+    Vsync,
+    Async,
+}
+
+impl Cacheable for PresentationHint {
+    fn commit(&mut self, _display_handle: &DisplayHandle) -> Self {
+        *self
+    }
+
+    fn merge_into(self, into: &mut Self, _display_handle: &DisplayHandle) {
+        *into = self;
+    }
+}
+
+// Marks surfaces that already have a tearing control object, only one is allowed per surface
+#[derive(Default)]
+struct TearingControlExists(AtomicBool);
+
+// The hint of the last commit of `surface`
+pub fn presentation_hint(surface: &WlSurface) -> PresentationHint {
+    with_states(surface, |states| {
+        *states.cached_state.current::<PresentationHint>()
+    })
+}
+
+pub fn init<B: Backend + 'static>(display_handle: &DisplayHandle) {
+    display_handle.create_global::<Waysight<B>, WpTearingControlManagerV1, _>(MANAGER_VERSION, ());
+}
+
+impl<B: Backend + 'static> GlobalDispatch<WpTearingControlManagerV1, ()> for Waysight<B> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WpTearingControlManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<WpTearingControlManagerV1, ()> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        manager: &WpTearingControlManagerV1,
+        request: wp_tearing_control_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wp_tearing_control_manager_v1::Request::GetTearingControl { id, surface } = request {
+            let exists = with_states(&surface, |states| {
+                states
+                    .data_map
+                    .insert_if_missing_threadsafe(TearingControlExists::default);
+                states
+                    .data_map
+                    .get::<TearingControlExists>()
+                    .unwrap()
+                    .0
+                    .swap(true, Ordering::SeqCst)
+            });
+            if exists {
+                manager.post_error(
+                    wp_tearing_control_manager_v1::Error::TearingControlExists,
+                    "surface already has a tearing control object",
+                );
+                return;
+            }
+            data_init.init(id, surface);
+        }
+    }
+}
+
+impl<B: Backend + 'static> Dispatch<WpTearingControlV1, WlSurface> for Waysight<B> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _control: &WpTearingControlV1,
+        request: wp_tearing_control_v1::Request,
+        surface: &WlSurface,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wp_tearing_control_v1::Request::SetPresentationHint { hint } = request {
+            let hint = match hint {
+                WEnum::Value(wp_tearing_control_v1::PresentationHint::Async) => {
+                    PresentationHint::Async
+                }
+                _ => PresentationHint::Vsync,
+            };
+            if surface.is_alive() {
+                with_states(surface, |states| {
+                    *states.cached_state.pending::<PresentationHint>() = hint;
+                });
+            }
+        }
+    }
+
+    // Destroying the object resets the hint with the next commit
+    fn destroyed(
+        _state: &mut Self,
+        _client: ClientId,
+        _control: WpTearingControlV1,
+        surface: &WlSurface,
+    ) {
+        if !surface.is_alive() {
+            return;
+        }
+        with_states(surface, |states| {
+            *states.cached_state.pending::<PresentationHint>() = PresentationHint::Vsync;
+            if let Some(exists) = states.data_map.get::<TearingControlExists>() {
+                exists.0.store(false, Ordering::SeqCst);
+            }
+        });
+    }
+}
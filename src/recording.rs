@@ -0,0 +1,303 @@
+// Recording of an output to an uncompressed video file. Only frames that changed something are
+// written, their times go to a timestamps file next to it so the video can be encoded with the
+// right timing later, e.g. `mkvmerge --timestamps 0:<file>.timestamps`. Frames are converted and
+// written on a worker thread so a slow disk doesn't hold up repaints
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use smithay::{
+    output::Output,
+    utils::{Physical, Size},
+};
+
+use crate::{
+    capture, ipc,
+    state::{Backend, Waysight, CONFIG},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordingFormat {
+    // YUV 4:4:4 frames with a small header, readable by ffmpeg, mpv and most encoders
+    #[default]
+    #[serde(rename = "y4m")]
+    Y4m,
+    // Bare BGRA frames, the size is only known from the ipc reply
+    #[serde(rename = "raw")]
+    Raw,
+}
+
+// Frames waiting for the worker. When it falls this far behind new frames are dropped
+const FRAME_QUEUE: usize = 4;
+
+pub struct Recording {
+    pub output: Output,
+    pub path: PathBuf,
+    pub timestamps_path: PathBuf,
+    pub format: RecordingFormat,
+    pub size: Size<i32, Physical>,
+    // Frames handed to the worker
+    pub frames: u64,
+    // Frames dropped because the worker fell behind
+    pub dropped: u64,
+    // Frames dropped since the worker last took one, to log once per stall
+    behind: u64,
+    sender: Option<SyncSender<(Vec<u8>, Duration)>>,
+    worker: Option<JoinHandle<io::Result<()>>>,
+}
+
+// The worker's half, owns the files
+struct Writer {
+    format: RecordingFormat,
+    video: BufWriter<File>,
+    timestamps: BufWriter<File>,
+    // Time of the first frame, the timestamps are relative to it
+    start: Option<Duration>,
+}
+
+impl Recording {
+    pub fn new(output: &Output, path: PathBuf, format: RecordingFormat) -> io::Result<Self> {
+        let size = capture::upright_size(output, None)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "output has no mode"))?;
+        let timestamps_path = path.with_extension("timestamps");
+        let mut video = BufWriter::new(File::create(&path)?);
+        let mut timestamps = BufWriter::new(File::create(&timestamps_path)?);
+        if format == RecordingFormat::Y4m {
+            // The nominal frame rate is the refresh rate, the timestamps have the real timing
+            let refresh = output
+                .current_mode()
+                .map(|mode| mode.refresh)
+                .filter(|refresh| *refresh > 0)
+                .unwrap_or(60_000);
+            writeln!(
+                video,
+                "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444",
+                size.w, size.h, refresh
+            )?;
+        }
+        // Matroska timestamp format v2: one time in milliseconds per frame
+        writeln!(timestamps, "# timestamp format v2")?;
+        let mut writer = Writer {
+            format,
+            video,
+            timestamps,
+            start: None,
+        };
+        let (sender, receiver) = mpsc::sync_channel::<(Vec<u8>, Duration)>(FRAME_QUEUE);
+        let worker = thread::Builder::new()
+            .name("recording".to_owned())
+            .spawn(move || {
+                for (pixels, time) in receiver {
+                    writer.write_frame(&pixels, time)?;
+                }
+                writer.finish()
+            })?;
+        Ok(Recording {
+            output: output.clone(),
+            path,
+            timestamps_path,
+            format,
+            size,
+            frames: 0,
+            dropped: 0,
+            behind: 0,
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    // `pixels` are tightly packed Argb8888 rows of `size`. The frame is dropped if the worker is
+    // still busy with earlier ones, errors are those of the worker, which stopped
+    pub fn write_frame(&mut self, pixels: Vec<u8>, time: Duration) -> io::Result<()> {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "recording finished")),
+        };
+        match sender.try_send((pixels, time)) {
+            Ok(()) => {
+                if self.behind > 0 {
+                    tracing::warn!(
+                        "Recording of {} caught up after dropping {} frames",
+                        self.output.name(),
+                        self.behind
+                    );
+                    self.behind = 0;
+                }
+                self.frames += 1;
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                if self.behind == 0 {
+                    tracing::warn!(
+                        "Recording of {} can't keep up, dropping frames",
+                        self.output.name()
+                    );
+                }
+                self.behind += 1;
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(self
+                .finish()
+                .err()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "recording stopped"))),
+        }
+    }
+
+    // Waits for the worker to write the queued frames
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.sender.take();
+        match self.worker.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the recording thread panicked",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Writer {
+    fn write_frame(&mut self, pixels: &[u8], time: Duration) -> io::Result<()> {
+        let start = *self.start.get_or_insert(time);
+        match self.format {
+            RecordingFormat::Raw => self.video.write_all(pixels)?,
+            RecordingFormat::Y4m => {
+                self.video.write_all(b"FRAME\n")?;
+                self.video.write_all(&to_yuv444(pixels))?;
+            }
+        }
+        let elapsed = time.saturating_sub(start);
+        writeln!(
+            self.timestamps,
+            "{}.{:03}",
+            elapsed.as_millis(),
+            elapsed.subsec_micros() % 1000
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.video.flush()?;
+        self.timestamps.flush()
+    }
+}
+
+// Planar BT.601 limited range, the Y4M default
+fn to_yuv444(pixels: &[u8]) -> Vec<u8> {
+    let count = pixels.len() / 4;
+    let mut yuv = vec![0u8; count * 3];
+    let (y_plane, chroma) = yuv.split_at_mut(count);
+    let (u_plane, v_plane) = chroma.split_at_mut(count);
+    // Argb8888 is stored as B, G, R, A in memory
+    for (index, pixel) in pixels.chunks_exact(4).enumerate() {
+        let (b, g, r) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+        y_plane[index] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        u_plane[index] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        v_plane[index] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    yuv
+}
+
+fn describe(recording: &Recording) -> Value {
+    json!({
+        "output": recording.output.name(),
+        "path": path_string(&recording.path),
+        "timestamps": path_string(&recording.timestamps_path),
+        "format": recording.format,
+        "width": recording.size.w,
+        "height": recording.size.h,
+        "frames": recording.frames,
+        "dropped": recording.dropped,
+    })
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // `record start [output]`, `record stop` and `record status`. Recordings default to the
+    // output under the pointer, only one runs at a time
+    pub fn record_command<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Value {
+        match args.next() {
+            Some("start") => {
+                let output = match self.find_output(args.next()) {
+                    Some(output) => output,
+                    None => return ipc::error("no such output"),
+                };
+                let (dir, format) = {
+                    let config = CONFIG.read().unwrap();
+                    (
+                        config.capture.recording_dir.clone(),
+                        config.capture.recording_format,
+                    )
+                };
+                let extension = match format {
+                    RecordingFormat::Y4m => "y4m",
+                    RecordingFormat::Raw => "raw",
+                };
+                let recording =
+                    capture::output_path(dir, "XDG_VIDEOS_DIR", "Videos", "recording", extension)
+                        .and_then(|path| Recording::new(&output, path, format));
+                match recording {
+                    Ok(recording) => {
+                        let reply = describe(&recording);
+                        self.capture_state.start_recording(recording);
+                        self.backend_data.schedule_render(&output);
+                        reply
+                    }
+                    Err(err) => ipc::error(&format!("failed to start recording: {}", err)),
+                }
+            }
+            Some("stop") => match self.capture_state.stop_recording() {
+                Some(recording) => describe(&recording),
+                None => ipc::error("not recording"),
+            },
+            Some("status") => match self.capture_state.recording() {
+                Some(recording) => describe(recording),
+                None => json!({ "recording": false }),
+            },
+            _ => ipc::error("usage: record start [output] | record stop | record status"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One Argb8888 pixel, stored as B, G, R, A
+    fn pixel(r: u8, g: u8, b: u8) -> [u8; 4] {
+        [b, g, r, 0xff]
+    }
+
+    #[test]
+    fn converts_to_limited_range() {
+        assert_eq!(to_yuv444(&pixel(0, 0, 0)), [16, 128, 128]);
+        assert_eq!(to_yuv444(&pixel(255, 255, 255)), [235, 128, 128]);
+        assert_eq!(to_yuv444(&pixel(255, 0, 0)), [82, 90, 240]);
+        assert_eq!(to_yuv444(&pixel(0, 255, 0)), [144, 54, 34]);
+        assert_eq!(to_yuv444(&pixel(0, 0, 255)), [41, 240, 110]);
+    }
+
+    #[test]
+    fn planes_are_separate() {
+        let pixels = [pixel(0, 0, 0), pixel(255, 255, 255), pixel(255, 0, 0)].concat();
+        assert_eq!(
+            to_yuv444(&pixels),
+            [16, 235, 82, 128, 128, 90, 128, 128, 240]
+        );
+        // Alpha is ignored
+        assert_eq!(to_yuv444(&[0, 0, 0, 0]), [16, 128, 128]);
+        assert!(to_yuv444(&[]).is_empty());
+    }
+}
@@ -0,0 +1,504 @@
+use std::{sync::Mutex, time::Duration};
+
+use smithay::{
+    backend::{
+        allocator::Fourcc,
+        renderer::{
+            element::{
+                default_primary_scanout_output_compare,
+                memory::{MemoryRenderBuffer, MemoryRenderBufferRenderElement},
+                render_elements,
+                solid::{SolidColorBuffer, SolidColorRenderElement},
+                surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
+                utils::select_dmabuf_feedback,
+                Kind, RenderElementStates,
+            },
+            ImportAll, ImportMem, Renderer,
+        },
+    },
+    desktop::{
+        utils::{
+            send_frames_surface_tree, surface_presentation_feedback_flags_from_states,
+            surface_primary_scanout_output, update_surface_primary_scanout_output,
+            OutputPresentationFeedback,
+        },
+        PopupManager, Space, Window,
+    },
+    input::pointer::{CursorImageAttributes, CursorImageStatus},
+    output::Output,
+    reexports::{
+        wayland_protocols::{
+            wp::presentation_time::server::wp_presentation_feedback,
+            xdg::shell::server::xdg_toplevel,
+        },
+        wayland_server::protocol::wl_surface::WlSurface,
+    },
+    utils::{Logical, Physical, Point, Rectangle, Scale, Size, Transform},
+    wayland::{
+        compositor::{with_states, with_surface_tree_downward, SurfaceData, TraversalAction},
+        dmabuf::DmabufFeedback,
+        fractional_scale::with_fractional_scale,
+    },
+};
+
+use crate::{config::DecorationConfig, handlers::session_lock::SessionLock, state::CONFIG};
+
+const CURSOR_SIZE: i32 = 64;
+// Surfaces that aren't visible on any output, e.g. occluded or off-screen windows, get at most one
+// frame callback per interval
+pub const FRAME_CALLBACK_THROTTLE: Duration = Duration::from_secs(1);
+static CURSOR_DATA: &[u8] = include_bytes!("../resources/cursor.rgba");
+
+render_elements! {
+    pub OutputRenderElements<R> where R: ImportAll + ImportMem;
+    Surface=WaylandSurfaceRenderElement<R>,
+    Cursor=MemoryRenderBufferRenderElement<R>,
+    Decoration=SolidColorRenderElement,
+}
+
+// Frame callbacks for windows no output repaints, e.g. minimized or off-screen windows. `output` is
+// only used for bookkeeping, the windows aren't on it so the callbacks are always throttled
+pub fn send_throttled_frames<'a>(
+    windows: impl Iterator<Item = &'a Window>,
+    output: &Output,
+    time: Duration,
+) {
+    for window in windows {
+        window.send_frame(output, time, Some(FRAME_CALLBACK_THROTTLE), |_, _| None);
+    }
+}
+
+pub fn default_cursor() -> MemoryRenderBuffer {
+    MemoryRenderBuffer::from_memory(
+        CURSOR_DATA,
+        Fourcc::Abgr8888,
+        (CURSOR_SIZE, CURSOR_SIZE),
+        1,
+        Transform::Normal,
+        None,
+    )
+}
+
+// The buffers of a window's borders, kept in the window's user data. Reusing them between frames
+// keeps their ids stable, so the damage tracker only redraws them when they actually change
+struct Borders([SolidColorBuffer; 4]);
+
+impl Default for Borders {
+    fn default() -> Self {
+        Borders([(); 4].map(|_| SolidColorBuffer::new((0, 0), [0.0; 4])))
+    }
+}
+
+// Everything visible on `output`, topmost first: the cursor, then every window with its borders.
+// While the session is locked the lock surface takes the place of the windows. Locations are
+// relative to the output
+pub fn output_elements<R>(
+    renderer: &mut R,
+    output: &Output,
+    space: &Space<Window>,
+    session_lock: &SessionLock,
+    pointer_location: Option<Point<f64, Logical>>,
+    cursor_status: &CursorImageStatus,
+    cursor_buffer: &MemoryRenderBuffer,
+) -> Vec<OutputRenderElements<R>>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let output_geometry = match space.output_geometry(output) {
+        Some(geometry) => geometry,
+        None => return Vec::new(),
+    };
+    let scale = Scale::from(output.current_scale().fractional_scale());
+    let mut elements = Vec::new();
+
+    if let Some(location) = pointer_location {
+        if output_geometry.to_f64().contains(location) {
+            let location = location - output_geometry.loc.to_f64();
+            elements.extend(cursor_elements(
+                renderer,
+                location,
+                scale,
+                cursor_status,
+                cursor_buffer,
+            ));
+        }
+    }
+
+    if session_lock.is_locked() {
+        elements.extend(lock_elements(
+            renderer,
+            output,
+            output_geometry.size,
+            session_lock.surface(output),
+            scale,
+        ));
+        return elements;
+    }
+
+    let decoration = CONFIG.read().unwrap().decoration;
+    for window in space.elements_for_output(output).rev() {
+        let window_location = match space.element_location(window) {
+            Some(location) => location,
+            None => continue,
+        };
+        let location = window_location - window.geometry().loc - output_geometry.loc;
+        elements.extend(
+            window_elements(
+                renderer,
+                window,
+                location.to_physical_precise_round(scale),
+                scale,
+            )
+            .into_iter()
+            .map(OutputRenderElements::Surface),
+        );
+        // Fullscreen windows cover the whole output, borders would only keep them off a plane
+        if !is_fullscreen(window) {
+            let geometry = Rectangle::from_loc_and_size(
+                window_location - output_geometry.loc,
+                window.geometry().size,
+            );
+            elements.extend(border_elements(window, geometry, scale, &decoration));
+        }
+    }
+
+    elements
+}
+
+// Kept in the output's user data, like the borders
+struct LockBackground(SolidColorBuffer);
+
+// The lock surface of `output` over an opaque black background. Only the background is left when
+// the locker has no surface on the output, e.g. because it crashed
+fn lock_elements<R>(
+    renderer: &mut R,
+    output: &Output,
+    size: Size<i32, Logical>,
+    surface: Option<&WlSurface>,
+    scale: Scale<f64>,
+) -> Vec<OutputRenderElements<R>>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let mut elements: Vec<OutputRenderElements<R>> = match surface {
+        Some(surface) => render_elements_from_surface_tree(
+            renderer,
+            surface,
+            Point::default(),
+            scale,
+            1.0,
+            Kind::ScanoutCandidate,
+        ),
+        None => Vec::new(),
+    };
+
+    output
+        .user_data()
+        .insert_if_missing(|| Mutex::new(LockBackground(SolidColorBuffer::new((0, 0), [0.0; 4]))));
+    let mut background = output
+        .user_data()
+        .get::<Mutex<LockBackground>>()
+        .unwrap()
+        .lock()
+        .unwrap();
+    background.0.update(size, [0.0, 0.0, 0.0, 1.0]);
+    elements.push(OutputRenderElements::Decoration(
+        SolidColorRenderElement::from_buffer(&background.0, (0, 0), scale, 1.0, Kind::Unspecified),
+    ));
+    elements
+}
+
+// Like `Window::render_elements`, but the toplevel surfaces are candidates for direct scanout, so
+// the drm compositor can put them on the primary or an overlay plane. Popups stay composited
+fn window_elements<R>(
+    renderer: &mut R,
+    window: &Window,
+    location: Point<i32, Physical>,
+    scale: Scale<f64>,
+) -> Vec<WaylandSurfaceRenderElement<R>>
+where
+    R: Renderer + ImportAll,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let surface = window.toplevel().wl_surface();
+    let mut elements: Vec<WaylandSurfaceRenderElement<R>> =
+        PopupManager::popups_for_surface(surface)
+            .flat_map(|(popup, popup_offset)| {
+                let offset = (window.geometry().loc + popup_offset - popup.geometry().loc)
+                    .to_physical_precise_round(scale);
+                render_elements_from_surface_tree(
+                    renderer,
+                    popup.wl_surface(),
+                    location + offset,
+                    scale,
+                    1.0,
+                    Kind::Unspecified,
+                )
+            })
+            .collect();
+    elements.extend(render_elements_from_surface_tree(
+        renderer,
+        surface,
+        location,
+        scale,
+        1.0,
+        Kind::ScanoutCandidate,
+    ));
+    elements
+}
+
+// A window on its own with the origin at its geometry, used to capture it. Popups reaching out of
+// the geometry are cut off
+pub fn toplevel_elements<R>(
+    renderer: &mut R,
+    window: &Window,
+    scale: f64,
+) -> Vec<OutputRenderElements<R>>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let scale = Scale::from(scale);
+    let location = (Point::default() - window.geometry().loc).to_physical_precise_round(scale);
+    window_elements(renderer, window, location, scale)
+        .into_iter()
+        .map(OutputRenderElements::Surface)
+        .collect()
+}
+
+pub fn is_fullscreen(window: &Window) -> bool {
+    window
+        .toplevel()
+        .current_state()
+        .states
+        .contains(xdg_toplevel::State::Fullscreen)
+}
+
+fn cursor_elements<R>(
+    renderer: &mut R,
+    location: Point<f64, Logical>,
+    scale: Scale<f64>,
+    cursor_status: &CursorImageStatus,
+    cursor_buffer: &MemoryRenderBuffer,
+) -> Vec<OutputRenderElements<R>>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    match cursor_status {
+        CursorImageStatus::Hidden => Vec::new(),
+        CursorImageStatus::Surface(surface) => {
+            let hotspot = with_states(surface, |states| {
+                states
+                    .data_map
+                    .get::<Mutex<CursorImageAttributes>>()
+                    .map(|attributes| attributes.lock().unwrap().hotspot)
+                    .unwrap_or_default()
+            });
+            let location = (location - hotspot.to_f64()).to_physical_precise_round(scale);
+            render_elements_from_surface_tree(renderer, surface, location, scale, 1.0, Kind::Cursor)
+        }
+        CursorImageStatus::Default => {
+            match MemoryRenderBufferRenderElement::from_buffer(
+                renderer,
+                location.to_physical(scale),
+                cursor_buffer,
+                None,
+                None,
+                None,
+                Kind::Cursor,
+            ) {
+                Ok(element) => vec![OutputRenderElements::Cursor(element)],
+                Err(err) => {
+                    tracing::warn!("Failed to upload the cursor image: {:?}", err);
+                    Vec::new()
+                }
+            }
+        }
+    }
+}
+
+// Four rectangles drawn just outside the window geometry
+fn border_elements<R>(
+    window: &Window,
+    geometry: Rectangle<i32, Logical>,
+    scale: Scale<f64>,
+    decoration: &DecorationConfig,
+) -> Vec<OutputRenderElements<R>>
+where
+    R: Renderer + ImportAll + ImportMem,
+    <R as Renderer>::TextureId: Clone + 'static,
+{
+    let width = decoration.border_width;
+    if width <= 0 {
+        return Vec::new();
+    }
+    let (x, y) = (geometry.loc.x, geometry.loc.y);
+    let (w, h) = (geometry.size.w, geometry.size.h);
+    let rects = [
+        Rectangle::from_loc_and_size((x - width, y - width), (w + 2 * width, width)),
+        Rectangle::from_loc_and_size((x - width, y + h), (w + 2 * width, width)),
+        Rectangle::from_loc_and_size((x - width, y), (width, h)),
+        Rectangle::from_loc_and_size((x + w, y), (width, h)),
+    ];
+
+    window
+        .user_data()
+        .insert_if_missing(|| Mutex::new(Borders::default()));
+    let mut borders = window
+        .user_data()
+        .get::<Mutex<Borders>>()
+        .unwrap()
+        .lock()
+        .unwrap();
+    borders
+        .0
+        .iter_mut()
+        .zip(rects)
+        .map(|(buffer, rect)| {
+            // Only bumps the commit counter, and so causes damage, if something changed
+            buffer.update(rect.size, decoration.border_color);
+            OutputRenderElements::Decoration(SolidColorRenderElement::from_buffer(
+                buffer,
+                rect.loc.to_physical_precise_round(scale),
+                scale,
+                1.0,
+                Kind::Unspecified,
+            ))
+        })
+        .collect()
+}
+
+// Sends frame callbacks after `output` was repainted. `states` tells which surfaces were actually
+// visible in the frame, surfaces that weren't are throttled. Without states, e.g. on the headless
+// backend, everything on the output counts as visible
+pub fn post_repaint(
+    output: &Output,
+    space: &Space<Window>,
+    cursor_status: &CursorImageStatus,
+    states: Option<&RenderElementStates>,
+    time: Duration,
+) {
+    let visible: Vec<Window> = space.elements_for_output(output).cloned().collect();
+    for window in space.elements() {
+        match states {
+            Some(states) => {
+                window.with_surfaces(|surface, surface_data| {
+                    let primary_output = update_surface_primary_scanout_output(
+                        surface,
+                        output,
+                        surface_data,
+                        states,
+                        default_primary_scanout_output_compare,
+                    );
+                    if let Some(primary_output) = primary_output {
+                        send_preferred_scale(&primary_output, surface_data);
+                    }
+                });
+                window.send_frame(
+                    output,
+                    time,
+                    Some(FRAME_CALLBACK_THROTTLE),
+                    surface_primary_scanout_output,
+                );
+            }
+            None => {
+                // Windows spanning several outputs take the scale of the first one
+                if space.outputs_for_element(window).first() == Some(output) {
+                    window.with_surfaces(|_, surface_data| {
+                        send_preferred_scale(output, surface_data);
+                    });
+                }
+                let on_output = visible.contains(window);
+                window.send_frame(output, time, Some(FRAME_CALLBACK_THROTTLE), |_, _| {
+                    on_output.then(|| output.clone())
+                });
+            }
+        }
+    }
+
+    if let CursorImageStatus::Surface(surface) = cursor_status {
+        if let Some(states) = states {
+            with_surface_tree_downward(
+                surface,
+                (),
+                |_, _, _| TraversalAction::DoChildren(()),
+                |surface, surface_data, _| {
+                    update_surface_primary_scanout_output(
+                        surface,
+                        output,
+                        surface_data,
+                        states,
+                        default_primary_scanout_output_compare,
+                    );
+                },
+                |_, _, _| true,
+            );
+        }
+        send_frames_surface_tree(
+            surface,
+            output,
+            time,
+            Some(FRAME_CALLBACK_THROTTLE),
+            surface_primary_scanout_output,
+        );
+    }
+}
+
+// Fractional scale clients render at exactly the scale of their output. The update is only sent
+// when the scale changed, e.g. after the window moved to an output with a different scale
+fn send_preferred_scale(output: &Output, surface_data: &SurfaceData) {
+    with_fractional_scale(surface_data, |fractional_scale| {
+        fractional_scale.set_preferred_scale(output.current_scale().fractional_scale());
+    });
+}
+
+// Collects the presentation feedback of every surface shown in the last frame of `output`. Must be
+// called after `post_repaint` so the surfaces know which output they are primarily shown on
+pub fn take_presentation_feedback(
+    output: &Output,
+    space: &Space<Window>,
+    states: Option<&RenderElementStates>,
+) -> OutputPresentationFeedback {
+    let mut feedback = OutputPresentationFeedback::new(output);
+    for window in space.elements_for_output(output) {
+        match states {
+            Some(states) => window.take_presentation_feedback(
+                &mut feedback,
+                surface_primary_scanout_output,
+                |surface, _| surface_presentation_feedback_flags_from_states(surface, states),
+            ),
+            // Like in `post_repaint`, everything on the output counts as shown
+            None => window.take_presentation_feedback(
+                &mut feedback,
+                |_, _| Some(output.clone()),
+                |_, _| wp_presentation_feedback::Kind::empty(),
+            ),
+        }
+    }
+    feedback
+}
+
+// The dmabuf feedback of an output: `render` for surfaces that are composited, `scanout` adds a
+// tranche for formats the output's planes can show directly
+pub struct SurfaceDmabufFeedback {
+    pub render: DmabufFeedback,
+    pub scanout: DmabufFeedback,
+}
+
+// Tells every surface primarily shown on `output` which feedback fits it best, based on whether
+// it ended up on a plane in the last frame
+pub fn send_dmabuf_feedback(
+    output: &Output,
+    space: &Space<Window>,
+    states: &RenderElementStates,
+    feedback: &SurfaceDmabufFeedback,
+) {
+    for window in space.elements() {
+        window.send_dmabuf_feedback(output, surface_primary_scanout_output, |surface, _| {
+            select_dmabuf_feedback(surface, states, &feedback.render, &feedback.scanout)
+        });
+    }
+}
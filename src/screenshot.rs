@@ -0,0 +1,181 @@
+// Screenshots taken by the compositor itself, through ipc or a key binding. They are queued like
+// protocol captures and saved as png once the output was repainted
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+use serde_json::{json, Value};
+use smithay::{
+    desktop::Window,
+    output::Output,
+    utils::{Logical, Physical, Rectangle, Size},
+};
+
+use crate::{
+    capture::{self, CaptureRequest, CaptureSource, CaptureTarget},
+    ipc,
+    state::{Backend, Waysight, CONFIG},
+};
+
+// `pixels` are tightly packed Argb8888 rows of `size`. Outputs are opaque, so alpha is dropped
+pub fn write_png(path: &Path, size: Size<i32, Physical>, pixels: &[u8]) -> io::Result<()> {
+    let rgb: Vec<u8> = pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+        .collect();
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, size.w as u32, size.h as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+    Ok(())
+}
+
+// Parses the `<x>,<y> <width>x<height>` format printed by slurp
+fn parse_region(position: &str, size: &str) -> Option<Rectangle<i32, Logical>> {
+    let (x, y) = position.split_once(',')?;
+    let (w, h) = size.split_once('x')?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    if w <= 0 || h <= 0 {
+        return None;
+    }
+    Some(Rectangle::from_loc_and_size(
+        (x.parse().ok()?, y.parse().ok()?),
+        (w, h),
+    ))
+}
+
+impl<B: Backend + 'static> Waysight<B> {
+    // The output called `name`, without a name the one under the pointer
+    pub fn find_output(&self, name: Option<&str>) -> Option<Output> {
+        match name {
+            Some(name) => self
+                .space
+                .outputs()
+                .find(|output| output.name() == name)
+                .cloned(),
+            None => self
+                .pointer_location()
+                .and_then(|location| self.space.output_under(location).next())
+                .or_else(|| self.space.outputs().next())
+                .cloned(),
+        }
+    }
+
+    // `screenshot output [name]`, `screenshot region <x>,<y> <width>x<height>` in global
+    // coordinates, or `screenshot window` for the focused window. Regions are cut from the output
+    // they start on, parts on other outputs are left out. Windows are captured on their own
+    pub fn screenshot_command<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Value {
+        let target = match args.next() {
+            None | Some("output") => self
+                .find_output(args.next())
+                .map(|output| (output, CaptureSource::Output(None)))
+                .ok_or("no such output"),
+            Some("region") => match (args.next(), args.next()) {
+                (Some(position), Some(size)) => parse_region(position, size)
+                    .ok_or("invalid region")
+                    .and_then(|region| self.output_region(region).ok_or("region is off-screen"))
+                    .map(|(output, region)| (output, CaptureSource::Output(Some(region)))),
+                _ => Err("usage: screenshot region <x>,<y> <width>x<height>"),
+            },
+            Some("window") => self
+                .focused_window()
+                .ok_or("no focused window")
+                .and_then(|window| {
+                    capture::toplevel_output(&self.space, &window)
+                        .map(|output| (output, CaptureSource::Toplevel(window)))
+                        .ok_or("no output to render on")
+                }),
+            Some(_) => Err("usage: screenshot [output [name] | region <x>,<y> <w>x<h> | window]"),
+        };
+        let (output, source) = match target {
+            Ok(target) => target,
+            Err(err) => return ipc::error(err),
+        };
+
+        let dir = CONFIG.read().unwrap().capture.screenshot_dir.clone();
+        let path =
+            match capture::output_path(dir, "XDG_PICTURES_DIR", "Pictures", "screenshot", "png") {
+                Ok(path) => path,
+                Err(err) => {
+                    return ipc::error(&format!("failed to create the screenshot: {}", err))
+                }
+            };
+        self.capture_state.queue(CaptureRequest {
+            output: output.clone(),
+            source,
+            overlay_cursor: false,
+            wait_for_damage: false,
+            target: CaptureTarget::Screenshot(path.clone()),
+        });
+        self.backend_data.schedule_render(&output);
+        // The file is written with the next repaint of the output
+        json!({ "output": output.name(), "path": path.to_string_lossy() })
+    }
+
+    // The output `region` starts on and the part of `region` on it, relative to the output
+    fn output_region(
+        &self,
+        region: Rectangle<i32, Logical>,
+    ) -> Option<(Output, Rectangle<i32, Logical>)> {
+        let output = self
+            .space
+            .output_under(region.loc.to_f64())
+            .next()
+            .cloned()?;
+        let geometry = self.space.output_geometry(&output)?;
+        let mut region = region.intersection(geometry)?;
+        region.loc -= geometry.loc;
+        Some((output, region))
+    }
+
+    // The window with keyboard focus, else the one under the pointer
+    fn focused_window(&self) -> Option<Window> {
+        self.seat
+            .get_keyboard()
+            .and_then(|keyboard| keyboard.current_focus())
+            .and_then(|surface| {
+                self.space
+                    .elements()
+                    .find(|window| window.toplevel().wl_surface() == &surface)
+            })
+            .or_else(|| {
+                self.pointer_location()
+                    .and_then(|location| self.space.element_under(location))
+                    .map(|(window, _)| window)
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_slurp_regions() {
+        assert_eq!(
+            parse_region("10,20", "300x200"),
+            Some(Rectangle::from_loc_and_size((10, 20), (300, 200)))
+        );
+        // Outputs left of or above the origin have negative positions
+        assert_eq!(
+            parse_region("-1920,-5", "1x1"),
+            Some(Rectangle::from_loc_and_size((-1920, -5), (1, 1)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regions() {
+        assert_eq!(parse_region("10,20", "0x200"), None);
+        assert_eq!(parse_region("10,20", "300x-1"), None);
+        assert_eq!(parse_region("10 20", "300x200"), None);
+        assert_eq!(parse_region("10,20", "300,200"), None);
+        assert_eq!(parse_region("a,20", "300x200"), None);
+        assert_eq!(parse_region("10,20", "300x"), None);
+    }
+}
@@ -1,32 +1,68 @@
 use std::{
+    fs,
     os::fd::AsRawFd,
-    sync::{Arc, Mutex},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
 };
 
 use smithay::{
-    delegate_output,
+    backend::{
+        allocator::dmabuf::Dmabuf, drm::DrmNode, renderer::element::memory::MemoryRenderBuffer,
+    },
+    delegate_output, delegate_presentation, delegate_viewporter,
+    desktop::{PopupManager, Space, Window},
     input::{pointer::CursorImageStatus, Seat, SeatState},
+    output::Output,
     reexports::{
         calloop::{
-            generic::Generic, EventLoop, Interest, LoopHandle, LoopSignal, Mode, PostAction,
+            generic::Generic,
+            signals::{Signal, Signals},
+            timer::{TimeoutAction, Timer},
+            EventLoop, Interest, LoopHandle, LoopSignal, Mode, PostAction,
         },
         wayland_server::{
             backend::{ClientData, ClientId},
+            protocol::wl_surface::WlSurface,
             Display, DisplayHandle,
         },
     },
+    utils::{Clock, Logical, Monotonic, Point},
     wayland::{
         compositor::{CompositorClientState, CompositorState},
+        dmabuf::DmabufState,
+        fractional_scale::FractionalScaleManagerState,
         output::OutputManagerState,
+        presentation::PresentationState,
+        shell::xdg::XdgShellState,
         shm::ShmState,
         socket::ListeningSocketSource,
+        viewporter::ViewporterState,
     },
 };
 use static_init::lazy::Lazy;
 
-use crate::config::WaysightConfig;
+use crate::{
+    capture::CaptureState,
+    config::{self, WaysightConfig},
+    handlers::{session_lock::SessionLock, xdg_activation::Activation},
+    ipc::{self, OutputStats},
+    protocols::{
+        foreign_toplevel::ForeignToplevelState,
+        idle_inhibit::IdleInhibitState,
+        idle_notify::IdleNotifyState,
+        image_copy_capture::{self, ImageCopyCaptureState},
+        output_management::{
+            Head, HeadConfiguration, OutputConfigurationError, OutputManagementState,
+        },
+        output_power::OutputPowerState,
+        screencopy, tearing_control,
+    },
+    render,
+    utils::{process, systemd},
+};
 
-pub static CONFIG: Lazy<WaysightConfig> = Lazy::from_generator(WaysightConfig::load_config);
+pub static CONFIG: Lazy<RwLock<WaysightConfig>> =
+    Lazy::from_generator(|| RwLock::new(WaysightConfig::load_config()));
 
 // Our loop data
 pub struct CalloopData<B: Backend + 'static> {
@@ -37,17 +73,43 @@ pub struct CalloopData<B: Backend + 'static> {
 // Base struct for storing any wayland globals and handling requests
 pub struct Waysight<B: Backend + 'static> {
     pub display_handle: DisplayHandle,
+    // Timestamps for frame callbacks and presentation feedback
+    pub clock: Clock<Monotonic>,
     pub cursor_image_status: Arc<Mutex<CursorImageStatus>>,
+    // Drawn when no client sets a cursor surface
+    pub cursor_buffer: MemoryRenderBuffer,
     pub compositor: CompositorState,
     pub loop_handle: LoopHandle<'static, CalloopData<B>>,
     pub loop_signal: LoopSignal,
-    // pub xdg_shell_state: XdgShellState,
+    pub xdg_shell_state: XdgShellState,
+    pub space: Space<Window>,
+    // Windows taken out of the space and where they were
+    pub minimized_windows: Vec<(Window, Point<i32, Logical>)>,
+    pub popups: PopupManager,
     pub shm_state: ShmState,
+    // The global itself is created by the backend, which knows the renderer's formats
+    pub dmabuf_state: DmabufState,
     pub seat_state: SeatState<Self>,
     pub seat_name: String,
     pub seat: Seat<Self>,
+    // Keys pressed for a binding, their release isn't sent to clients either
+    pub suppressed_keys: Vec<u32>,
     pub socket_name: String,
+    pub ipc_socket: Option<PathBuf>,
     pub output_state: OutputManagerState,
+    pub output_management_state: OutputManagementState,
+    pub output_power_state: OutputPowerState,
+    pub presentation_state: PresentationState,
+    pub fractional_scale_state: FractionalScaleManagerState,
+    pub viewporter_state: ViewporterState,
+    pub foreign_toplevel_state: ForeignToplevelState,
+    pub session_lock: SessionLock,
+    pub activation: Activation,
+    pub idle_notify_state: IdleNotifyState,
+    pub idle_inhibit_state: IdleInhibitState,
+    pub image_copy_capture_state: ImageCopyCaptureState,
+    // Screencopy frames waiting for the next repaint of their output
+    pub capture_state: CaptureState,
     pub backend_data: B,
 }
 
@@ -102,6 +164,31 @@ fn init_wl_socket<B: Backend + 'static>(
     Some(socket_name)
 }
 
+// SIGCHLD reaps the processes we spawned, SIGHUP reloads the config and SIGTERM/SIGINT shut the
+// compositor down cleanly (systemd stops services with SIGTERM)
+fn init_signals<B: Backend + 'static>(handle: &LoopHandle<'static, CalloopData<B>>) {
+    let signals = match Signals::new(&[
+        Signal::SIGCHLD,
+        Signal::SIGHUP,
+        Signal::SIGTERM,
+        Signal::SIGINT,
+    ]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::error!("Error when creating the signal source: {}", err);
+            return;
+        }
+    };
+    handle
+        .insert_source(signals, |event, _, data| match event.signal() {
+            Signal::SIGCHLD => process::reap_children(),
+            Signal::SIGHUP => data.state.reload_config(),
+            Signal::SIGTERM | Signal::SIGINT => data.state.stop(),
+            _ => {}
+        })
+        .unwrap();
+}
+
 impl<B: Backend + 'static> Waysight<B> {
     pub fn new(
         event_loop: &EventLoop<'static, CalloopData<B>>,
@@ -112,39 +199,265 @@ impl<B: Backend + 'static> Waysight<B> {
         let socket_name = init_wl_socket(&event_loop.handle(), display).unwrap();
         let loop_handle = event_loop.handle();
         let loop_signal = event_loop.get_signal();
+        init_signals(&loop_handle);
+        let ipc_socket = ipc::init(&loop_handle, &socket_name);
+        // Deferred to the first loop iteration so the backend has set up its outputs by the time
+        // the autostarted clients connect
+        loop_handle.insert_idle(|data| {
+            data.state.notify_ready();
+            data.state.run_autostart();
+        });
+        // Hidden windows aren't part of any repaint, without this they'd never get frame callbacks
+        loop_handle
+            .insert_source(
+                Timer::from_duration(render::FRAME_CALLBACK_THROTTLE),
+                |_, _, data| {
+                    data.state.send_hidden_frames();
+                    TimeoutAction::ToDuration(render::FRAME_CALLBACK_THROTTLE)
+                },
+            )
+            .unwrap();
 
         let mut seat_state = SeatState::<Self>::new();
         let seat_name = backend_data.seat_name();
-        let seat = seat_state.new_wl_seat(&display_handle, &seat_name);
+        let mut seat = seat_state.new_wl_seat(&display_handle, &seat_name);
+        seat.add_pointer();
 
         let compositor = CompositorState::new::<Self>(&display_handle);
         let shm_state = ShmState::new::<Self>(&display_handle, []);
 
-        let output_state = OutputManagerState::new_with_xdg_output::<Self>(&display_handle);
+        let xdg_shell_state = XdgShellState::new::<Self>(&display_handle);
         let cursor_image_status = Arc::new(Mutex::new(CursorImageStatus::Default));
 
         let output_state = OutputManagerState::new_with_xdg_output::<Self>(&display_handle);
+        let output_management_state = OutputManagementState::new::<B>(&display_handle);
+        let output_power_state = OutputPowerState::new::<B>(&display_handle);
+        tearing_control::init::<B>(&display_handle);
+        screencopy::init::<B>(&display_handle);
+        image_copy_capture::init::<B>(&display_handle);
+        let foreign_toplevel_state = ForeignToplevelState::new::<B>(&display_handle);
+        let session_lock = SessionLock::new::<B>(&display_handle);
+        let activation = Activation::new::<B>(&display_handle);
+        let idle_notify_state = IdleNotifyState::new::<B>(&display_handle);
+        let idle_inhibit_state = IdleInhibitState::new::<B>(&display_handle);
+        let clock = Clock::new().expect("Failed to initialize the clock");
+        let presentation_state = PresentationState::new::<Self>(&display_handle, clock.id() as u32);
+        let fractional_scale_state = FractionalScaleManagerState::new::<Self>(&display_handle);
+        let viewporter_state = ViewporterState::new::<Self>(&display_handle);
 
         Waysight {
             display_handle,
+            clock,
             cursor_image_status,
+            cursor_buffer: render::default_cursor(),
             compositor,
             loop_handle,
             loop_signal,
+            xdg_shell_state,
+            space: Space::default(),
+            minimized_windows: Vec::new(),
+            popups: PopupManager::default(),
             shm_state,
+            dmabuf_state: DmabufState::new(),
             seat_state,
             seat_name,
             seat,
+            suppressed_keys: Vec::new(),
             socket_name,
+            ipc_socket,
             output_state,
+            output_management_state,
+            output_power_state,
+            presentation_state,
+            fractional_scale_state,
+            viewporter_state,
+            foreign_toplevel_state,
+            session_lock,
+            activation,
+            idle_notify_state,
+            idle_inhibit_state,
+            image_copy_capture_state: ImageCopyCaptureState::default(),
+            capture_state: CaptureState::default(),
             backend_data,
         }
     }
 }
 
+impl<B: Backend + 'static> Waysight<B> {
+    pub fn run_autostart(&mut self) {
+        // Cloned so the config lock isn't held while `spawn` reads the environment from it
+        let autostart = CONFIG.read().unwrap().autostart.clone();
+        for command in autostart.exec.iter().chain(autostart.exec_always.iter()) {
+            process::spawn(command, &self.socket_name);
+        }
+    }
+
+    // Called once the socket is listening and the backend is initialized
+    pub fn notify_ready(&mut self) {
+        let (notify, import_environment) = {
+            let config = CONFIG.read().unwrap();
+            (config.systemd.notify, config.systemd.import_environment)
+        };
+        if import_environment {
+            systemd::import_environment(&self.socket_name);
+        }
+        if notify {
+            systemd::notify("READY=1");
+        }
+    }
+
+    // Stops the event loop, this should be used instead of calling `loop_signal.stop()` directly
+    pub fn stop(&mut self) {
+        if CONFIG.read().unwrap().systemd.notify {
+            systemd::notify("STOPPING=1");
+        }
+        if let Some(path) = self.ipc_socket.take() {
+            let _ = fs::remove_file(path);
+        }
+        self.loop_signal.stop();
+    }
+
+    // Backends call this after outputs were added, removed or reconfigured. Keeps the outputs
+    // mapped in the space in sync with the backend and notifies output management clients
+    pub fn outputs_changed(&mut self) {
+        let heads = self.backend_data.heads();
+        for head in heads.iter() {
+            if head.enabled {
+                self.space
+                    .map_output(&head.output, head.output.current_location());
+            } else {
+                self.space.unmap_output(&head.output);
+            }
+        }
+        let removed: Vec<_> = self
+            .space
+            .outputs()
+            .filter(|output| !heads.iter().any(|head| &head.output == *output))
+            .cloned()
+            .collect();
+        for output in removed {
+            self.space.unmap_output(&output);
+        }
+        self.migrate_windows();
+        let outputs: Vec<Output> = self.space.outputs().cloned().collect();
+        self.capture_state.retain_outputs(&outputs);
+        self.configure_lock_surfaces();
+        self.update_output_power();
+        self.update_capture_sessions();
+        self.refresh_foreign_toplevels();
+        self.update_output_heads();
+    }
+
+    // Repaints every output `surface` is visible on. Surfaces that aren't part of a mapped window
+    // (popups, cursors, ...) repaint every output
+    pub fn schedule_render_for_surface(&mut self, surface: &WlSurface) {
+        let outputs: Vec<Output> = match self
+            .space
+            .elements()
+            .find(|window| window.toplevel().wl_surface() == surface)
+        {
+            Some(window) => self.space.outputs_for_element(window),
+            None => self.space.outputs().cloned().collect(),
+        };
+        for output in outputs.iter() {
+            self.backend_data.schedule_render(output);
+        }
+    }
+
+    // Throttled frame callbacks for minimized windows, windows outside of every output and, while the
+    // session is locked, every window
+    pub fn send_hidden_frames(&mut self) {
+        let output = match self.space.outputs().next() {
+            Some(output) => output.clone(),
+            None => return,
+        };
+        let locked = self.session_lock.is_locked();
+        let hidden = self
+            .space
+            .elements()
+            .filter(|window| locked || self.space.outputs_for_element(window).is_empty());
+        let minimized = self.minimized_windows.iter().map(|(window, _)| window);
+        render::send_throttled_frames(hidden.chain(minimized), &output, self.clock.now().into());
+    }
+
+    pub fn schedule_render_all(&mut self) {
+        let outputs: Vec<Output> = self.space.outputs().cloned().collect();
+        for output in outputs.iter() {
+            self.backend_data.schedule_render(output);
+        }
+    }
+
+    // Repaints the output the pointer is on, e.g. after the cursor image changed
+    pub fn schedule_render_for_pointer(&mut self) {
+        let location = match self.pointer_location() {
+            Some(location) => location,
+            None => return,
+        };
+        let output = self.space.output_under(location).next().cloned();
+        if let Some(output) = output {
+            self.backend_data.schedule_render(&output);
+        }
+    }
+
+    pub fn pointer_location(&self) -> Option<Point<f64, Logical>> {
+        self.seat
+            .get_pointer()
+            .map(|pointer| pointer.current_location())
+    }
+
+    pub fn reload_config(&mut self) {
+        let new_config = match config::reload() {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!("Failed to reload config, keeping the old one: {}", err);
+                return;
+            }
+        };
+        let exec_always = new_config.autostart.exec_always.clone();
+        *CONFIG.write().unwrap() = new_config;
+        tracing::info!("Reloaded config");
+
+        self.apply_keyboard_config();
+        self.backend_data.apply_output_config(&self.display_handle);
+        self.outputs_changed();
+        // The clear color or borders may have changed
+        self.schedule_render_all();
+
+        for command in exec_always.iter() {
+            process::spawn(command, &self.socket_name);
+        }
+    }
+}
+
 pub trait Backend {
     fn seat_name(&self) -> String;
+    // (Re)applies the `[[output]]` config entries to every output
+    fn apply_output_config(&mut self, display_handle: &DisplayHandle);
+    // Every output the backend knows about, including disabled ones
+    fn heads(&self) -> Vec<Head>;
+    // Applies a configuration requested through output management. The configuration must be
+    // applied entirely or not at all. With `test_only` nothing is changed
+    fn apply_output_configuration(
+        &mut self,
+        display_handle: &DisplayHandle,
+        heads: &[HeadConfiguration],
+        test_only: bool,
+    ) -> Result<(), OutputConfigurationError>;
+    // Asks for `output` to be repainted. Backends coalesce requests and repaint at most once per
+    // refresh cycle
+    fn schedule_render(&mut self, output: &Output);
+    // Checks that a client buffer can be used by the renderer. Failures are logged by the backend
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> bool;
+    // Per output frame statistics for the `stats` ipc command
+    fn output_stats(&self) -> Vec<OutputStats>;
+    // The gpu clients should allocate dmabufs on, `None` if dmabufs can't be used
+    fn render_node(&self) -> Option<DrmNode>;
+    // Turns `output` off or on, it stays in the layout either way. Returns false if the backend
+    // can't
+    fn set_output_power(&mut self, output: &Output, on: bool) -> bool;
     // TODO: add more methods
 }
 
 delegate_output!(@<B: Backend + 'static> Waysight<B>);
+delegate_presentation!(@<B: Backend + 'static> Waysight<B>);
+delegate_viewporter!(@<B: Backend + 'static> Waysight<B>);
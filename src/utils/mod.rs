@@ -1 +1,4 @@
+pub mod output;
+pub mod process;
+pub mod systemd;
 pub mod workspaces;
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use smithay::{
+    output::{Output, Scale},
+    utils::{Logical, Point, Transform},
+};
+
+use crate::{config::OutputConfig, state::CONFIG};
+
+// Looks up the `[[output]]` entry for `output`. The connector name is the output's name, make and
+// model come from its physical properties
+pub fn find_output_config(output: &Output, serial: &str) -> Option<OutputConfig> {
+    let properties = output.physical_properties();
+    CONFIG
+        .read()
+        .unwrap()
+        .output_config(&output.name(), &properties.make, &properties.model, serial)
+        .cloned()
+}
+
+// Position, scale and transform an output should have according to its config entry. Outputs
+// without a configured position are placed at `fallback`, without an entry they are also unscaled
+// and untransformed
+pub fn configured_state(
+    config: Option<&OutputConfig>,
+    fallback: Point<i32, Logical>,
+) -> (Point<i32, Logical>, Scale, Transform) {
+    let position = config
+        .and_then(|config| config.position)
+        .map(|[x, y]| Point::from((x, y)))
+        .unwrap_or(fallback);
+    let scale = config
+        .and_then(|config| config.scale)
+        .map(Scale::Fractional)
+        .unwrap_or(Scale::Integer(1));
+    let transform = config
+        .and_then(|config| config.transform)
+        .map(Transform::from)
+        .unwrap_or(Transform::Normal);
+    (position, scale, transform)
+}
+
+// The x coordinate right of all `outputs`, where the next output without a configured position
+// goes
+pub fn layout_right_edge<'a>(outputs: impl IntoIterator<Item = &'a Output>) -> i32 {
+    outputs
+        .into_iter()
+        .filter_map(|output| {
+            let mode = output.current_mode()?;
+            let size = output
+                .current_transform()
+                .transform_size(mode.size)
+                .to_f64()
+                .to_logical(output.current_scale().fractional_scale())
+                .to_i32_round::<i32>();
+            Some(output.current_location().x + size.w)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// Time between two vblanks of `output`, assuming 60Hz for outputs without a mode
+pub fn refresh_interval(output: &Output) -> Duration {
+    let refresh = output
+        .current_mode()
+        .map(|mode| mode.refresh)
+        .filter(|refresh| *refresh > 0)
+        .unwrap_or(60_000);
+    Duration::from_micros(1_000_000_000 / refresh as u64)
+}
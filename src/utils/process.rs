@@ -0,0 +1,64 @@
+use std::{
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+};
+
+use smithay::reexports::nix::{
+    errno::Errno,
+    sys::{
+        signal::{sigprocmask, SigSet, SigmaskHow},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+
+use crate::state::CONFIG;
+
+// Spawns `command` through `sh -c` with the environment a client of this compositor expects
+pub fn spawn(command: &str, socket_name: &str) {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("WAYLAND_DISPLAY", socket_name)
+        .env("XDG_CURRENT_DESKTOP", "waysight")
+        .envs(CONFIG.read().unwrap().environment.iter())
+        .stdin(Stdio::null());
+    unblock_signals(&mut cmd);
+
+    match cmd.spawn() {
+        Ok(child) => tracing::info!("Spawned `{}` with pid {}", command, child.id()),
+        Err(err) => tracing::error!("Failed to spawn `{}`: {}", command, err),
+    }
+}
+
+// The event loop blocks the signals it listens to (SIGCHLD, SIGHUP...) with a signalfd, and the
+// signal mask survives exec. Every command we run must clear it so it doesn't start with those
+// signals blocked
+pub fn unblock_signals(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            sigprocmask(SigmaskHow::SIG_SETMASK, Some(&SigSet::empty()), None)?;
+            Ok(())
+        });
+    }
+}
+
+// Collects the exit status of every child that has terminated so they don't linger as zombies
+pub fn reap_children() {
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+            Ok(WaitStatus::Exited(pid, code)) => {
+                tracing::debug!("Child process {} exited with status {}", pid, code);
+            }
+            Ok(WaitStatus::Signaled(pid, signal, _)) => {
+                tracing::debug!("Child process {} was killed by {}", pid, signal);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!("Error while reaping child processes: {}", err);
+                break;
+            }
+        }
+    }
+}
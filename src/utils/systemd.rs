@@ -0,0 +1,102 @@
+use std::{
+    env, io,
+    os::{linux::net::SocketAddrExt, unix::net::SocketAddr, unix::net::UnixDatagram},
+    process::Command,
+};
+
+use crate::utils::process;
+
+// Sends `state` (e.g. "READY=1") to the service manager as described in sd_notify(3). Does nothing
+// when waysight wasn't started by systemd, i.e. `NOTIFY_SOCKET` is unset
+pub fn notify(state: &str) {
+    let socket_path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => {
+            tracing::debug!("NOTIFY_SOCKET is not set, not sending `{}`", state);
+            return;
+        }
+    };
+
+    if let Err(err) = send_notify(&socket_path, state) {
+        tracing::warn!("Failed to notify systemd with `{}`: {}", state, err);
+    } else {
+        tracing::debug!("Notified systemd with `{}`", state);
+    }
+}
+
+fn send_notify(socket_path: &str, state: &str) -> io::Result<()> {
+    // Paths starting with '@' refer to sockets in the abstract namespace
+    let addr = match socket_path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// Pushes the variables clients need into the systemd user manager and the dbus activation
+// environment, so services and dbus-activated apps started after us can find the compositor
+pub fn import_environment(socket_name: &str) {
+    let mut variables = vec!["WAYLAND_DISPLAY", "XDG_CURRENT_DESKTOP"];
+    for variable in ["DISPLAY", "WAYSIGHT_SOCKET"] {
+        if env::var(variable).is_ok() {
+            variables.push(variable);
+        }
+    }
+
+    let commands: [(&str, &[&str]); 2] = [
+        ("systemctl", &["--user", "import-environment"]),
+        ("dbus-update-activation-environment", &["--systemd"]),
+    ];
+    for (program, args) in commands {
+        // Ran synchronously so the environment is in place before READY=1 is sent
+        let mut cmd = Command::new(program);
+        cmd.args(args)
+            .args(&variables)
+            .env("WAYLAND_DISPLAY", socket_name)
+            .env("XDG_CURRENT_DESKTOP", "waysight");
+        process::unblock_signals(&mut cmd);
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => tracing::warn!("`{}` exited with {}", program, status),
+            Err(err) => tracing::warn!("Failed to run `{}`: {}", program, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0; 64];
+        let len = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..len]).into_owned()
+    }
+
+    // Both socket kinds in one test, `NOTIFY_SOCKET` is process wide
+    #[test]
+    fn notifies_the_service_manager() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        assert_eq!(receive(&socket), "READY=1");
+
+        let name = format!("waysight-test-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        notify("STOPPING=1");
+        assert_eq!(receive(&socket), "STOPPING=1");
+
+        env::remove_var("NOTIFY_SOCKET");
+    }
+}
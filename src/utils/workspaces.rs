@@ -8,7 +8,9 @@ pub struct Workspaces {
 
 impl Workspaces {
     fn create_workspaces() -> Self {
-        let workspaces: Vec<Workspace> = (0..CONFIG.workspaces).map(|_| Workspace::new()).collect();
+        let workspaces: Vec<Workspace> = (0..CONFIG.read().unwrap().workspaces)
+            .map(|_| Workspace::new())
+            .collect();
         Workspaces { workspaces }
     }
 }
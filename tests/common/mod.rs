@@ -0,0 +1,54 @@
+// Runs the compositor with the headless backend for the integration tests
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Kills the compositor when the test ends, passed or not
+pub struct Compositor(Child);
+
+impl Drop for Compositor {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Starts waysight with `dir` as its runtime, config and home directory and returns the path of its
+// wayland socket once it exists
+pub fn start_compositor(dir: &Path) -> (Compositor, PathBuf) {
+    let child = Command::new(env!("CARGO_BIN_EXE_waysight"))
+        .arg("--backend=headless")
+        .env("XDG_RUNTIME_DIR", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("HOME", dir)
+        .env_remove("WAYLAND_DISPLAY")
+        .env_remove("NOTIFY_SOCKET")
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to start waysight");
+    let mut compositor = Compositor(child);
+    let start = Instant::now();
+    loop {
+        let socket = fs::read_dir(dir).unwrap().flatten().find_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name.starts_with("wayland-") && !name.ends_with(".lock")).then(|| entry.path())
+        });
+        if let Some(socket) = socket {
+            return (compositor, socket);
+        }
+        if let Some(status) = compositor.0.try_wait().unwrap() {
+            panic!("waysight exited early: {}", status);
+        }
+        assert!(
+            start.elapsed() < TIMEOUT,
+            "waysight didn't create its socket"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,152 @@
+// Starts the headless backend and copies its output into an shm buffer through wlr-screencopy
+mod common;
+
+use std::{
+    os::{
+        fd::AsFd,
+        unix::{fs::FileExt, net::UnixStream},
+    },
+    time::Instant,
+};
+
+use wayland_client::{
+    delegate_noop,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_output::WlOutput,
+        wl_registry::{self, WlRegistry},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+    },
+    Connection, Dispatch, QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use common::{start_compositor, TIMEOUT};
+
+#[derive(Default)]
+struct Client {
+    shm: Option<WlShm>,
+    output: Option<WlOutput>,
+    screencopy: Option<ZwlrScreencopyManagerV1>,
+    // Format, width, height and stride of the buffer the frame wants
+    buffer: Option<(wl_shm::Format, i32, i32, i32)>,
+    buffer_done: bool,
+    ready: bool,
+    failed: bool,
+}
+
+impl Dispatch<WlRegistry, ()> for Client {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name, interface, ..
+        } = event
+        {
+            match interface.as_str() {
+                "wl_shm" => state.shm = Some(registry.bind(name, 1, qh, ())),
+                "wl_output" if state.output.is_none() => {
+                    state.output = Some(registry.bind(name, 1, qh, ()))
+                }
+                "zwlr_screencopy_manager_v1" => {
+                    state.screencopy = Some(registry.bind(name, 3, qh, ()))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrScreencopyFrameV1,
+        event: zwlr_screencopy_frame_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_screencopy_frame_v1::Event::Buffer {
+                format: WEnum::Value(format),
+                width,
+                height,
+                stride,
+            } => state.buffer = Some((format, width as i32, height as i32, stride as i32)),
+            zwlr_screencopy_frame_v1::Event::BufferDone => state.buffer_done = true,
+            zwlr_screencopy_frame_v1::Event::Ready { .. } => state.ready = true,
+            zwlr_screencopy_frame_v1::Event::Failed => state.failed = true,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(Client: ignore WlShm);
+delegate_noop!(Client: ignore WlOutput);
+delegate_noop!(Client: ignore WlBuffer);
+delegate_noop!(Client: WlShmPool);
+delegate_noop!(Client: ZwlrScreencopyManagerV1);
+
+#[test]
+fn screencopy_into_shm() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_compositor, socket) = start_compositor(dir.path());
+
+    let connection = Connection::from_socket(UnixStream::connect(socket).unwrap()).unwrap();
+    let mut queue = connection.new_event_queue();
+    let qh = queue.handle();
+    let _registry = connection.display().get_registry(&qh, ());
+    let mut client = Client::default();
+    queue.roundtrip(&mut client).unwrap();
+
+    let shm = client.shm.clone().expect("no wl_shm");
+    let output = client.output.clone().expect("no wl_output");
+    let screencopy = client.screencopy.clone().expect("no screencopy");
+
+    let frame = screencopy.capture_output(0, &output, &qh, ());
+    while !client.buffer_done && !client.failed {
+        queue.blocking_dispatch(&mut client).unwrap();
+    }
+    assert!(!client.failed, "the frame failed before copying");
+    let (format, width, height, stride) = client.buffer.expect("no shm buffer constraints");
+    assert_eq!((width, height), (1920, 1080));
+
+    let file = tempfile::tempfile().unwrap();
+    file.set_len((stride * height) as u64).unwrap();
+    let pool = shm.create_pool(file.as_fd(), stride * height, &qh, ());
+    let buffer = pool.create_buffer(0, width, height, stride, format, &qh, ());
+    frame.copy(&buffer);
+
+    let start = Instant::now();
+    while !client.ready && !client.failed {
+        assert!(start.elapsed() < TIMEOUT, "the frame was never copied");
+        queue.blocking_dispatch(&mut client).unwrap();
+    }
+    assert!(client.ready, "the copy failed");
+
+    // Nothing is mapped, so the whole output is the default clear color, cyan. Pixels are stored
+    // as B, G, R, A
+    let mut pixels = vec![0u8; (stride * height) as usize];
+    file.read_exact_at(&mut pixels, 0).unwrap();
+    for y in [0, height - 1] {
+        for x in [0, width - 1] {
+            let index = (y * stride + x * 4) as usize;
+            assert_eq!(
+                &pixels[index..index + 3],
+                &[0xff, 0xff, 0x00],
+                "pixel {},{}",
+                x,
+                y
+            );
+        }
+    }
+}
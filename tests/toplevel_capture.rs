@@ -0,0 +1,359 @@
+// Maps a window on the headless backend and captures it through ext-image-copy-capture with a
+// foreign toplevel capture source
+mod common;
+
+use std::{
+    fs::File,
+    os::{
+        fd::AsFd,
+        unix::{fs::FileExt, net::UnixStream},
+    },
+    time::Instant,
+};
+
+use wayland_client::{
+    delegate_noop,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_compositor::WlCompositor,
+        wl_registry::{self, WlRegistry},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+        wl_surface::WlSurface,
+    },
+    Connection, Dispatch, EventQueue, QueueHandle, WEnum,
+};
+use wayland_protocols::{
+    ext::foreign_toplevel_list::v1::client::{
+        ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1,
+        ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+    },
+    xdg::shell::client::{
+        xdg_surface::{self, XdgSurface},
+        xdg_toplevel::XdgToplevel,
+        xdg_wm_base::{self, XdgWmBase},
+    },
+};
+
+use common::{start_compositor, TIMEOUT};
+use protocols::{
+    image_capture_source::{
+        ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+        ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    },
+    image_copy_capture::{
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+        ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+        ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+    },
+};
+
+// Client side of the protocols the compositor generates from resources/protocols
+#[allow(
+    dead_code,
+    non_camel_case_types,
+    unused_imports,
+    non_upper_case_globals
+)]
+#[allow(clippy::all)]
+mod protocols {
+    pub mod image_capture_source {
+        use wayland_client;
+        use wayland_client::protocol::*;
+        use wayland_protocols::ext::foreign_toplevel_list::v1::client::*;
+
+        pub mod __interfaces {
+            use wayland_client::protocol::__interfaces::*;
+            use wayland_protocols::ext::foreign_toplevel_list::v1::client::__interfaces::*;
+            wayland_scanner::generate_interfaces!(
+                "resources/protocols/ext-image-capture-source-v1.xml"
+            );
+        }
+        use self::__interfaces::*;
+
+        wayland_scanner::generate_client_code!(
+            "resources/protocols/ext-image-capture-source-v1.xml"
+        );
+    }
+
+    pub mod image_copy_capture {
+        use super::image_capture_source::*;
+        use wayland_client;
+        use wayland_client::protocol::*;
+
+        pub mod __interfaces {
+            use super::super::image_capture_source::__interfaces::*;
+            use wayland_client::protocol::__interfaces::*;
+            wayland_scanner::generate_interfaces!(
+                "resources/protocols/ext-image-copy-capture-v1.xml"
+            );
+        }
+        use self::__interfaces::*;
+
+        wayland_scanner::generate_client_code!("resources/protocols/ext-image-copy-capture-v1.xml");
+    }
+}
+
+const WINDOW_SIZE: i32 = 64;
+
+#[derive(Default)]
+struct Client {
+    compositor: Option<WlCompositor>,
+    shm: Option<WlShm>,
+    wm_base: Option<XdgWmBase>,
+    toplevel_list: Option<ExtForeignToplevelListV1>,
+    source_manager: Option<ExtForeignToplevelImageCaptureSourceManagerV1>,
+    copy_manager: Option<ExtImageCopyCaptureManagerV1>,
+    configured: bool,
+    handles: Vec<ExtForeignToplevelHandleV1>,
+    // Buffer size and shm formats of the session, complete once `constraints_done` is set
+    buffer_size: Option<(i32, i32)>,
+    shm_formats: Vec<wl_shm::Format>,
+    constraints_done: bool,
+    stopped: bool,
+    ready: bool,
+    failed: Option<WEnum<ext_image_copy_capture_frame_v1::FailureReason>>,
+}
+
+impl Dispatch<WlRegistry, ()> for Client {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name, interface, ..
+        } = event
+        {
+            match interface.as_str() {
+                "wl_compositor" => state.compositor = Some(registry.bind(name, 4, qh, ())),
+                "wl_shm" => state.shm = Some(registry.bind(name, 1, qh, ())),
+                "xdg_wm_base" => state.wm_base = Some(registry.bind(name, 1, qh, ())),
+                "ext_foreign_toplevel_list_v1" => {
+                    state.toplevel_list = Some(registry.bind(name, 1, qh, ()))
+                }
+                "ext_foreign_toplevel_image_capture_source_manager_v1" => {
+                    state.source_manager = Some(registry.bind(name, 1, qh, ()))
+                }
+                "ext_image_copy_capture_manager_v1" => {
+                    state.copy_manager = Some(registry.bind(name, 1, qh, ()))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for Client {
+    fn event(
+        _: &mut Self,
+        wm_base: &XdgWmBase,
+        event: xdg_wm_base::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for Client {
+    fn event(
+        state: &mut Self,
+        xdg_surface: &XdgSurface,
+        event: xdg_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+            state.configured = true;
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &ExtForeignToplevelListV1,
+        event: ext_foreign_toplevel_list_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let ext_foreign_toplevel_list_v1::Event::Toplevel { toplevel } = event {
+            state.handles.push(toplevel);
+        }
+    }
+
+    wayland_client::event_created_child!(Client, ExtForeignToplevelListV1, [
+        ext_foreign_toplevel_list_v1::EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &ExtImageCopyCaptureSessionV1,
+        event: ext_image_copy_capture_session_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                state.buffer_size = Some((width as i32, height as i32))
+            }
+            ext_image_copy_capture_session_v1::Event::ShmFormat {
+                format: WEnum::Value(format),
+            } => state.shm_formats.push(format),
+            ext_image_copy_capture_session_v1::Event::Done => state.constraints_done = true,
+            ext_image_copy_capture_session_v1::Event::Stopped => state.stopped = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &ExtImageCopyCaptureFrameV1,
+        event: ext_image_copy_capture_frame_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Ready => state.ready = true,
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                state.failed = Some(reason)
+            }
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(Client: WlCompositor);
+delegate_noop!(Client: ignore WlSurface);
+delegate_noop!(Client: ignore WlShm);
+delegate_noop!(Client: WlShmPool);
+delegate_noop!(Client: ignore WlBuffer);
+delegate_noop!(Client: ignore XdgToplevel);
+delegate_noop!(Client: ignore ExtForeignToplevelHandleV1);
+delegate_noop!(Client: ExtForeignToplevelImageCaptureSourceManagerV1);
+delegate_noop!(Client: ExtImageCaptureSourceV1);
+delegate_noop!(Client: ExtImageCopyCaptureManagerV1);
+
+// Dispatches until `done` returns true, failing the test after `TIMEOUT`
+fn dispatch_until(
+    queue: &mut EventQueue<Client>,
+    client: &mut Client,
+    done: impl Fn(&Client) -> bool,
+) {
+    let start = Instant::now();
+    while !done(client) {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for waysight");
+        queue.blocking_dispatch(client).unwrap();
+    }
+}
+
+// An Xrgb8888 shm buffer and the file backing it
+fn shm_buffer(
+    shm: &WlShm,
+    qh: &QueueHandle<Client>,
+    (width, height): (i32, i32),
+    pixel: [u8; 4],
+) -> (WlBuffer, File) {
+    let stride = width * 4;
+    let file = tempfile::tempfile().unwrap();
+    file.write_all_at(&pixel.repeat((width * height) as usize), 0)
+        .unwrap();
+    let pool = shm.create_pool(file.as_fd(), stride * height, qh, ());
+    let buffer = pool.create_buffer(0, width, height, stride, wl_shm::Format::Xrgb8888, qh, ());
+    pool.destroy();
+    (buffer, file)
+}
+
+#[test]
+fn toplevel_capture_into_shm() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_compositor, socket) = start_compositor(dir.path());
+
+    let connection = Connection::from_socket(UnixStream::connect(socket).unwrap()).unwrap();
+    let mut queue = connection.new_event_queue();
+    let qh = queue.handle();
+    let _registry = connection.display().get_registry(&qh, ());
+    let mut client = Client::default();
+    queue.roundtrip(&mut client).unwrap();
+
+    let compositor = client.compositor.clone().expect("no wl_compositor");
+    let shm = client.shm.clone().expect("no wl_shm");
+    let wm_base = client.wm_base.clone().expect("no xdg_wm_base");
+    assert!(client.toplevel_list.is_some(), "no toplevel list");
+    let source_manager = client
+        .source_manager
+        .clone()
+        .expect("no toplevel capture source manager");
+    let copy_manager = client.copy_manager.clone().expect("no image copy manager");
+
+    // A red window, Xrgb8888 is stored as B, G, R, X
+    let surface = compositor.create_surface(&qh, ());
+    let xdg_surface = wm_base.get_xdg_surface(&surface, &qh, ());
+    let _toplevel = xdg_surface.get_toplevel(&qh, ());
+    surface.commit();
+    dispatch_until(&mut queue, &mut client, |client| client.configured);
+    let (window_buffer, _window_file) = shm_buffer(
+        &shm,
+        &qh,
+        (WINDOW_SIZE, WINDOW_SIZE),
+        [0x00, 0x00, 0xff, 0xff],
+    );
+    surface.attach(Some(&window_buffer), 0, 0);
+    surface.damage_buffer(0, 0, WINDOW_SIZE, WINDOW_SIZE);
+    surface.commit();
+    dispatch_until(&mut queue, &mut client, |client| !client.handles.is_empty());
+    // The size is only known once the buffer was committed, which the roundtrip makes sure of
+    queue.roundtrip(&mut client).unwrap();
+
+    let source = source_manager.create_source(&client.handles[0], &qh, ());
+    let session = copy_manager.create_session(
+        &source,
+        ext_image_copy_capture_manager_v1::Options::empty(),
+        &qh,
+        (),
+    );
+    dispatch_until(&mut queue, &mut client, |client| {
+        client.constraints_done || client.stopped
+    });
+    assert!(!client.stopped, "the session stopped right away");
+    assert_eq!(client.buffer_size, Some((WINDOW_SIZE, WINDOW_SIZE)));
+    assert!(client.shm_formats.contains(&wl_shm::Format::Xrgb8888));
+
+    // The capture buffer starts out black, so the copy is what makes it red
+    let (capture_buffer, capture_file) = shm_buffer(
+        &shm,
+        &qh,
+        (WINDOW_SIZE, WINDOW_SIZE),
+        [0x00, 0x00, 0x00, 0xff],
+    );
+    let frame = session.create_frame(&qh, ());
+    frame.attach_buffer(&capture_buffer);
+    frame.damage_buffer(0, 0, WINDOW_SIZE, WINDOW_SIZE);
+    frame.capture();
+    dispatch_until(&mut queue, &mut client, |client| {
+        client.ready || client.failed.is_some()
+    });
+    assert!(client.ready, "the capture failed: {:?}", client.failed);
+
+    let mut pixels = vec![0u8; (WINDOW_SIZE * WINDOW_SIZE * 4) as usize];
+    capture_file.read_exact_at(&mut pixels, 0).unwrap();
+    for pixel in pixels.chunks_exact(4) {
+        assert_eq!(&pixel[..3], &[0x00, 0x00, 0xff]);
+    }
+}